@@ -0,0 +1,63 @@
+//! Benchmark of the dispute lookup hot path: events referencing stored transactions.
+//! Run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use toy_payments_engine::engine::Engine;
+use toy_payments_engine::transaction::{Transaction, TxStateKind};
+
+const CLIENTS: u32 = 1_000;
+const DEPOSITS: u32 = 100_000;
+const CYCLES: u32 = 5;
+const RUNS: u32 = 5;
+
+/// Reads transactions from CSV, initialized as the engine does.
+fn read_txs(csv: &str) -> Vec<Transaction> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(csv.as_bytes())
+        .deserialize::<Transaction>()
+        .map(|tx| {
+            let mut tx = tx.unwrap();
+            tx.init(TxStateKind::Received).unwrap();
+            tx
+        })
+        .collect()
+}
+
+/// Deposits spread over the clients, then dispute-resolve cycles on each of them.
+fn workload() -> (Vec<Transaction>, Vec<Transaction>) {
+    let mut deposits = String::from("type,client,tx,amount\n");
+    let mut events = String::from("type,client,tx,amount\n");
+    for id in 0..DEPOSITS {
+        deposits.push_str(&format!("deposit,{},{},1.5\n", id % CLIENTS, id));
+    }
+    for _ in 0..CYCLES {
+        for id in 0..DEPOSITS {
+            events.push_str(&format!("dispute,{},{},\n", id % CLIENTS, id));
+            events.push_str(&format!("resolve,{},{},\n", id % CLIENTS, id));
+        }
+    }
+    (read_txs(&deposits), read_txs(&events))
+}
+
+fn main() {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let (deposits, events) = workload();
+        let n = events.len();
+        let mut engine = Engine::new();
+        for tx in deposits {
+            engine.process(tx).unwrap();
+        }
+        let start = Instant::now();
+        for tx in events {
+            black_box(engine.process(tx)).unwrap();
+        }
+        let spent = start.elapsed();
+        best = best.min(spent);
+        println!("{} events in {:?}, {:?}/event", n, spent, spent / n as u32);
+    }
+    println!("best: {:?}", best);
+}
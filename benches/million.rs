@@ -0,0 +1,40 @@
+//! Benchmark of processing a million deposits and withdrawals, by an engine growing
+//! its maps as it goes and by one pre-sized for them. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use toy_payments_engine::engine::Engine;
+
+const CLIENTS: u32 = 10_000;
+const TRANSACTIONS: u32 = 1_000_000;
+const RUNS: u32 = 5;
+
+/// Deposits, each followed by a withdrawal of a part of it, spread over the clients.
+fn workload() -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for id in (0..TRANSACTIONS).step_by(2) {
+        csv.push_str(&format!("deposit,{},{},2\n", id % CLIENTS, id));
+        csv.push_str(&format!("withdrawal,{},{},1\n", id % CLIENTS, id + 1));
+    }
+    csv
+}
+
+fn main() {
+    let input = workload();
+    for presized in [false, true] {
+        let mut best = Duration::MAX;
+        for _ in 0..RUNS {
+            let mut engine = match presized {
+                true => Engine::with_capacity(CLIENTS as usize, TRANSACTIONS as usize),
+                false => Engine::new(),
+            };
+            let start = Instant::now();
+            engine.ingest(input.as_bytes());
+            best = best.min(start.elapsed());
+        }
+        println!(
+            "pre-sized: {}, {} transactions, best of {}: {:?}",
+            presized, TRANSACTIONS, RUNS, best
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! Benchmark of memory taken by stored transactions: a million of them, half being
+//! withdrawals, processed with and without withdrawals stored. Run with `cargo bench`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use toy_payments_engine::engine::Engine;
+
+const CLIENTS: u32 = 1_000;
+const TRANSACTIONS: u32 = 1_000_000;
+
+/// System allocator keeping track of the memory allocated, and of its peak.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Deposits, each followed by a withdrawal of a part of it, spread over the clients.
+fn workload() -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for id in (0..TRANSACTIONS).step_by(2) {
+        csv.push_str(&format!("deposit,{},{},2\n", id % CLIENTS, id));
+        csv.push_str(&format!("withdrawal,{},{},1\n", id % CLIENTS, id + 1));
+    }
+    csv
+}
+
+fn main() {
+    let input = workload();
+    for store in [true, false] {
+        let base = ALLOCATED.load(Ordering::Relaxed);
+        PEAK.store(base, Ordering::Relaxed);
+        let start = Instant::now();
+        let mut engine = Engine::new().with_store_withdrawals(store);
+        let summary = engine.ingest(input.as_bytes());
+        let spent = start.elapsed();
+        println!(
+            "withdrawals stored: {}, {} transactions processed in {:?}, peak memory {} MiB",
+            store,
+            summary.processed,
+            spent,
+            (PEAK.load(Ordering::Relaxed) - base) >> 20
+        );
+    }
+}
@@ -1,7 +1,12 @@
-use serde::Serializer;
+use serde::de::{self, Error as _, Visitor};
+use serde::{Deserialize, Deserializer, Serializer};
+use std::{fmt, io};
+
+use crate::error::{EngineError, OutputError};
+use crate::transaction::{parse_scaled_amount, scale, signed, Amount, SignedAmount, DECIMALS};
 
 /// User account.
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Account {
     /// Client ID, unique, one per client.
     pub id: u32,
@@ -9,19 +14,45 @@ pub struct Account {
     /// We store balances as integers for simpler operations,
     /// as only precision to 10^-4 needed,
     /// we store it as <amount>*10^4.
-    /// This allows dealing with balances up to ~1.84 quadrillion (`u64::MAX/10^4`),
-    /// which should be quite enough.
-    pub total: u64,
-    /// Total funds held for dispute.
-    pub held: u64,
+    /// This allows dealing with balances up to ~1.84 quadrillion (`MAX_DECIMAL`),
+    /// which should be quite enough. Engines of other precision store it
+    /// as <amount>*10^DIGITS, with the ceiling lowered or raised accordingly.
+    /// With `decimal` feature, it's a decimal of the same scale, keeping the digits
+    /// beyond it, up to ~7.9*10^24 (`MAX_DECIMAL`).
+    pub total: Amount,
+    /// Part of total balance below zero, drawn on the overdraft; `total` is zero then,
+    /// as the balance is `total - overdrawn`.
+    pub overdrawn: Amount,
+    /// How far available balance may drop below zero upon a withdrawal,
+    /// no overdraft by default.
+    pub overdraft_limit: Amount,
+    /// Total funds held, for dispute or any of the other `HoldReason`s.
+    pub held: Amount,
+    /// Part of held funds put on a manual hold.
+    pub held_manual: Amount,
+    /// Part of held funds put on a fraud hold.
+    pub held_fraud: Amount,
     /// Whether the account is locked. An account is locked if a charge back occurs.
     pub locked: bool,
+    /// Position of the last transaction applied to the account among the ones applied
+    /// by the engine, 1 for the first one, for it to be reconciled against other systems.
+    #[serde(default)]
+    pub last_seq: u64,
+}
+
+/// Reasons to hold funds other than a dispute, which holds them on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldReason {
+    /// Hold put by an operator, e.g. pending a review.
+    Manual,
+    /// Hold put on suspicion of fraud.
+    Fraud,
 }
 
 macro_rules! ensure_unlocked {
     ($a:ident) => {
         if $a.locked {
-            return Err("account is frozen".to_string());
+            return Err(EngineError::AccountFrozen);
         }
     };
 }
@@ -34,118 +65,947 @@ impl Account {
             ..Default::default()
         }
     }
-    /// Returns available balance of the account.
-    pub fn available(&self) -> u64 {
+    /// Returns available balance of the account, the one withdrawals and holds
+    /// are checked against. Clamped to zero if more funds are held than the account
+    /// has, see `available_signed()` for the actual one.
+    pub fn available(&self) -> Amount {
         self.total.saturating_sub(self.held)
     }
-    /// Deposits amount to the account.
+    /// Returns available balance of the account, negative if more funds are held
+    /// than it has, e.g. upon a manual adjustment, for the account to show as underwater.
+    /// Balances beyond `i128::MAX`, possible with `bigamount` feature only, wrap around.
+    pub fn available_signed(&self) -> SignedAmount {
+        self.total_signed() - signed(self.held)
+    }
+    /// Returns total balance of the account, negative if it's overdrawn.
+    /// Balances beyond `i128::MAX`, possible with `bigamount` feature only, wrap around.
+    pub fn total_signed(&self) -> SignedAmount {
+        signed(self.total) - signed(self.overdrawn)
+    }
+    /// Returns part of held funds held for disputes,
+    /// i.e. the part not held for any of the `HoldReason`s.
+    pub fn held_dispute(&self) -> Amount {
+        self.held
+            .saturating_sub(self.held_manual)
+            .saturating_sub(self.held_fraud)
+    }
+    /// Deposits amount to the account, paying the overdraft off first.
+    /// Fails if total balance would exceed `MAX_AMOUNT`.
     /// Returns new total balance upon success.
-    pub fn deposit(&mut self, amount: u64) -> Result<u64, String> {
+    pub fn deposit(&mut self, amount: Amount) -> Result<Amount, EngineError> {
         ensure_unlocked!(self);
 
-        self.total = self.total.checked_add(amount).ok_or(
-            "tx makes balance overflow; such enourmous balances are not supported".to_string(),
-        )?;
-
-        Ok(self.total)
+        self.credit(amount)
     }
     /// Withdraws amount from the account.
+    /// Fails telling so if total balance covers the amount, but available one
+    /// does not due to held funds, and with insufficient total balance if it does not.
+    /// With an overdraft limit, available balance may drop down to minus the limit,
+    /// the part of the amount beyond total balance being drawn on the overdraft,
+    /// and it fails with insufficient total balance beyond that.
     /// Returns new total balance upon success.
-    pub fn withdraw(&mut self, amount: u64) -> Result<u64, String> {
+    pub fn withdraw(&mut self, amount: Amount) -> Result<Amount, EngineError> {
         ensure_unlocked!(self);
 
-        if self.available() < amount {
-            return Err(format!("insufficient available balance, acc: {:?}", &self));
-        };
-
-        self.total = self
-            .total
-            .checked_sub(amount)
-            .ok_or("insufficient total balance".to_string())?;
-
-        Ok(self.total)
+        if self.overdraft_limit == 0 {
+            self.ensure_available(amount)?;
+        } else if self.available_signed() - signed(amount) < -signed(self.overdraft_limit) {
+            return Err(EngineError::InsufficientFunds);
+        }
+        self.debit(amount)
     }
     /// Holds amount on the account.
+    /// Only available funds can be held, so that held never exceeds total balance:
+    /// e.g. a deposit disputed after its funds have been withdrawn can't be held,
+    /// and the hold fails as `withdraw()` does. Fails with `EngineError::Overflow`
+    /// if held funds would exceed `MAX_AMOUNT`, rather than being clamped.
     /// Returns new available balance upon success.
-    pub fn hold(&mut self, amount: u64) -> Result<u64, String> {
+    pub fn hold(&mut self, amount: Amount) -> Result<Amount, EngineError> {
         ensure_unlocked!(self);
 
-        self.held = self.held.saturating_add(amount);
+        self.ensure_available(amount)?;
+        self.held = self.held.checked_add(amount).ok_or(EngineError::Overflow)?;
         Ok(self.available())
     }
     /// Releases amount on the account.
+    /// Fails with `EngineError::HeldShortfall` if less than the amount is held,
+    /// rather than releasing what there is.
     /// Returns new available balance upon success.
-    pub fn release(&mut self, amount: u64) -> Result<u64, String> {
+    pub fn release(&mut self, amount: Amount) -> Result<Amount, EngineError> {
         ensure_unlocked!(self);
 
-        self.held = self.held.saturating_sub(amount);
+        self.held = self.ensure_held(amount)?;
         Ok(self.available())
     }
-    /// Charges an amount back.
+    /// Holds amount on the account for the reason given.
+    /// Fails as `hold()` does, and with `EngineError::Overflow` if funds held
+    /// for the reason would exceed `MAX_AMOUNT`.
+    /// Returns new available balance upon success.
+    pub fn hold_for(&mut self, reason: HoldReason, amount: Amount) -> Result<Amount, EngineError> {
+        let part = match reason {
+            HoldReason::Manual => self.held_manual,
+            HoldReason::Fraud => self.held_fraud,
+        };
+        let part = part.checked_add(amount).ok_or(EngineError::Overflow)?;
+        let available = self.hold(amount)?;
+        match reason {
+            HoldReason::Manual => self.held_manual = part,
+            HoldReason::Fraud => self.held_fraud = part,
+        }
+        Ok(available)
+    }
+    /// Releases amount held on the account for the reason given.
+    /// Fails if less than the amount is held for it.
+    /// Returns new available balance upon success.
+    pub fn release_for(
+        &mut self,
+        reason: HoldReason,
+        amount: Amount,
+    ) -> Result<Amount, EngineError> {
+        ensure_unlocked!(self);
+
+        let part = match reason {
+            HoldReason::Manual => &mut self.held_manual,
+            HoldReason::Fraud => &mut self.held_fraud,
+        };
+        *part = part
+            .checked_sub(amount)
+            .ok_or(EngineError::NotHeld { amount, reason })?;
+        self.release(amount)
+    }
+    /// Adjusts total balance by a signed `delta`, as a manual correction, interest
+    /// or a fee does. Unlike deposit and withdraw, it bypasses both the availability
+    /// and the freeze checks, as held funds don't count for it. A credit pays the
+    /// overdraft off first, and fails if total balance would exceed `MAX_AMOUNT`,
+    /// while a debit beyond total balance is drawn on the overdraft, and fails
+    /// with insufficient funds beyond its limit.
     /// Returns new total balance upon success.
-    pub fn chargeback(&mut self, amount: u64) -> Result<u64, String> {
+    pub fn adjust(&mut self, delta: i64) -> Result<Amount, EngineError> {
+        self.adjust_by(Amount::from(delta.unsigned_abs()), delta < 0)
+    }
+    /// Adjusts total balance as `adjust()` does, by an `amount` debited if `debit`,
+    /// and credited otherwise, for amounts of any size, e.g. of `decimal` feature.
+    /// Returns new total balance upon success.
+    pub fn adjust_by(&mut self, amount: Amount, debit: bool) -> Result<Amount, EngineError> {
+        if !debit {
+            return self.credit(amount);
+        }
+        if self.total_signed() - signed(amount) < -signed(self.overdraft_limit) {
+            return Err(EngineError::InsufficientFunds);
+        }
+        self.debit(amount)
+    }
+    /// Charges an amount back, taking it out of held funds.
+    /// Fails with `EngineError::HeldShortfall` if less than the amount is held,
+    /// rather than charging back what there is. If total balance is less than
+    /// the amount, e.g. upon a manual adjustment, the rest is overdrawn,
+    /// failing with `EngineError::Overflow` if the overdraft would exceed `MAX_AMOUNT`.
+    /// Returns new total balance upon success.
+    pub fn chargeback(&mut self, amount: Amount) -> Result<Amount, EngineError> {
         ensure_unlocked!(self);
 
-        self.total = self.total.saturating_sub(amount);
-        self.held = self.held.saturating_sub(amount);
+        let held = self.ensure_held(amount)?;
+        let drawn = amount.saturating_sub(self.total);
+        self.overdrawn = self
+            .overdrawn
+            .checked_add(drawn)
+            .ok_or(EngineError::Overflow)?;
+        self.total -= amount - drawn;
+        self.held = held;
 
         self.lock();
         Ok(self.total)
     }
+    /// Credits an amount back to the account, available at once.
+    /// This is what a disputed withdrawal does: the funds are returned to the client
+    /// pending the resolution, as a deposit would.
+    /// Returns new total balance upon success.
+    pub fn credit_back(&mut self, amount: Amount) -> Result<Amount, EngineError> {
+        self.deposit(amount)
+    }
+    /// Takes back an amount credited with `credit_back()`, as a resolved withdrawal
+    /// dispute does. Fails as withdraw does if less than the amount is available,
+    /// e.g. when the credit has been spent pending the resolution, as balances
+    /// can't go negative.
+    /// Returns new total balance upon success.
+    pub fn revoke_credit(&mut self, amount: Amount) -> Result<Amount, EngineError> {
+        ensure_unlocked!(self);
+
+        self.ensure_available(amount)?;
+        self.total -= amount;
+        Ok(self.total)
+    }
+    /// Keeps an amount credited with `credit_back()` for good, as a withdrawal
+    /// charged back does, leaving the balances as they are. Locks the account,
+    /// as `chargeback()` does.
+    /// Returns available balance upon success.
+    pub fn keep_credit(&mut self, _amount: Amount) -> Result<Amount, EngineError> {
+        ensure_unlocked!(self);
+
+        self.lock();
+        Ok(self.available())
+    }
+    /// Credits amount to total balance, paying the overdraft off first.
+    /// Fails if total balance would exceed `MAX_AMOUNT`.
+    fn credit(&mut self, amount: Amount) -> Result<Amount, EngineError> {
+        let repaid = amount.min(self.overdrawn);
+        self.total = self
+            .total
+            .checked_add(amount - repaid)
+            .ok_or(EngineError::Overflow)?;
+        self.overdrawn -= repaid;
+
+        Ok(self.total)
+    }
+    /// Debits amount from total balance, the part of it beyond the balance being
+    /// drawn on the overdraft, which is to be checked against its limit beforehand.
+    fn debit(&mut self, amount: Amount) -> Result<Amount, EngineError> {
+        let drawn = amount.saturating_sub(self.total);
+        self.overdrawn = self
+            .overdrawn
+            .checked_add(drawn)
+            .ok_or(EngineError::Overflow)?;
+        self.total -= amount - drawn;
+
+        Ok(self.total)
+    }
+    /// Returns held funds less the amount, failing if less than it is held.
+    fn ensure_held(&self, amount: Amount) -> Result<Amount, EngineError> {
+        self.held
+            .checked_sub(amount)
+            .ok_or(EngineError::HeldShortfall {
+                amount,
+                held: self.held,
+            })
+    }
+    /// Fails as `withdraw()` does if less than the amount is available.
+    fn ensure_available(&self, amount: Amount) -> Result<(), EngineError> {
+        if self.total < amount {
+            return Err(EngineError::InsufficientFunds);
+        };
+        if self.available() < amount {
+            return Err(EngineError::FundsHeld {
+                available: self.available(),
+                held: self.held,
+            });
+        };
+        Ok(())
+    }
+    /// Returns balances of the account as they are reported: total, overdrawn
+    /// and held ones along with the lock, leaving out the bookkeeping, e.g. `last_seq`.
+    fn reported(&self) -> (Amount, Amount, Amount, bool) {
+        (self.total, self.overdrawn, self.held, self.locked)
+    }
     /// Locks account.
     pub fn lock(&mut self) {
         self.locked = true;
     }
     /// Unlocks account.
-    #[allow(dead_code)]
     pub fn unlock(&mut self) {
         self.locked = false;
     }
 }
 
-/// Helper struct for simpler Account serilization.
-#[derive(Debug, serde::Serialize)]
-pub struct AccountSer {
-    client: u32,
-    #[serde(serialize_with = "ser_amount")]
-    available: u64,
-    #[serde(serialize_with = "ser_amount")]
-    held: u64,
-    #[serde(serialize_with = "ser_amount")]
-    total: u64,
+/// Helper struct for simpler Account (de)serilization,
+/// with amounts of `DIGITS` decimal digits.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AccountSer<const DIGITS: u32 = DECIMALS> {
+    /// Client of the account, blank for a subtotal row.
+    client: Option<u32>,
+    /// Currency of the account, output only if there are accounts in currencies
+    /// other than the implicit one, which is blank then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<String>,
+    /// Available balance, negative only if asked, see `with_signed_available()`.
+    #[serde(
+        serialize_with = "ser_signed::<DIGITS, _>",
+        deserialize_with = "de_signed::<DIGITS, _>"
+    )]
+    available: Signed,
+    #[serde(
+        serialize_with = "ser_amount::<DIGITS, _>",
+        deserialize_with = "de_amount::<DIGITS, _>"
+    )]
+    held: Amount,
+    /// Held funds broken out by the reason, summing up to `held`, output only if asked.
+    #[serde(
+        default,
+        serialize_with = "ser_opt_amount::<DIGITS, _>",
+        deserialize_with = "de_opt_amount::<DIGITS, _>",
+        skip_serializing_if = "Option::is_none"
+    )]
+    held_dispute: Option<Amount>,
+    #[serde(
+        default,
+        serialize_with = "ser_opt_amount::<DIGITS, _>",
+        deserialize_with = "de_opt_amount::<DIGITS, _>",
+        skip_serializing_if = "Option::is_none"
+    )]
+    held_manual: Option<Amount>,
+    #[serde(
+        default,
+        serialize_with = "ser_opt_amount::<DIGITS, _>",
+        deserialize_with = "de_opt_amount::<DIGITS, _>",
+        skip_serializing_if = "Option::is_none"
+    )]
+    held_fraud: Option<Amount>,
+    /// Total balance, negative if overdrawn.
+    #[serde(
+        serialize_with = "ser_signed::<DIGITS, _>",
+        deserialize_with = "de_signed::<DIGITS, _>"
+    )]
+    total: Signed,
+    #[serde(deserialize_with = "de_locked")]
     locked: bool,
+    /// Position of the last transaction applied to the account, output only if asked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_seq: Option<u64>,
+}
+
+/// Columns of the balances CSV, in the order they are emitted.
+pub const OUTPUT_COLUMNS: [&str; 5] = ["client", "available", "held", "total", "locked"];
+
+/// Columns of the balances CSV with held funds broken out by the reason.
+pub const BREAKDOWN_COLUMNS: [&str; 8] = [
+    "client",
+    "available",
+    "held",
+    "held_dispute",
+    "held_manual",
+    "held_fraud",
+    "total",
+    "locked",
+];
+
+/// Subtotal rows are not accounts, so they are to be told apart with
+/// `AccountSer::is_subtotal()` and skipped beforehand.
+impl<const DIGITS: u32> From<AccountSer<DIGITS>> for Account {
+    fn from(a: AccountSer<DIGITS>) -> Self {
+        let (total, overdrawn) = match a.total.negative {
+            true => (Amount::MIN, a.total.abs),
+            false => (a.total.abs, Amount::MIN),
+        };
+        Account {
+            id: a.client.unwrap_or_default(),
+            total,
+            overdrawn,
+            overdraft_limit: Amount::MIN,
+            held: a.held,
+            held_manual: a.held_manual.unwrap_or_default(),
+            held_fraud: a.held_fraud.unwrap_or_default(),
+            locked: a.locked,
+            last_seq: a.last_seq.unwrap_or_default(),
+        }
+    }
 }
 
-impl From<Account> for AccountSer {
+impl<const DIGITS: u32> From<Account> for AccountSer<DIGITS> {
     fn from(a: Account) -> Self {
         AccountSer {
-            client: a.id,
-            available: a.available(),
+            client: Some(a.id),
+            currency: None,
+            // an overdrawn total is negative as it is, and so is available balance then
+            available: match a.overdrawn > 0 {
+                true => Signed::diff(a.total, a.held.saturating_add(a.overdrawn)),
+                false => Signed::from(a.available()),
+            },
             held: a.held,
-            total: a.total,
+            held_dispute: None,
+            held_manual: None,
+            held_fraud: None,
+            total: Signed::diff(a.total, a.overdrawn),
             locked: a.locked,
+            last_seq: None,
+        }
+    }
+}
+
+impl<const DIGITS: u32> AccountSer<DIGITS> {
+    /// Makes the row break out held funds of the account by the reason.
+    pub fn with_held_breakdown(self, a: &Account) -> Self {
+        AccountSer {
+            held_dispute: Some(a.held_dispute()),
+            held_manual: Some(a.held_manual),
+            held_fraud: Some(a.held_fraud),
+            ..self
+        }
+    }
+
+    /// Makes the row carry currency of the account, blank for the implicit one.
+    pub fn with_currency(self, currency: &str) -> Self {
+        AccountSer {
+            currency: Some(currency.to_string()),
+            ..self
+        }
+    }
+
+    /// Makes the row carry position of the last transaction applied to the account.
+    pub fn with_last_seq(self, a: &Account) -> Self {
+        AccountSer {
+            last_seq: Some(a.last_seq),
+            ..self
+        }
+    }
+
+    /// Makes the row show available balance of an underwater account negative,
+    /// rather than clamped to zero.
+    pub fn with_signed_available(self, a: &Account) -> Self {
+        AccountSer {
+            available: Signed::diff(a.total, a.held.saturating_add(a.overdrawn)),
+            ..self
+        }
+    }
+
+    /// Whether `available + held == total` for the row, which doesn't hold for an account
+    /// with more funds held than it has, if its available balance is clamped to zero.
+    pub fn is_balanced(&self) -> bool {
+        self.available.balances(self.held, self.total)
+    }
+
+    /// Makes the row hold no more funds than its total, zero if it's negative,
+    /// the rest being available, so that it's balanced. Held funds breakdown,
+    /// if output, is cut down in its order to sum up to `held`.
+    pub fn with_held_clamped(self) -> Self {
+        let held = match self.total.negative {
+            true => Amount::MIN,
+            false => self.held.min(self.total.abs),
+        };
+        let mut rest = held;
+        let mut cut = |amount: Option<Amount>| {
+            amount.map(|amount| {
+                let amount = amount.min(rest);
+                rest -= amount;
+                amount
+            })
+        };
+        AccountSer {
+            held_dispute: cut(self.held_dispute),
+            held_manual: cut(self.held_manual),
+            held_fraud: cut(self.held_fraud),
+            available: match self.total.negative {
+                true => self.total,
+                false => Signed::from(self.total.abs - held),
+            },
+            held,
+            ..self
+        }
+    }
+
+    /// Returns subtotal row of the rows given, carrying the sums of their balances,
+    /// the breakdown of held funds included if all of them have it, with a blank client.
+    /// Its `last_seq` is the latest one of the rows, if all of them have it, and its
+    /// currency is the one of the first row, as they're to be of a single currency.
+    pub fn subtotal(rows: &[Self]) -> Self {
+        let sum = |amount: fn(&Self) -> Amount| {
+            rows.iter()
+                .fold(Amount::MIN, |sum, row| sum.saturating_add(amount(row)))
+        };
+        let sum_opt = |amount: fn(&Self) -> Option<Amount>| {
+            rows.iter().try_fold(Amount::MIN, |sum, row| {
+                amount(row).map(|amount| sum.saturating_add(amount))
+            })
+        };
+        AccountSer {
+            client: None,
+            currency: rows.first().and_then(|row| row.currency.clone()),
+            available: Signed::sum(rows.iter().map(|row| row.available)),
+            held: sum(|row| row.held),
+            held_dispute: sum_opt(|row| row.held_dispute),
+            held_manual: sum_opt(|row| row.held_manual),
+            held_fraud: sum_opt(|row| row.held_fraud),
+            total: Signed::sum(rows.iter().map(|row| row.total)),
+            locked: false,
+            last_seq: rows
+                .iter()
+                .try_fold(0, |last, row| row.last_seq.map(|seq| last.max(seq))),
+        }
+    }
+
+    /// Returns balances of the row, available, held and total ones, as decimals
+    /// formatted as they are output.
+    pub fn balances(&self) -> (String, String, String) {
+        (
+            self.available.fmt(DIGITS),
+            fmt_scaled_amount(self.held, DIGITS),
+            self.total.fmt(DIGITS),
+        )
+    }
+
+    /// Returns client of the row, `None` for a subtotal one.
+    pub fn client(&self) -> Option<u32> {
+        self.client
+    }
+
+    /// Returns columns of the row, as in the header of CSV output.
+    pub fn columns(&self) -> Vec<&'static str> {
+        let mut columns = match (self.held_dispute, self.held_manual, self.held_fraud) {
+            (Some(_), Some(_), Some(_)) => BREAKDOWN_COLUMNS.to_vec(),
+            _ => OUTPUT_COLUMNS.to_vec(),
+        };
+        if self.currency.is_some() {
+            columns.insert(1, "currency");
+        }
+        if self.last_seq.is_some() {
+            columns.push("last_seq");
+        }
+        columns
+    }
+
+    /// Whether it's a subtotal row rather than a row of an account.
+    pub fn is_subtotal(&self) -> bool {
+        self.client.is_none()
+    }
+
+    /// Renders the row as SQL `INSERT` statement into the `table`, with the same columns
+    /// as in CSV, amounts being quoted decimals, and blank client of a subtotal `NULL`.
+    pub fn to_sql_insert(&self, table: &str) -> String {
+        let amount = |a: Amount| sql_string(&fmt_scaled_amount(a, DIGITS));
+        let mut values = vec![self
+            .client
+            .map_or_else(|| "NULL".to_owned(), |c| c.to_string())];
+        values.extend(self.currency.as_deref().map(sql_string));
+        values.extend([sql_string(&self.available.fmt(DIGITS)), amount(self.held)]);
+        if let (Some(dispute), Some(manual), Some(fraud)) =
+            (self.held_dispute, self.held_manual, self.held_fraud)
+        {
+            values.extend([dispute, manual, fraud].map(amount));
+        }
+        values.push(sql_string(&self.total.fmt(DIGITS)));
+        values.push(if self.locked { "TRUE" } else { "FALSE" }.to_owned());
+        values.extend(self.last_seq.map(|seq| seq.to_string()));
+        let columns = self.columns();
+        format!(
+            "INSERT INTO {} ({}) VALUES ({});",
+            sql_ident(table),
+            columns.join(", "),
+            values.join(", ")
+        )
+    }
+}
+
+/// Balance of a row which may be negative, as sign and magnitude
+/// for the whole range of `Amount` to fit in. Zero is never negative.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Signed {
+    negative: bool,
+    abs: Amount,
+}
+
+impl From<Amount> for Signed {
+    fn from(abs: Amount) -> Self {
+        Signed {
+            negative: false,
+            abs,
+        }
+    }
+}
+
+impl Signed {
+    /// Returns `pos - neg`.
+    fn diff(pos: Amount, neg: Amount) -> Self {
+        Signed {
+            negative: neg > pos,
+            abs: pos.abs_diff(neg),
         }
     }
+
+    /// Returns the signed amount as sign and magnitude.
+    #[cfg(not(feature = "decimal"))]
+    fn of(a: SignedAmount) -> Self {
+        Signed {
+            negative: a < 0,
+            abs: a.unsigned_abs() as Amount,
+        }
+    }
+    #[cfg(feature = "decimal")]
+    fn of(a: SignedAmount) -> Self {
+        Signed {
+            negative: a.is_sign_negative() && !a.is_zero(),
+            abs: Amount::new(a.abs()).unwrap_or_default(),
+        }
+    }
+
+    /// Whether `self + held == total`, as for available balance.
+    fn balances(self, held: Amount, total: Self) -> bool {
+        let sum = match self.negative {
+            true => Some(Signed::diff(held, self.abs)),
+            false => self.abs.checked_add(held).map(Signed::from),
+        };
+        sum == Some(total)
+    }
+
+    /// Returns sum of the balances, with positive and negative parts saturating apart.
+    fn sum(all: impl Iterator<Item = Self>) -> Self {
+        let (pos, neg) = all.fold((Amount::MIN, Amount::MIN), |(pos, neg), a| {
+            match a.negative {
+                true => (pos, neg.saturating_add(a.abs)),
+                false => (pos.saturating_add(a.abs), neg),
+            }
+        });
+        Signed::diff(pos, neg)
+    }
+
+    /// Formats the balance as a decimal of the given number of digits,
+    /// with no trailing zeros.
+    fn fmt(self, decimals: u32) -> String {
+        let abs = fmt_scaled_amount(self.abs, decimals);
+        match self.negative {
+            true => format!("-{}", abs),
+            false => abs,
+        }
+    }
+}
+
+/// Quotes SQL string literal, doubling the quotes inside.
+fn sql_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Quotes SQL identifier, possibly schema-qualified, part by part,
+/// leaving plain ones, e.g. `accounts` or `public.accounts`, as they are.
+fn sql_ident(name: &str) -> String {
+    let plain = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    name.split('.')
+        .map(|part| match plain(part) {
+            true => part.to_owned(),
+            false => format!("\"{}\"", part.replace('"', "\"\"")),
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Helper for serialization of amounts output only if asked.
+fn ser_opt_amount<const DIGITS: u32, S>(
+    a: &Option<Amount>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    ser_amount::<DIGITS, S>(&a.unwrap_or_default(), serializer)
+}
+
+/// Change of client account since a prior state, old and new values side by side,
+/// with amounts of `DIGITS` decimal digits, available and total ones being negative
+/// if overdrawn.
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub struct AccountDelta<const DIGITS: u32 = DECIMALS> {
+    pub client: u32,
+    /// Currency of the account, set only if there are accounts in currencies
+    /// other than the implicit one, which is blank then.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    #[serde(serialize_with = "ser_signed_amount::<DIGITS, _>")]
+    pub old_available: SignedAmount,
+    #[serde(serialize_with = "ser_signed_amount::<DIGITS, _>")]
+    pub new_available: SignedAmount,
+    #[serde(serialize_with = "ser_amount::<DIGITS, _>")]
+    pub old_held: Amount,
+    #[serde(serialize_with = "ser_amount::<DIGITS, _>")]
+    pub new_held: Amount,
+    #[serde(serialize_with = "ser_signed_amount::<DIGITS, _>")]
+    pub old_total: SignedAmount,
+    #[serde(serialize_with = "ser_signed_amount::<DIGITS, _>")]
+    pub new_total: SignedAmount,
+    pub old_locked: bool,
+    pub new_locked: bool,
+}
+
+impl<const DIGITS: u32> AccountDelta<DIGITS> {
+    /// Returns delta between the two states of an account, if it has changed
+    /// in the balances reported, the overdraft included.
+    pub fn between(old: &Account, new: &Account) -> Option<Self> {
+        (old.reported() != new.reported()).then(|| AccountDelta {
+            client: new.id,
+            currency: None,
+            old_available: old.available_signed(),
+            new_available: new.available_signed(),
+            old_held: old.held,
+            new_held: new.held,
+            old_total: old.total_signed(),
+            new_total: new.total_signed(),
+            old_locked: old.locked,
+            new_locked: new.locked,
+        })
+    }
+
+    /// Makes the delta carry currency of the account, blank for the implicit one.
+    pub fn with_currency(self, currency: &str) -> Self {
+        AccountDelta {
+            currency: Some(currency.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Difference of client account between two states, e.g. of a dry run and the actual one,
+/// in the balances reported, total one being negative if overdrawn.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDiff {
+    pub client: u32,
+    /// Currency of the account, `None` for the implicit one.
+    pub currency: Option<String>,
+    pub before_total: SignedAmount,
+    pub after_total: SignedAmount,
+    pub before_held: Amount,
+    pub after_held: Amount,
+    pub before_locked: bool,
+    pub after_locked: bool,
+}
+
+impl AccountDiff {
+    /// Returns difference between the two states of an account, if they differ
+    /// in the balances reported, the overdraft included.
+    pub fn between(before: &Account, after: &Account) -> Option<Self> {
+        (before.reported() != after.reported()).then(|| AccountDiff {
+            client: after.id,
+            currency: None,
+            before_total: before.total_signed(),
+            after_total: after.total_signed(),
+            before_held: before.held,
+            after_held: after.held,
+            before_locked: before.locked,
+            after_locked: after.locked,
+        })
+    }
+
+    /// Makes the difference carry currency of the account.
+    pub fn with_currency(self, currency: &str) -> Self {
+        AccountDiff {
+            currency: Some(currency.to_string()),
+            ..self
+        }
+    }
+}
+
+/// Reads accounts from balances CSV as emitted by the engine, skipping subtotal rows,
+/// as well as accounts in currencies other than the implicit one.
+pub fn read_accounts<R: io::Read>(input: R) -> Result<Vec<Account>, csv::Error> {
+    csv::Reader::from_reader(input)
+        .deserialize::<AccountSer>()
+        .filter(|a| {
+            !a.as_ref().is_ok_and(|a| {
+                a.is_subtotal() || a.currency.as_ref().is_some_and(|c| !c.is_empty())
+            })
+        })
+        .map(|a| a.map(Account::from))
+        .collect()
 }
 
 /// Helper for amounts serialization.
-fn ser_amount<S>(a: &u64, serializer: S) -> Result<S::Ok, S::Error>
+fn ser_amount<const DIGITS: u32, S>(a: &Amount, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let f = a % 10_000;
-    let s = if f > 0 {
-        let f = format!("{}", &f);
-        let mut zeros = String::new();
-        for c in std::iter::repeat('0').take(4 - f.len()) {
-            zeros.push(c)
-        }
-        format!("{}.{}{}", a / 10_000, zeros, f)
+    serializer.serialize_str(&fmt_scaled_amount(*a, DIGITS))
+}
+
+/// Formats amount as a decimal, with no trailing zeros.
+pub fn fmt_amount(a: Amount) -> String {
+    fmt_scaled_amount(a, DECIMALS)
+}
+
+/// Formats amount of the given number of decimal digits as a decimal,
+/// with no trailing zeros.
+#[cfg(not(feature = "decimal"))]
+pub fn fmt_scaled_amount(a: Amount, decimals: u32) -> String {
+    let scale = scale(decimals);
+    let f = a % scale;
+    if f > 0 {
+        format!("{}.{:0width$}", a / scale, f, width = decimals as usize)
             .trim_end_matches('0')
             .to_owned()
     } else {
-        format!("{}", a / 10_000)
+        format!("{}", a / scale)
+    }
+}
+
+/// Formats amount of the given number of decimal digits as a decimal, all of its digits
+/// being there with `decimal` feature, and no trailing zeros. Ones beyond the 28 digits
+/// `Decimal` keeps, which only the amounts of the engine scale with as many do, are rounded.
+#[cfg(feature = "decimal")]
+pub fn fmt_scaled_amount(a: Amount, decimals: u32) -> String {
+    match crate::decimal::to_decimal(a, decimals) {
+        Some(d) => d.to_string(),
+        None => (a / scale(decimals)).to_string(),
+    }
+}
+
+/// Helper for serialization of available balance, which may be negative.
+fn ser_signed<const DIGITS: u32, S>(a: &Signed, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&a.fmt(DIGITS))
+}
+
+/// Helper for serialization of signed amounts, e.g. of overdrawn balances.
+fn ser_signed_amount<const DIGITS: u32, S>(
+    a: &SignedAmount,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    ser_signed::<DIGITS, S>(&Signed::of(*a), serializer)
+}
+
+/// Helper for amounts deserialization.
+/// Unlike transaction amounts, which are silently dropped when malformed,
+/// an invalid amount in a balances row is an error.
+fn de_amount<'de, const DIGITS: u32, D>(de: D) -> Result<Amount, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = <&str>::deserialize(de)?;
+    parse_scaled_amount(s, DIGITS)
+        .ok_or_else(|| D::Error::custom(format!("invalid amount: {:?}", s)))
+}
+
+/// Helper for deserialization of available balance, which may be negative.
+fn de_signed<'de, const DIGITS: u32, D>(de: D) -> Result<Signed, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = <&str>::deserialize(de)?;
+    let (negative, abs) = match s.strip_prefix('-') {
+        Some(abs) => (true, abs),
+        None => (false, s),
     };
+    parse_scaled_amount(abs, DIGITS)
+        .map(|abs| match negative {
+            true => Signed::diff(Amount::MIN, abs),
+            false => Signed::from(abs),
+        })
+        .ok_or_else(|| D::Error::custom(format!("invalid amount: {:?}", s)))
+}
+
+/// Helper for deserialization of amounts output only if asked.
+fn de_opt_amount<'de, const DIGITS: u32, D>(de: D) -> Result<Option<Amount>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    de_amount::<DIGITS, D>(de).map(Some)
+}
+
+/// Helper for `locked` flag deserialization, tolerant to common spellings
+/// of different producers, case-insensitive: `true`/`false`, `1`/`0`, `yes`/`no`,
+/// as well as to native booleans of self-describing formats, e.g. JSON.
+fn de_locked<'de, D>(de: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct LockedVisitor;
+
+    impl Visitor<'_> for LockedVisitor {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a boolean flag")
+        }
+
+        fn visit_bool<E: de::Error>(self, v: bool) -> Result<bool, E> {
+            Ok(v)
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<bool, E> {
+            match v {
+                1 => Ok(true),
+                0 => Ok(false),
+                _ => Err(E::custom(format!("invalid locked flag: {}", v))),
+            }
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<bool, E> {
+            match s.to_ascii_lowercase().as_str() {
+                "true" | "1" | "yes" => Ok(true),
+                "false" | "0" | "no" => Ok(false),
+                _ => Err(E::custom(format!("invalid locked flag: {:?}", s))),
+            }
+        }
+    }
+
+    de.deserialize_any(LockedVisitor)
+}
+
+/// Validates balances CSV as emitted by the engine.
+/// Checks that it has exactly the expected columns, every row holds valid amounts,
+/// and `available + held == total` for each row, as well as held funds breakdown
+/// summing up to `held`, if output. The `last_seq` column may follow the expected ones.
+/// Subtotal rows, with a blank client, are to carry
+/// the sums of the balances of the account rows since the previous subtotal one.
+/// Returns number of rows validated, or the first violation found.
+pub fn validate_output<R: io::Read>(input: R) -> Result<usize, OutputError> {
+    let mut rdr = csv::Reader::from_reader(input);
+
+    let headers = rdr
+        .headers()
+        .map_err(|e| OutputError::Csv(e.to_string()))?
+        .clone();
+    // the last transaction applied may follow either set of columns,
+    // and currency may follow the client
+    let len = headers.len() - usize::from(headers.iter().next_back() == Some("last_seq"));
+    let currency = headers.get(1) == Some("currency");
+    let columns = || {
+        headers
+            .iter()
+            .take(len)
+            .enumerate()
+            .filter(move |(i, _)| !(currency && *i == 1))
+            .map(|(_, column)| column)
+    };
+    if columns().ne(OUTPUT_COLUMNS) && columns().ne(BREAKDOWN_COLUMNS) {
+        return Err(OutputError::UnexpectedColumns(
+            headers.iter().map(String::from).collect(),
+        ));
+    }
+
+    let mut rows = 0;
+    let mut sums = vec![];
+    for record in rdr.records() {
+        let record = record.map_err(|e| OutputError::Csv(e.to_string()))?;
+        let line = record.position().map_or(0, |p| p.line());
+        let acc: AccountSer =
+            record
+                .deserialize(Some(&headers))
+                .map_err(|e| OutputError::Malformed {
+                    line,
+                    reason: e.to_string(),
+                })?;
+        if !acc.available.balances(acc.held, acc.total) {
+            return Err(OutputError::Unbalanced {
+                line,
+                client: acc.client,
+            });
+        }
+        if let (Some(dispute), Some(manual), Some(fraud)) =
+            (acc.held_dispute, acc.held_manual, acc.held_fraud)
+        {
+            if dispute
+                .checked_add(manual)
+                .and_then(|h| h.checked_add(fraud))
+                != Some(acc.held)
+            {
+                return Err(OutputError::BreakdownMismatch {
+                    line,
+                    client: acc.client,
+                });
+            }
+        }
+        if acc.is_subtotal() {
+            let sum = AccountSer::subtotal(&sums);
+            if (acc.available, acc.held, acc.total) != (sum.available, sum.held, sum.total) {
+                return Err(OutputError::SubtotalMismatch { line });
+            }
+            sums.clear();
+        } else {
+            sums.push(acc);
+        }
+        rows += 1;
+    }
 
-    serializer.serialize_str(&s)
+    Ok(rows)
 }
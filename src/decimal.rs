@@ -0,0 +1,268 @@
+//! Exact decimal amounts, backed by `rust_decimal::Decimal`.
+//! With `decimal` feature, amounts and balances are stored as `Amount` decimals
+//! of the engine scale, keeping the digits beyond its precision, e.g. `1.23456`
+//! is stored as `12345.6`, rather than being truncated to it, and the account
+//! arithmetic is done in decimals.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, AddAssign, Div, Mul, Rem, Sub, SubAssign};
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+
+use crate::error::AmountError;
+use crate::transaction::{scale, AmountParser, DECIMALS};
+
+/// Amount or balance of `decimal` feature: a non-negative decimal, scaled
+/// as integer ones are, <amount>*10^4, but with no digits dropped beyond that.
+/// It mirrors the operations of the unsigned integers it replaces: subtraction
+/// below zero panics, and `checked_` and `saturating_` ones stop at zero and `MAX`.
+#[derive(
+    Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Amount(Decimal);
+
+impl Amount {
+    pub const MIN: Amount = Amount(Decimal::ZERO);
+    pub const MAX: Amount = Amount(Decimal::MAX);
+
+    /// Returns amount of the decimal given, of the engine scale already,
+    /// or `None` if it's negative.
+    pub fn new(d: Decimal) -> Option<Self> {
+        (!d.is_sign_negative() || d.is_zero()).then_some(Amount(d.abs()))
+    }
+
+    /// Returns 10^exp, up to 10^28 which is the largest power of 10 fitting into `Decimal`.
+    pub const fn pow10(exp: u32) -> Self {
+        let v = 10u128.pow(exp);
+        Amount(Decimal::from_parts(
+            v as u32,
+            (v >> 32) as u32,
+            (v >> 64) as u32,
+            false,
+            0,
+        ))
+    }
+
+    /// Returns the amount as a decimal, of the engine scale.
+    pub fn to_decimal(self) -> Decimal {
+        self.0
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).and_then(Amount::new)
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        self.0.checked_mul(rhs.0).map(Amount)
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        self.checked_add(rhs).unwrap_or(Amount::MAX)
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        self.checked_sub(rhs).unwrap_or(Amount::MIN)
+    }
+
+    pub fn abs_diff(self, rhs: Self) -> Self {
+        Amount((self.0 - rhs.0).abs())
+    }
+}
+
+impl TryFrom<Decimal> for Amount {
+    type Error = AmountError;
+
+    fn try_from(d: Decimal) -> Result<Self, Self::Error> {
+        Amount::new(d).ok_or_else(|| AmountError(d.to_string()))
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(a: Amount) -> Self {
+        a.0
+    }
+}
+
+impl From<u64> for Amount {
+    fn from(v: u64) -> Self {
+        Amount(Decimal::from(v))
+    }
+}
+
+impl From<bool> for Amount {
+    fn from(v: bool) -> Self {
+        Amount(Decimal::from(u8::from(v)))
+    }
+}
+
+/// Parses a whole number of units of the engine scale, as integer amounts are parsed,
+/// see `parse_amount_exact()` for decimals.
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix('+').unwrap_or(s);
+        Some(digits)
+            .filter(|d| !d.is_empty() && d.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|d| Decimal::from_str_exact(d).ok())
+            .map(Amount)
+            .ok_or_else(|| AmountError(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0.normalize(), f)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0.normalize(), f)
+    }
+}
+
+impl PartialEq<u64> for Amount {
+    fn eq(&self, other: &u64) -> bool {
+        self.0 == Decimal::from(*other)
+    }
+}
+
+impl PartialOrd<u64> for Amount {
+    fn partial_cmp(&self, other: &u64) -> Option<Ordering> {
+        self.0.partial_cmp(&Decimal::from(*other))
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Self) -> Self {
+        self.checked_add(rhs).expect("amount overflow")
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Self) -> Self {
+        self.checked_sub(rhs).expect("amount underflow")
+    }
+}
+
+impl Mul for Amount {
+    type Output = Amount;
+
+    fn mul(self, rhs: Self) -> Self {
+        self.checked_mul(rhs).expect("amount overflow")
+    }
+}
+
+impl Mul<Amount> for u64 {
+    type Output = Amount;
+
+    fn mul(self, rhs: Amount) -> Amount {
+        Amount::from(self) * rhs
+    }
+}
+
+impl Div for Amount {
+    type Output = Amount;
+
+    fn div(self, rhs: Self) -> Self {
+        Amount(self.0 / rhs.0)
+    }
+}
+
+impl Rem for Amount {
+    type Output = Amount;
+
+    fn rem(self, rhs: Self) -> Self {
+        Amount(self.0 % rhs.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+/// Amount parser accepting decimals with up to `DIGITS` decimal digits,
+/// and rejecting ones with more of them, which `DecimalParser` keeps.
+/// Trailing zeros don't count, so `1.23450` is a fine amount of 4 digits.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExactParser<const DIGITS: u32 = DECIMALS>;
+
+impl<const DIGITS: u32> AmountParser for ExactParser<DIGITS> {
+    fn parse(&self, s: &str) -> Result<Amount, AmountError> {
+        Decimal::from_str_exact(s)
+            .ok()
+            .and_then(|d| to_amount(d, DIGITS))
+            .ok_or_else(|| AmountError(s.to_owned()))
+    }
+}
+
+/// Parses decimal amount string into amount of the given number of decimal digits,
+/// in scientific notation as well, keeping all of its digits.
+/// Returns None if the string is not a valid amount, or it has more digits
+/// than `Decimal` does, or it exceeds `MAX_AMOUNT / 10^decimals`.
+pub fn parse_amount_exact(s: &str, decimals: u32) -> Option<Amount> {
+    let (mantissa, exp) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exp)) => (mantissa, exp.parse::<i64>().ok()?),
+        None => (s, 0),
+    };
+    let mantissa = mantissa.strip_prefix('+').unwrap_or(mantissa);
+    let digits = mantissa.bytes().filter(u8::is_ascii_digit).count();
+    // no sign but a plus, as for integer amounts, nor a grouping of digits
+    if digits == 0 || mantissa.bytes().any(|b| !b.is_ascii_digit() && b != b'.') {
+        return None;
+    }
+    let d = Decimal::from_str_exact(mantissa).ok()?;
+    let shift = i64::from(decimals).checked_add(exp)?;
+    let d = match u32::try_from(shift) {
+        _ if d.is_zero() => Decimal::ZERO,
+        // 10^28 is the largest power of 10 fitting into `Decimal`
+        Ok(zeros) if zeros <= 28 => d.checked_mul(Amount::pow10(zeros).0)?,
+        Ok(_) => return None,
+        Err(_) => {
+            let mut d = d;
+            let scale = u64::from(d.scale()).checked_add(shift.unsigned_abs())?;
+            d.set_scale(u32::try_from(scale).ok()?).ok()?;
+            d
+        }
+    };
+    Amount::new(d)
+}
+
+/// Converts decimal into the amount of the given number of decimal digits.
+/// Returns None if it is negative, has more digits than that, or exceeds `MAX_AMOUNT`.
+pub fn to_amount(d: Decimal, decimals: u32) -> Option<Amount> {
+    let d = d.normalize();
+    if d.is_sign_negative() && !d.is_zero() || d.scale() > decimals {
+        return None;
+    }
+    d.checked_mul(scale(decimals).0).and_then(Amount::new)
+}
+
+/// Converts amount of the given number of decimal digits into decimal,
+/// with no trailing zeros.
+/// Returns None if it has more digits than `Decimal` keeps, which is 28 of them.
+pub fn to_decimal(a: Amount, decimals: u32) -> Option<Decimal> {
+    let mut d = a.0;
+    d.set_scale(d.scale().checked_add(decimals)?).ok()?;
+    Some(d.normalize())
+}
@@ -0,0 +1,18 @@
+//! Transcoding of input in legacy encodings to UTF-8,
+//! which is what the CSV reader expects.
+
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use std::io;
+
+/// Wraps `input` in encoding named `label` (e.g. `latin1`) into a reader yielding UTF-8.
+/// Labels are the ones of the WHATWG Encoding Standard.
+/// Returns None if the label is unknown.
+pub fn transcoding_reader<R: io::Read>(input: R, label: &str) -> Option<impl io::Read> {
+    let encoding = Encoding::for_label(label.as_bytes())?;
+    Some(
+        DecodeReaderBytesBuilder::new()
+            .encoding(Some(encoding))
+            .build(input),
+    )
+}
@@ -1,74 +1,724 @@
+use csv::Trim;
 use std::collections::hash_map::{HashMap, Values};
+use std::collections::{HashSet, VecDeque};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{error::Error, ffi::OsString, fmt, fs, fs::File, io, mem, thread};
 
 use crate::account::*;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Code, EngineError};
+#[cfg(feature = "hmac")]
+use crate::hmac::SigningKey;
+use crate::snapshot::{EngineSnapshot, TransactionSnapshot};
 use crate::transaction::*;
+use crate::wal::{read_wal, Wal, WalRecord};
 
 /// Toy Payments Engine,
 /// which processes transactions and stores account states and processed transactions.
 /// It stores only fund-moving types of transactions, namely `Deposit` and `Withdraw`,
-/// as dispute-related events don't need to be stored.
+/// as dispute-related events don't need to be stored, and withdrawals may be left out
+/// as well, see `with_store_withdrawals()`.
+/// Amounts have `DIGITS` decimal digits, 4 by default; an engine with another
+/// precision is made as in `Engine::<8>::default()`.
 #[derive(Debug, Default)]
-pub struct Engine {
-    accounts: HashMap<u32, Account>,
-    transactions: HashMap<u32, Transaction>,
+pub struct Engine<const DIGITS: u32 = DECIMALS> {
+    accounts: IdMap<Account>,
+    /// Accounts in currencies other than the implicit one, by the currency, swapped in
+    /// for `accounts` while transactions in them are processed.
+    ledgers: HashMap<String, IdMap<Account>>,
+    transactions: IdMap<Transaction, TxKey>,
+    /// Keys of the stored transactions, in the order they were stored.
+    order: Vec<TxKey>,
+    /// Number of disputes per transaction, tracked if a cycle threshold is set.
+    dispute_cycles: HashMap<TxKey, u32>,
+    /// Last event applied to each transaction, along with its position in the
+    /// sequence of processed transactions, tracked if a redelivery window is set.
+    last_events: IdMap<(Tx, u64), TxKey>,
+    /// Number of transactions passed to `process()`, the clock of the redelivery window.
+    seq: u64,
+    /// Number of transactions applied, the clock of `last_seq` of the accounts.
+    applied: u64,
+    /// Number of open disputes per client, tracked if a limit of them is set.
+    open_disputes: IdMap<u32>,
+    /// Number of deposits, withdrawals and transfers applied per client,
+    /// tracked if a limit of them is set.
+    client_txs: IdMap<u32>,
+    /// Last money-moving transactions applied, oldest first, tracked up to the rollback
+    /// depth, if set.
+    history: VecDeque<Applied>,
+    /// Log the applied transactions are appended to, if set.
+    wal: Option<Wal>,
+    config: Config,
+}
+
+/// Money-moving transaction applied, along with the accounts it touched as they
+/// were before it, `None` for the ones it created, for it to be rolled back.
+#[derive(Debug)]
+struct Applied {
+    key: TxKey,
+    ty: Tx,
+    /// Currency of the ledger the accounts are in, `None` for the implicit one.
+    currency: Option<String>,
+    /// Whether the transaction was stored, e.g. not an unstored withdrawal.
+    stored: bool,
+    accounts: Vec<(u32, Option<Account>)>,
+}
+
+/// Map keyed by client IDs, or by other keys made of IDs, e.g. `TxKey`.
+type IdMap<V, K = u32> = HashMap<K, V, BuildHasherDefault<IdHasher>>;
+
+/// Moves entries of the keys `of` holds for out of the map, into a new one.
+fn split_map<K: Eq + Hash, V, S: BuildHasher + Default>(
+    map: &mut HashMap<K, V, S>,
+    of: impl Fn(&K) -> bool,
+) -> HashMap<K, V, S> {
+    let (split, rest) = mem::take(map).into_iter().partition(|(k, _)| of(k));
+    *map = rest;
+    split
+}
+
+/// Hasher of client and transaction IDs, much cheaper than the default SipHash one,
+/// as every event looks up both the transaction and the account.
+/// It's a multiplicative hash, with the high bits of the product rotated down
+/// for the keys differing only in their high bits to spread over the buckets.
+/// Unlike SipHash, it is not resistant to crafted collisions.
+#[derive(Debug, Default, Clone, Copy)]
+struct IdHasher(u64);
+
+impl IdHasher {
+    const SEED: u64 = 0xf135_7aea_2e62_a9c5;
+
+    fn add(&mut self, n: u64) {
+        self.0 = (self.0 ^ n).wrapping_mul(Self::SEED);
+    }
+}
+
+impl Hasher for IdHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.add(u64::from(*b))
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.add(u64::from(n))
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.rotate_left(26)
+    }
+}
+
+/// Engine settings, set up with the `with_*` builder methods.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Maximum amount of a single deposit or withdrawal, unlimited if `None`.
+    pub max_tx_amount: Option<Amount>,
+    /// Run (correlation) ID, tagging all log records of the engine.
+    pub run_id: Option<String>,
+    /// Whether disputes preceding their transactions are retried at the end of input.
+    pub defer_disputes: bool,
+    /// Whether the amount of a dispute disputes only that part of the transaction,
+    /// rather than being ignored as amounts of other events are.
+    pub partial_disputes: bool,
+    /// Whether input is reordered by transaction ID before processing,
+    /// and how transactions sharing an ID are treated then.
+    pub reorder: Option<TieBreak>,
+    /// Soft limit, in bytes, of memory taken by stored accounts and transactions,
+    /// unlimited if `None`.
+    pub memory_budget: Option<usize>,
+    /// Whether time spent in parsing and processing is recorded.
+    pub timing: bool,
+    /// Grouping of accounts in the output table by their `locked` status,
+    /// ascending client order if `None`.
+    pub grouping: Option<LockGrouping>,
+    /// Whether the output table breaks held funds out by the reason.
+    pub held_breakdown: bool,
+    /// Whether the output table carries position of the last transaction applied
+    /// to each account, see `Account::last_seq`.
+    pub last_seq: bool,
+    /// Whether the output table shows available balance of an underwater account,
+    /// with more funds held than it has, negative rather than zero.
+    pub signed_available: bool,
+    /// Treatment of the output rows of accounts with more funds held than they have,
+    /// not showing `available + held == total`; output as they are if `None`.
+    pub held_overflow: Option<HeldOverflow>,
+    /// Whether the accounts of each currency in the output table are followed
+    /// with the row of their subtotals.
+    pub currency_subtotals: bool,
+    /// Parser of the input amounts, `DecimalParser` if `None`.
+    pub amount_parser: Option<Arc<dyn AmountParser>>,
+    /// Whether the parser of the input amounts is the `LenientParser`
+    /// of `Engine::with_lenient_amounts()`.
+    pub lenient_amounts: bool,
+    /// Number of dispute cycles on a single transaction, beyond which
+    /// it is flagged as suspicious; not tracked if `None`.
+    pub dispute_cycle_threshold: Option<u32>,
+    /// Whether the output table holds only accounts with funds held,
+    /// sorted by held amount descending, instead of all of them.
+    pub held_only: bool,
+    /// Whether manual adjustments apply to frozen accounts as well.
+    pub adjust_frozen: bool,
+    /// Whether privileged operations, e.g. unlocks, are accepted from input.
+    pub admin_ops: bool,
+    /// Whether declined transactions are retained in the summary, for resubmission.
+    pub keep_rejects: bool,
+    /// Format of the output accounts table.
+    pub format: OutputFormat,
+    /// Table the SQL output inserts accounts into, `accounts` if `None`.
+    pub sql_table: Option<String>,
+    /// Compression of the input files, told by their names if `None`.
+    pub compression: Option<Compression>,
+    /// Whether accounts are left unfrozen by chargebacks of withdrawals and transfers,
+    /// which only credit the client back, unlike chargebacks of deposits.
+    pub unfrozen_credit_chargebacks: bool,
+    /// Whether successful withdrawals are left unstored, so they can't be disputed,
+    /// and their IDs can be reused.
+    pub unstored_withdrawals: bool,
+    /// Whether input rows are required to have as many fields as the header,
+    /// except for events, which may omit the trailing ones.
+    pub strict_arity: bool,
+    /// Number of transactions after an event, within which its repeated delivery
+    /// is accepted as a no-op; repeats are declined if `None`.
+    pub redelivery_window: Option<u64>,
+    /// Number of transactions after a transaction, within which it may be disputed;
+    /// unlimited if `None`.
+    pub max_dispute_age: Option<u64>,
+    /// Time after a transaction, within which it may be disputed; unlimited if `None`.
+    pub dispute_expiry: Option<Duration>,
+    /// Source of the current time, `SystemClock` if `None`.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Number of disputes a client may have open at once; unlimited if `None`.
+    pub max_open_disputes: Option<u32>,
+    /// Number of deposits, withdrawals and transfers a client may have applied,
+    /// bounding the transactions stored per client; unlimited if `None`.
+    pub max_txs_per_client: Option<u32>,
+    /// Number of the last money-moving transactions kept track of, for them
+    /// to be rolled back; none if `None`.
+    pub rollback_depth: Option<usize>,
+    /// Names of the input columns of the fields of transaction records,
+    /// the standard ones if `None`.
+    pub column_map: Option<ColumnMap>,
+    /// Key to verify signatures of the input rows with; not verified if `None`.
+    #[cfg(feature = "hmac")]
+    pub signing_key: Option<SigningKey>,
+}
+
+/// Formats of the output accounts table.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// Pretty-printed JSON array of account objects.
+    Json,
+    /// Newline-delimited JSON, one account object per line, flushed as written.
+    Ndjson,
+    /// SQL `INSERT` statements, one per account, into the configured table.
+    Sql,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "ndjson" | "jsonl" => Ok(Self::Ndjson),
+            "sql" => Ok(Self::Sql),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+/// Compression of the input files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    /// gzip, as of `*.csv.gz` files, supported with `gzip` feature.
+    Gzip,
+}
+
+impl Compression {
+    /// Returns compression of the file its name suggests: gzip for `*.gz` ones.
+    pub fn of(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gz") => Self::Gzip,
+            _ => Self::Plain,
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" | "none" => Ok(Self::Plain),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
+
+/// Wraps `input` into a reader decompressing it, e.g. stdin of a compressed stream.
+pub fn decompress<R: io::Read + 'static>(
+    input: R,
+    compression: Compression,
+) -> io::Result<Box<dyn io::Read>> {
+    match compression {
+        Compression::Plain => Ok(Box::new(input)),
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(input))),
+        #[cfg(not(feature = "gzip"))]
+        Compression::Gzip => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without `gzip` feature, compressed input is not supported",
+        )),
+    }
+}
+
+/// Treatment of deposits and withdrawals of a client sharing an ID, when input
+/// is reordered by transaction ID.
+/// Events (disputes, resolves and chargebacks) share ID with their transaction by design,
+/// they are always placed after it, keeping input order among themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Keep input order, so the first one is processed and the rest are declined
+    /// as duplicates.
+    InputOrder,
+    /// Reject the whole batch.
+    Reject,
+}
+
+/// Grouping of accounts by their `locked` status, each group sorted by client ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockGrouping {
+    UnlockedFirst,
+    LockedFirst,
+}
+
+impl std::str::FromStr for LockGrouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unlocked-first" => Ok(Self::UnlockedFirst),
+            "locked-first" => Ok(Self::LockedFirst),
+            _ => Err(format!("unknown grouping: {}", s)),
+        }
+    }
+}
+
+/// Treatment of the output rows of accounts with more funds held than they have,
+/// with available balance clamped to zero, which break `available + held == total`
+/// consumers rely on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeldOverflow {
+    /// Output the row as it is, preceded with a warning comment, in CSV and SQL,
+    /// or a logged warning, in JSON.
+    Warn,
+    /// Leave the row out.
+    Skip,
+    /// Output the row with held funds clamped to its total, the rest available.
+    Clamp,
+}
+
+impl std::str::FromStr for HeldOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "skip" => Ok(Self::Skip),
+            "clamp" => Ok(Self::Clamp),
+            _ => Err(format!("unknown held overflow treatment: {}", s)),
+        }
+    }
+}
+
+/// Outcome of processing an input.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Number of transactions successfully processed.
+    pub processed: usize,
+    /// Number of transactions declined.
+    pub declined: usize,
+    /// IDs of declined transactions along with the errors,
+    /// ones rejected for missing fields come last.
+    pub errors: Vec<(u32, EngineError)>,
+    /// Input lines, 1-based, of the declined transactions, in the order of `errors`,
+    /// `None` for ones not read from input. Blank lines right before a row
+    /// are taken for its start, as the CSV reader skips them along with it.
+    pub lines: Vec<Option<u64>>,
+    /// Number of input rows which failed to be parsed.
+    pub parse_errors: usize,
+    /// Input lines of the rows which failed to be parsed, where known.
+    pub malformed: Vec<u64>,
+    /// Number of disputes (and following events) applied on the final pass,
+    /// as their transactions came later in the input.
+    pub deferred: usize,
+    /// Disputes (and following events) whose transactions never came.
+    pub orphans: Vec<Transaction>,
+    /// Transaction ID shared by several deposits or withdrawals,
+    /// for which the whole batch has been rejected in reorder mode.
+    pub rejected_on: Option<u32>,
+    /// Declined transactions, in the order of `errors`, if retained.
+    pub rejects: Vec<Reject>,
+    /// Time spent in each phase of the run, if recorded.
+    pub timing: Option<Timing>,
+}
+
+/// Per-phase breakdown of the run time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timing {
+    /// Reading and deserializing input records.
+    pub parsing: Duration,
+    /// Applying transactions to the accounts.
+    pub processing: Duration,
+    /// Writing the results, filled in by `run`, or by the caller doing output itself.
+    pub output: Duration,
+}
+
+impl Summary {
+    fn decline(&mut self, id: u32, e: EngineError, line: Option<u64>) {
+        self.declined += 1;
+        self.errors.push((id, e));
+        self.lines.push(line);
+    }
+
+    /// Returns IDs of declined transactions with the codes of their errors.
+    pub fn codes(&self) -> impl Iterator<Item = (u32, Code)> + '_ {
+        self.errors.iter().map(|(id, e)| (*id, e.code()))
+    }
+
+    /// Adds up outcome of processing another input into this one.
+    pub fn extend(&mut self, other: Summary) {
+        self.processed += other.processed;
+        self.declined += other.declined;
+        self.errors.extend(other.errors);
+        self.lines.extend(other.lines);
+        self.parse_errors += other.parse_errors;
+        self.malformed.extend(other.malformed);
+        self.deferred += other.deferred;
+        self.orphans.extend(other.orphans);
+        self.rejects.extend(other.rejects);
+        self.rejected_on = self.rejected_on.or(other.rejected_on);
+        match (&mut self.timing, other.timing) {
+            (Some(t), Some(o)) => {
+                t.parsing += o.parsing;
+                t.processing += o.processing;
+                t.output += o.output;
+            }
+            (t @ None, o) => *t = o,
+            _ => (),
+        }
+    }
+}
+
+/// Report of a run, for the caller to inspect what happened to the input.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    /// Number of transactions successfully processed.
+    pub processed: usize,
+    /// IDs of declined transactions along with the errors.
+    pub declined: Vec<(u32, EngineError)>,
+    /// Input lines, 1-based, of the declined transactions, in the order of `declined`,
+    /// `None` for ones not read from input.
+    pub lines: Vec<Option<u64>>,
+    /// Number of input rows which failed to be parsed.
+    pub parse_errors: usize,
+    /// Input lines of the rows which failed to be parsed, where known.
+    pub malformed: Vec<u64>,
+    /// Declined transactions, if retained, see `Engine::with_rejects()`.
+    pub rejects: Vec<Reject>,
+    /// Time spent in each phase of the run, if recorded.
+    pub timing: Option<Timing>,
+    /// Run ID of the engine, if set, see `Engine::with_run_id()`.
+    pub run_id: Option<String>,
+}
+
+impl From<Summary> for RunReport {
+    fn from(s: Summary) -> Self {
+        RunReport {
+            processed: s.processed,
+            declined: s.errors,
+            lines: s.lines,
+            parse_errors: s.parse_errors,
+            malformed: s.malformed,
+            rejects: s.rejects,
+            timing: s.timing,
+            run_id: None,
+        }
+    }
+}
+
+/// Top-line figures of the engine state, see `Engine::metrics()`.
+/// Amounts are of the engine scale, and sums of them saturate at `MAX_AMOUNT`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Sum of the amounts of stored deposits, charged back ones included.
+    pub deposits: Amount,
+    /// Sum of the amounts of stored withdrawals, none if they're not stored,
+    /// see `Engine::with_store_withdrawals()`.
+    pub withdrawals: Amount,
+    /// Sum of the amounts under dispute, the disputed part of partially disputed ones.
+    pub disputed: Amount,
+    /// Number of frozen accounts.
+    pub frozen_accounts: usize,
+    /// Sum of total balances of the accounts, negative ones of overdrawn accounts included.
+    pub balance: SignedAmount,
+}
+
+/// Writes declined transactions as CSV to `out`, in the input columns, `currency`
+/// included if any of them has one, followed by the `reason` one.
+pub fn write_rejects<W: io::Write>(rejects: &[Reject], out: W) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(out);
+    // the column is there for all of them, or for none
+    let currencies = rejects.iter().any(|r| r.currency.is_some());
+    for reject in rejects {
+        match reject.currency {
+            None if currencies => wtr.serialize(Reject {
+                currency: Some(String::new()),
+                ..reject.clone()
+            })?,
+            _ => wtr.serialize(reject)?,
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Row of the errors file, see `write_errors()`.
+#[derive(serde::Serialize)]
+struct ErrorRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<&'a str>,
+    line: Option<u64>,
+    tx: Option<u32>,
+    code: Option<Code>,
+    error: String,
+}
+
+/// Writes errors of the run as CSV to `out`: the declined transactions, with their
+/// input lines, IDs, error codes and messages, followed by the malformed rows,
+/// with their input lines only, as far as they're known. Rows lead with the run ID,
+/// if the report has one.
+pub fn write_errors<W: io::Write>(report: &RunReport, out: W) -> Result<(), csv::Error> {
+    let mut wtr = csv::Writer::from_writer(out);
+    let run_id = report.run_id.as_deref();
+    let lines = report.lines.iter().copied().chain(std::iter::repeat(None));
+    for ((tx, e), line) in report.declined.iter().zip(lines) {
+        wtr.serialize(ErrorRow {
+            run_id,
+            line,
+            tx: Some(*tx),
+            code: Some(e.code()),
+            error: e.to_string(),
+        })?;
+    }
+    for line in &report.malformed {
+        wtr.serialize(ErrorRow {
+            run_id,
+            line: Some(*line),
+            tx: None,
+            code: None,
+            error: "malformed row".to_owned(),
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Iterator adapter accumulating time spent in pulling items out of the inner one,
+/// if asked to.
+struct Timed<'a, I> {
+    inner: I,
+    spent: Option<&'a mut Duration>,
+}
+
+impl<I: Iterator> Iterator for Timed<'_, I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(spent) = &mut self.spent else {
+            return self.inner.next();
+        };
+        let start = Instant::now();
+        let item = self.inner.next();
+        **spent += start.elapsed();
+        item
+    }
+}
+
+/// Reads record of the CSV row, by the positions of its fields if they're `mapped`,
+/// or by the names of its columns otherwise. Returns `None` if it's malformed.
+fn read_record<'a>(
+    row: &'a csv::StringRecord,
+    headers: &'a csv::StringRecord,
+    mapped: Option<&ColumnIndex>,
+) -> Option<RawRecord<'a>> {
+    match mapped {
+        Some(columns) => columns.record(row),
+        None => row.deserialize(Some(headers)).ok(),
+    }
+}
+
+/// Entries of the input which failed to be read, or to make a transaction.
+#[derive(Debug, Default)]
+struct ReadErrors {
+    parse_errors: usize,
+    /// Input lines of the entries failed to be parsed, where known.
+    malformed: Vec<u64>,
+    /// IDs of the transactions rejected, with the errors and input lines.
+    rejected: Vec<(u32, EngineError, Option<u64>)>,
+    /// Rejected transactions, if retained.
+    rejects: Vec<Reject>,
+}
+
+impl ReadErrors {
+    fn malformed(&mut self, line: Option<u64>) {
+        self.parse_errors += 1;
+        self.malformed.extend(line);
+    }
+
+    fn reject(&mut self, id: u32, e: EngineError, line: Option<u64>, reject: Option<Reject>) {
+        self.rejects.extend(reject.map(|r| r.because(&e)));
+        self.rejected.push((id, e, line));
+    }
+}
+
+/// Log records prefix carrying the run ID, if any.
+struct RunTag<'a>(Option<&'a str>);
+
+impl fmt::Display for RunTag<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "[{}] ", id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Logs a record of processing of a transaction, emitting it as a `tracing` event
+/// as well with `trace` feature, within the span of the transaction, see
+/// `Engine::span()`. Without it, there is no span, and it's a plain record.
+macro_rules! log_tx {
+    (Debug, $($arg:tt)+) => {{
+        log::debug!($($arg)+);
+        #[cfg(feature = "trace")]
+        tracing::debug!($($arg)+);
+    }};
+    (Warn, $($arg:tt)+) => {{
+        log::warn!($($arg)+);
+        #[cfg(feature = "trace")]
+        tracing::warn!($($arg)+);
+    }};
 }
 
 macro_rules! impl_transaction_handler {
     ($action:ident) => {
-        fn $action(&mut self, mut tx: Transaction) -> Result<(), String> {
+        fn $action(&mut self, mut tx: Transaction) -> Result<(), EngineError> {
+            let amount = tx.amount.ok_or(EngineError::EmptyAmount)?;
+            if let Some(limit) = self.config.max_tx_amount {
+                if amount > limit {
+                    return Err(EngineError::AmountTooLarge { amount, limit });
+                }
+            }
             tx.execute();
             match tx.state() {
-                State::Executed if !self.transactions.contains_key(&tx.id) => {
+                _ if self.transactions.contains_key(&tx.key()) => {
+                    return Err(EngineError::DuplicateTx)
+                }
+                State::Executed => {
+                    self.ensure_memory_budget(tx.client)?;
                     let acc = &mut self.get_or_create_account(tx.client);
-                    acc.$action(tx.amount.ok_or("empty amount")?)?;
+                    acc.$action(amount)?;
                 }
-                r => return Err(format!("deposit/withdrawal tx declined: {:?}", &r)),
+                r => return Err(EngineError::InvalidState(r)),
             }
             // Store succeed transaction
-            self.transactions.insert(tx.id, tx);
+            if tx.ty == Some(Tx::Withdrawal) && self.config.unstored_withdrawals {
+                return Ok(());
+            }
+            self.store(tx);
             Ok(())
         }
     };
 }
 
 macro_rules! impl_event_handler {
-    ($event:ident, $action:ident, $state:ident) => {
+    ($event:ident, $action:ident, $mirror:ident, $state:ident) => {
         #[doc = "Handles "]
         #[doc = stringify!($event)]
         #[doc = " request by performing safety checks, and performing `"]
         #[doc = stringify!($action)]
-        #[doc = "()` action on the account balance. Succeed only if the transaction in question "]
+        #[doc = "()` action on the account balance for a deposit, or `"]
+        #[doc = stringify!($mirror)]
+        #[doc = "()` for a withdrawal. Succeed only if the transaction in question "]
         #[doc = "ended up at the `"]
         #[doc = stringify!($state)]
         #[doc = "` state."]
-        fn $event(&mut self, tx: &mut Transaction) -> Result<(), String> {
-            // lookup for the disputed tx, and fail if not found
+        #[doc = ""]
+        #[doc = "Amount of the event is advisory, and ignored: the events act on the one "]
+        #[doc = "of the stored transaction, unless `Engine::with_partial_disputes()` is set, "]
+        #[doc = "when a dispute carrying an amount disputes only that part of the transaction, "]
+        #[doc = "and the events following it act on that part."]
+        fn $event(&mut self, tx: &mut Transaction) -> Result<(), EngineError> {
+            let partial = tx
+                .amount
+                .filter(|_| tx.ty == Some(Tx::Dispute) && self.config.partial_disputes);
+            // lookup for the disputed tx of the claimant, and fail if not found,
+            // so transactions of other clients can't be disputed, this is kinda authentication.
             let tx = &mut self
                 .transactions
-                .get_mut(&tx.id)
-                .ok_or("disputed transaction not found".to_string())?;
-            // ensure accounts match in the dispute claim and in the original transaction,
-            // this is kinda authentication.
-            if tx.client.ne(&tx.client) {
-                return Err("dispute account is not the transaction owner".to_string());
-            }
+                .get_mut(&tx.key())
+                .ok_or(EngineError::TxNotFound)?;
             let acc = &mut self
                 .accounts
                 .get_mut(&tx.client)
-                .ok_or("dispute account does not exist".to_string())?;
+                .ok_or(EngineError::AccountNotFound)?;
 
+            let original = tx.amount.ok_or(EngineError::EmptyAmount)?;
+            // the part disputed already, or the one being disputed, all of it by default
+            let amount = tx.disputed.or(partial).unwrap_or(original);
+            if amount > original {
+                return Err(EngineError::DisputeExceedsAmount { amount, original });
+            }
+            // charged back transaction is final, no further events apply to it
+            let from = tx.state();
+            if from == State::Reverted {
+                return Err(EngineError::AlreadyChargedBack);
+            }
+            // the transition is to take place for the action to apply,
+            // so that e.g. a repeated resolve doesn't release the funds twice,
+            // and is rolled back if the action fails
             match tx.ty {
-                // only deposit transactions can be disputed
+                // disputed deposit gets its funds held
                 Some(Tx::Deposit) => {
-                    tx.$event();
-                    match tx.state() {
-                        State::$state => acc.$action(tx.amount.ok_or("empty amount")?).map(|_| ()),
-
-                        r => Err(format!("dispute tx declined: {:?}", &r)),
-                    }
+                    tx.transition_with(Transaction::$event, |tx| match tx.state() {
+                        State::$state if from != State::$state => acc.$action(amount).map(|_| ()),
+                        r => Err(EngineError::InvalidState(r)),
+                    })?
+                }
+                // disputed withdrawal is a mirror image: its funds are credited back
+                // pending the resolution, and kept if charged back;
+                // so is a disputed transfer, for its client is the one debited
+                Some(Tx::Withdrawal) | Some(Tx::Transfer) => {
+                    tx.transition_with(Transaction::$event, |tx| match tx.state() {
+                        State::$state if from != State::$state => acc.$mirror(amount).map(|_| ()),
+                        r => Err(EngineError::InvalidState(r)),
+                    })?
                 }
-                _ => Err("dispute on this type of transaction is not allowed".to_string()),
+                _ => return Err(EngineError::NotDisputable),
             }
+            // the part charged back is kept, for the chargeback to be reversible
+            tx.disputed = matches!(tx.state(), State::Disputed | State::Reverted).then_some(amount);
+            Ok(())
         }
     };
 }
@@ -78,42 +728,2338 @@ impl Engine {
         Default::default()
     }
 
-    /// Processes transaction, updating client Account.
-    pub fn process(&mut self, mut tx: Transaction) -> Result<(), String> {
-        match tx.ty {
-            Some(Tx::Deposit) => self.deposit(tx),
-            Some(Tx::Withdrawal) => self.withdraw(tx),
-            Some(Tx::Dispute) => self.dispute(&mut tx),
-            Some(Tx::Resolve) => self.resolve(&mut tx),
-            Some(Tx::Chargeback) => self.revert(&mut tx),
-            None => Err("transaction type not specified".to_string()),
+    /// Makes engine with room made up front for the given numbers of accounts
+    /// and stored transactions, sparing rehashing of the maps as they grow,
+    /// e.g. for inputs of known size.
+    pub fn with_capacity(accounts: usize, transactions: usize) -> Self {
+        let mut engine = Self::new();
+        engine.accounts.reserve(accounts);
+        engine.transactions.reserve(transactions);
+        engine.order.reserve(transactions);
+        engine
+    }
+}
+
+impl<const DIGITS: u32> Engine<DIGITS> {
+    /// Sets maximum amount allowed for a single deposit or withdrawal.
+    /// Transactions above it are declined before being applied.
+    pub fn with_max_tx_amount(mut self, limit: Amount) -> Self {
+        self.config.max_tx_amount = Some(limit);
+        self
+    }
+
+    /// Sets run (correlation) ID to be included in all log records of the engine,
+    /// so that they could be tied to the broader request trace.
+    pub fn with_run_id(mut self, id: impl Into<String>) -> Self {
+        self.config.run_id = Some(id.into());
+        self
+    }
+
+    /// Makes disputes referencing transactions not seen yet to be deferred until
+    /// the end of input, instead of being declined right away.
+    /// Resolves and chargebacks following a deferred dispute get deferred too,
+    /// to keep their order.
+    pub fn with_deferred_disputes(mut self, defer: bool) -> Self {
+        self.config.defer_disputes = defer;
+        self
+    }
+
+    /// Makes disputes carrying an amount dispute only that part of the transaction,
+    /// or all of it, as by default.
+    pub fn with_partial_disputes(mut self, partial: bool) -> Self {
+        self.config.partial_disputes = partial;
+        self
+    }
+
+    /// Makes the whole input to be read and reordered by transaction ID before processing,
+    /// with `tie_break` policy for deposits and withdrawals sharing an ID.
+    pub fn with_reorder(mut self, tie_break: TieBreak) -> Self {
+        self.config.reorder = Some(tie_break);
+        self
+    }
+
+    /// Sets a soft memory budget, in bytes, after which new accounts and transactions
+    /// are refused with `EngineError::MemoryBudgetExceeded`.
+    /// Usage is approximated by the sizes of the stored entries, not accounting for
+    /// the maps overhead, so it's a coarse safety limit rather than an exact one.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.config.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Makes runs record time spent in parsing and processing into `Summary::timing`.
+    pub fn with_timing(mut self, timing: bool) -> Self {
+        self.config.timing = timing;
+        self
+    }
+
+    /// Makes the output table group accounts by their `locked` status.
+    pub fn with_grouping(mut self, grouping: LockGrouping) -> Self {
+        self.config.grouping = Some(grouping);
+        self
+    }
+
+    /// Makes the output table break held funds out by the reason, or not.
+    pub fn with_held_breakdown(mut self, breakdown: bool) -> Self {
+        self.config.held_breakdown = breakdown;
+        self
+    }
+
+    /// Makes the output table carry `last_seq` column, the position of the last
+    /// transaction applied to each account, or not.
+    pub fn with_last_seq(mut self, last_seq: bool) -> Self {
+        self.config.last_seq = last_seq;
+        self
+    }
+
+    /// Makes the output table show available balance of an underwater account
+    /// negative, or clamped to zero.
+    pub fn with_signed_available(mut self, signed: bool) -> Self {
+        self.config.signed_available = signed;
+        self
+    }
+
+    /// Makes the output table treat rows of accounts with more funds held than they have
+    /// as given, see `HeldOverflow`.
+    pub fn with_held_overflow(mut self, treatment: HeldOverflow) -> Self {
+        self.config.held_overflow = Some(treatment);
+        self
+    }
+
+    /// Makes the output table follow the accounts of each currency with
+    /// a subtotal row, with a blank client: sums of their balances, or not.
+    pub fn with_currency_subtotals(mut self, subtotals: bool) -> Self {
+        self.config.currency_subtotals = subtotals;
+        self
+    }
+
+    /// Makes input amounts to be parsed by the given `parser`, instead of
+    /// the default `DecimalParser`.
+    pub fn with_amount_parser(mut self, parser: impl AmountParser + 'static) -> Self {
+        self.config.amount_parser = Some(Arc::new(parser));
+        self.config.lenient_amounts = false;
+        self
+    }
+
+    /// Makes input amounts with more decimal digits than the engine precision
+    /// to be rounded as given, by the default `DecimalParser`, instead of truncated,
+    /// or kept as they are with `decimal` feature.
+    pub fn with_rounding(mut self, rounding: RoundingMode) -> Self {
+        let rounding = Some(rounding);
+        self.config.amount_parser = Some(Arc::new(DecimalParser::<DIGITS> { rounding }));
+        self.config.lenient_amounts = false;
+        self
+    }
+
+    /// Makes input amounts to be parsed leniently, taking ones with a currency symbol
+    /// or grouped digits, as in `$1,234.56`, with `LenientParser`, or by the default
+    /// `DecimalParser`, which rejects them. The latter only replaces the lenient
+    /// parser, keeping one set up otherwise, e.g. with `with_rounding()`.
+    pub fn with_lenient_amounts(mut self, lenient: bool) -> Self {
+        match lenient {
+            true => self.config.amount_parser = Some(Arc::new(LenientParser::<DIGITS>::default())),
+            false if self.config.lenient_amounts => self.config.amount_parser = None,
+            false => (),
         }
+        self.config.lenient_amounts = lenient;
+        self
     }
 
-    impl_transaction_handler!(deposit);
-    impl_transaction_handler!(withdraw);
-    impl_event_handler!(dispute, hold, Disputed);
-    impl_event_handler!(resolve, release, Executed);
-    impl_event_handler!(revert, chargeback, Reverted);
+    /// Makes the engine track number of times each transaction gets disputed,
+    /// warning on and flagging ones disputed more than `threshold` times, as rapid
+    /// dispute-resolve cycles on the same transaction are a red flag for fraud.
+    pub fn with_dispute_cycle_threshold(mut self, threshold: u32) -> Self {
+        self.config.dispute_cycle_threshold = Some(threshold);
+        self
+    }
 
-    fn get_or_create_account(&mut self, id: u32) -> &mut Account {
-        if !&self.accounts.contains_key(&id) {
-            self.accounts.insert(id, Account::new(id));
+    /// Makes the output table a report of funds currently held: only accounts having
+    /// them, with the largest held amounts first. Takes precedence over grouping.
+    pub fn with_held_only(mut self, held_only: bool) -> Self {
+        self.config.held_only = held_only;
+        self
+    }
+
+    /// Makes manual adjustments apply to frozen accounts, which they are declined on
+    /// by default.
+    pub fn with_frozen_adjustments(mut self, allow: bool) -> Self {
+        self.config.adjust_frozen = allow;
+        self
+    }
+
+    /// Makes privileged operations, namely `Unlock`, `ReverseChargeback` and `Adjustment`
+    /// transactions, accepted from input, the latter applied as `adjust()` does,
+    /// negative amounts debiting the account.
+    /// They are declined by default, so that ordinary batches can't unfreeze accounts.
+    pub fn with_admin_ops(mut self, admin_ops: bool) -> Self {
+        self.config.admin_ops = admin_ops;
+        self
+    }
+
+    /// Makes declined transactions retained in `Summary::rejects` along with the reasons,
+    /// for them to be written out with `write_rejects()`, fixed and resubmitted.
+    /// Rows which failed to be parsed are only counted, as there is nothing to retain.
+    pub fn with_rejects(mut self, keep: bool) -> Self {
+        self.config.keep_rejects = keep;
+        self
+    }
+
+    /// Sets format of the output accounts table, CSV by default.
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// Sets compression of the input files, told by their names by default,
+    /// see `Compression::of()`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.config.compression = Some(compression);
+        self
+    }
+
+    /// Sets table the SQL output inserts accounts into, `accounts` by default.
+    pub fn with_sql_table(mut self, table: impl Into<String>) -> Self {
+        self.config.sql_table = Some(table.into());
+        self
+    }
+
+    /// Sets whether chargebacks of withdrawals and transfers freeze the account,
+    /// as chargebacks of deposits do, which is the default.
+    /// Such a chargeback credits the client back rather than takes the funds away, so
+    /// with `freeze` off the account stays open for subsequent refunds and deposits.
+    /// Either way, the chargeback is final for the transaction.
+    pub fn with_withdrawal_chargeback_freeze(mut self, freeze: bool) -> Self {
+        self.config.unfrozen_credit_chargebacks = !freeze;
+        self
+    }
+
+    /// Sets whether successful withdrawals are stored, which is the default.
+    /// Unless disputes of withdrawals are expected, they are better left out, sparing
+    /// the memory taken by them: such disputes are declined with `EngineError::TxNotFound`.
+    /// Neither is a withdrawal repeating the ID of another one declined as a duplicate then.
+    pub fn with_store_withdrawals(mut self, store: bool) -> Self {
+        self.config.unstored_withdrawals = !store;
+        self
+    }
+
+    /// Makes input rows with a number of fields other than the header has declined
+    /// with `EngineError::ArityMismatch`, instead of having the missing fields blank,
+    /// as a shifted column could otherwise assign values to the wrong fields.
+    /// Events may still omit the trailing fields, e.g. the amount.
+    pub fn with_strict_arity(mut self, strict: bool) -> Self {
+        self.config.strict_arity = strict;
+        self
+    }
+
+    /// Makes a dispute, resolve or chargeback repeating the last event applied
+    /// to its transaction, and coming within `window` transactions after it,
+    /// accepted as a harmless no-op, as at-least-once delivery may bring it twice.
+    /// Repeats are declined otherwise, and never have any effect either way.
+    pub fn with_redelivery_window(mut self, window: u64) -> Self {
+        self.config.redelivery_window = Some(window);
+        self
+    }
+
+    /// Sets dispute window: number of transactions processed since a transaction,
+    /// after which it can't be disputed anymore, and such disputes are declined
+    /// with `EngineError::DisputeExpired`. Events of the disputes opened within
+    /// the window apply regardless.
+    pub fn with_max_dispute_age(mut self, age: u64) -> Self {
+        self.config.max_dispute_age = Some(age);
+        self
+    }
+
+    /// Sets time after a transaction is stored, after which it can't be disputed
+    /// anymore, and such disputes are declined with `EngineError::DisputeTimedOut`.
+    pub fn with_dispute_expiry(mut self, expiry: Duration) -> Self {
+        self.config.dispute_expiry = Some(expiry);
+        self
+    }
+
+    /// Sets number of the last money-moving transactions kept track of,
+    /// for them to be rolled back with `rollback()`.
+    pub fn with_rollback_depth(mut self, depth: usize) -> Self {
+        self.config.rollback_depth = Some(depth);
+        self
+    }
+
+    /// Sets number of disputes a client may have open at once, i.e. not resolved
+    /// or charged back yet. Disputes beyond it are declined with
+    /// `EngineError::TooManyOpenDisputes`.
+    pub fn with_max_open_disputes(mut self, limit: u32) -> Self {
+        self.config.max_open_disputes = Some(limit);
+        self
+    }
+
+    /// Sets number of deposits, withdrawals and transfers a client may have applied,
+    /// for a flood of them not to exhaust the memory. Ones beyond it are declined
+    /// with `EngineError::LimitExceeded`, events of the ones applied are still accepted.
+    pub fn with_max_txs_per_client(mut self, limit: u32) -> Self {
+        self.config.max_txs_per_client = Some(limit);
+        self
+    }
+
+    /// Makes CSV input read with the columns of the given names, rather than
+    /// the standard `type, client, tx, amount` ones, e.g. for partners' feeds.
+    /// Fields are taken from the rows by the positions of the columns in the header.
+    pub fn with_column_map(mut self, columns: ColumnMap) -> Self {
+        self.config.column_map = Some(columns);
+        self
+    }
+
+    /// Sets key shared with the producer of the input, for signatures of its rows
+    /// to be verified, see `hmac` module. Rows with wrong or missing ones are declined
+    /// with `EngineError::SignatureInvalid`.
+    #[cfg(feature = "hmac")]
+    pub fn with_signing_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.config.signing_key = Some(SigningKey::new(key));
+        self
+    }
+
+    /// Sets source of the current time, e.g. `MockClock` for the tests
+    /// of time-dependent behavior.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.config.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Makes the engine append each transaction applied, by `process()` or `adjust()`,
+    /// to the write-ahead log at `path`, created if there is none, for a crashed run
+    /// to be recovered with `replay_wal()`, see `wal` module. Shards of sharded runs
+    /// don't write it. Failures to write it are logged, not to fail the transactions
+    /// applied already.
+    pub fn with_wal(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.wal = Some(Wal::open(path)?);
+        Ok(self)
+    }
+
+    /// Processes transaction, updating client Account, the one in the currency
+    /// of the transaction if it has one.
+    pub fn process(&mut self, tx: Transaction) -> Result<(), EngineError> {
+        self.process_with_result(tx).map(|_| ())
+    }
+
+    /// Same as `process()`, but returns the account of the client as it is
+    /// after the transaction, for the caller to learn the new balances at once,
+    /// e.g. in interactive use. For a transfer, it's the account debited.
+    pub fn process_with_result(&mut self, tx: Transaction) -> Result<Account, EngineError> {
+        match tx.currency.clone() {
+            Some(currency) => self.in_ledger(&currency, |engine| engine.process_in(tx)),
+            None => self.process_in(tx),
         }
-        self.accounts.get_mut(&id).unwrap()
     }
 
-    pub fn accounts(&self) -> Values<u32, Account> {
-        self.accounts.values()
+    /// Runs `f` with the accounts in the `currency` swapped in for the ones
+    /// in the implicit currency, for it to apply transactions in that currency.
+    /// The latter are parked in place of the former meanwhile, still counting
+    /// towards the memory budget.
+    fn in_ledger<T>(&mut self, currency: &str, f: impl FnOnce(&mut Self) -> T) -> T {
+        match self.ledgers.get_mut(currency) {
+            Some(ledger) => mem::swap(&mut self.accounts, ledger),
+            None => {
+                let implicit = mem::take(&mut self.accounts);
+                self.ledgers.insert(currency.to_string(), implicit);
+            }
+        }
+        let res = f(self);
+        if let Some(implicit) = self.ledgers.get_mut(currency) {
+            mem::swap(&mut self.accounts, implicit);
+        }
+        // a currency gets its ledger once it has an account
+        if self.ledgers.get(currency).is_some_and(IdMap::is_empty) {
+            self.ledgers.remove(currency);
+        }
+        res
     }
 
-    #[cfg(test)]
-    pub fn transactions(&self) -> Values<u32, Transaction> {
-        self.transactions.values()
+    /// Processes transaction against the accounts swapped in, returning the account
+    /// of the client among them as it is after the transaction.
+    fn process_in(&mut self, mut tx: Transaction) -> Result<Account, EngineError> {
+        let (id, client, ty) = (tx.id, tx.client, tx.ty.clone());
+        self.seq += 1;
+        #[cfg(feature = "trace")]
+        let _span = self.span(&tx).entered();
+        let tag = RunTag(self.config.run_id.as_deref());
+        if self.is_redelivery(&tx) {
+            log_tx!(
+                Debug,
+                "{}tx {} of client {} redelivered, ignored",
+                tag,
+                id,
+                client
+            );
+            return Ok(self.account_or_blank(client));
+        }
+        let clients = [Some(client), tx.target];
+        let before = self.accounts_before(clients);
+        let currency = self.config.rollback_depth.and_then(|_| tx.currency.clone());
+        let stored = self.order.len();
+        let logged = self.wal.is_some().then(|| TransactionSnapshot::from(&tx));
+        let res = match &ty {
+            // events of other currencies don't find their transactions
+            Some(ty) if ty.is_event() && !self.is_same_currency(&tx) => {
+                Err(EngineError::TxNotFound)
+            }
+            Some(Tx::Deposit) => self
+                .ensure_client_txs(client)
+                .and_then(|()| self.deposit(tx)),
+            Some(Tx::Withdrawal) => self
+                .ensure_client_txs(client)
+                .and_then(|()| self.withdraw(tx)),
+            Some(Tx::Transfer) => self
+                .ensure_client_txs(client)
+                .and_then(|()| self.transfer(tx)),
+            Some(Tx::Dispute) => self
+                .ensure_dispute_age(&tx)
+                .and_then(|()| self.ensure_open_disputes(client))
+                .and_then(|()| self.dispute(&mut tx)),
+            Some(Tx::Resolve) => self
+                .ensure_disputed(&tx)
+                .and_then(|()| self.resolve(&mut tx)),
+            Some(Tx::Chargeback) => self
+                .ensure_disputed(&tx)
+                .and_then(|()| self.revert(&mut tx))
+                .map(|()| self.settle_credit_chargeback((client, id))),
+            Some(Tx::Adjustment) if !self.config.admin_ops => Err(EngineError::AdminOnly),
+            Some(Tx::Adjustment) => self.adjust_tx(tx),
+            Some(Tx::Unlock) if !self.config.admin_ops => Err(EngineError::AdminOnly),
+            Some(Tx::Unlock) => self.unlock(client),
+            Some(Tx::ReverseChargeback) if !self.config.admin_ops => Err(EngineError::AdminOnly),
+            Some(Tx::ReverseChargeback) => self.reverse_chargeback((client, id)),
+            Some(Tx::Unknown(name)) => Err(EngineError::UnknownType(name.clone())),
+            None => Err(EngineError::NoType),
+        };
+
+        let tag = RunTag(self.config.run_id.as_deref());
+        match &res {
+            Ok(()) => log_tx!(Debug, "{}tx {} of client {} processed", tag, id, client),
+            Err(e) => log_tx!(
+                Warn,
+                "{}tx {} of client {} declined: {}",
+                tag,
+                id,
+                client,
+                e
+            ),
+        }
+        if res.is_ok() && matches!(ty, Some(Tx::Dispute)) {
+            self.track_dispute_cycle(id, client);
+        }
+        if res.is_ok() {
+            self.touch(clients);
+            self.track_open_disputes(client, ty.as_ref());
+            self.track_client_txs(client, ty.as_ref());
+            let stored = self.order.len() > stored;
+            self.track_history((client, id), ty.as_ref(), before, currency, stored);
+            if let Some(tx) = logged {
+                self.append_wal(tx);
+            }
+        }
+        match ty {
+            Some(ty) if res.is_ok() && ty.is_event() && self.config.redelivery_window.is_some() => {
+                self.last_events.insert((client, id), (ty, self.seq));
+            }
+            _ => (),
+        }
+        res.map(|()| self.account_or_blank(client))
     }
 
-    #[cfg(test)]
-    pub fn get_account(&self, id: &u32) -> Option<&Account> {
-        self.accounts.get(id)
+    /// Returns account of the client among the ones swapped in, a blank one if it has none,
+    /// e.g. upon a redelivered event.
+    fn account_or_blank(&self, client: u32) -> Account {
+        self.accounts
+            .get(&client)
+            .copied()
+            .unwrap_or_else(|| Account::new(client))
+    }
+
+    /// Whether the event is in the currency of the transaction it refers to, if any.
+    fn is_same_currency(&self, event: &Transaction) -> bool {
+        self.transactions
+            .get(&event.key())
+            .is_none_or(|tx| tx.currency == event.currency)
+    }
+
+    /// Fails if the transaction disputed is older than the dispute window,
+    /// either in transactions or in time.
+    fn ensure_dispute_age(&self, dispute: &Transaction) -> Result<(), EngineError> {
+        let Some(tx) = self.transactions.get(&dispute.key()) else {
+            return Ok(());
+        };
+        if let Some(limit) = self.config.max_dispute_age {
+            let age = self.seq - tx.seq;
+            if age > limit {
+                return Err(EngineError::DisputeExpired { age, limit });
+            }
+        }
+        if let (Some(limit), Some(at)) = (self.config.dispute_expiry, tx.at) {
+            let age = self.now().duration_since(at).unwrap_or_default();
+            if age > limit {
+                return Err(EngineError::DisputeTimedOut { age, limit });
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if the transaction resolved or charged back is not under dispute,
+    /// for the funds not to be released or taken while they aren't held.
+    /// Charged back one is left for the event handler to decline as such.
+    fn ensure_disputed(&self, event: &Transaction) -> Result<(), EngineError> {
+        match self.transactions.get(&event.key()).map(Transaction::state) {
+            Some(State::Disputed) | Some(State::Reverted) | None => Ok(()),
+            Some(state) => Err(EngineError::NotDisputed(state)),
+        }
+    }
+
+    /// Fails if the client has as many disputes open as the limit.
+    fn ensure_open_disputes(&self, client: u32) -> Result<(), EngineError> {
+        let Some(limit) = self.config.max_open_disputes else {
+            return Ok(());
+        };
+        if self.open_disputes.get(&client).copied().unwrap_or_default() >= limit {
+            return Err(EngineError::TooManyOpenDisputes { limit });
+        }
+        Ok(())
+    }
+
+    /// Counts disputes of the client opened and closed by the event applied,
+    /// if their number is limited.
+    fn track_open_disputes(&mut self, client: u32, ty: Option<&Tx>) {
+        if self.config.max_open_disputes.is_none() {
+            return;
+        }
+        let open = self.open_disputes.entry(client).or_default();
+        match ty {
+            Some(Tx::Dispute) => *open += 1,
+            Some(Tx::Resolve) | Some(Tx::Chargeback) => *open = open.saturating_sub(1),
+            _ => (),
+        }
+    }
+
+    /// Fails if the client has had as many transactions applied as the limit.
+    fn ensure_client_txs(&self, client: u32) -> Result<(), EngineError> {
+        let Some(limit) = self.config.max_txs_per_client else {
+            return Ok(());
+        };
+        if self.client_txs.get(&client).copied().unwrap_or_default() >= limit {
+            return Err(EngineError::LimitExceeded { limit });
+        }
+        Ok(())
     }
+
+    /// Counts transactions of the client applied, if their number is limited.
+    fn track_client_txs(&mut self, client: u32, ty: Option<&Tx>) {
+        if self.config.max_txs_per_client.is_none() {
+            return;
+        }
+        if let Some(Tx::Deposit | Tx::Withdrawal | Tx::Transfer) = ty {
+            *self.client_txs.entry(client).or_default() += 1;
+        }
+    }
+
+    /// Returns span of processing of the transaction, carrying its IDs and type,
+    /// and for an event, type and amount of the transaction it targets, if found.
+    #[cfg(feature = "trace")]
+    fn span(&self, tx: &Transaction) -> tracing::Span {
+        let span = tracing::info_span!(
+            "tx",
+            tx = tx.id,
+            client = tx.client,
+            ty = ?tx.ty,
+            original_ty = tracing::field::Empty,
+            original_amount = tracing::field::Empty,
+        );
+        let original = tx
+            .ty
+            .as_ref()
+            .filter(|ty| ty.is_event())
+            .and_then(|_| self.transactions.get(&tx.key()));
+        if let Some(original) = original {
+            let amount = original.amount.unwrap_or_default();
+            span.record("original_ty", tracing::field::debug(&original.ty));
+            span.record("original_amount", fmt_scaled_amount(amount, DIGITS));
+        }
+        span
+    }
+
+    /// Marks the accounts of the clients with the position of the transaction just applied
+    /// to them among the applied ones.
+    fn touch(&mut self, clients: [Option<u32>; 2]) {
+        self.applied += 1;
+        for client in clients.into_iter().flatten() {
+            if let Some(acc) = self.accounts.get_mut(&client) {
+                acc.last_seq = self.applied;
+            }
+        }
+    }
+
+    /// Appends the transaction just applied to the write-ahead log, if set,
+    /// along with the state of the transaction stored under its key.
+    fn append_wal(&mut self, tx: TransactionSnapshot) {
+        let Some(wal) = &mut self.wal else {
+            return;
+        };
+        let (id, client) = (tx.id, tx.client);
+        let record = WalRecord {
+            seq: self.seq,
+            applied: self.applied,
+            state: self.transactions.get(&(client, id)).and_then(|tx| tx.state),
+            tx,
+        };
+        if let Err(e) = wal.append(&record) {
+            log::error!(
+                "{}tx {} of client {} not logged to the WAL: {}",
+                RunTag(self.config.run_id.as_deref()),
+                id,
+                client,
+                e
+            );
+        }
+    }
+
+    /// Returns the accounts of the clients as they are, `None` for missing ones,
+    /// if the history is tracked.
+    fn accounts_before(&self, clients: [Option<u32>; 2]) -> Vec<(u32, Option<Account>)> {
+        if self.config.rollback_depth.is_none() {
+            return vec![];
+        }
+        clients
+            .into_iter()
+            .flatten()
+            .map(|id| (id, self.accounts.get(&id).copied()))
+            .collect()
+    }
+
+    /// Records the money-moving transaction applied into the history, along with
+    /// the accounts `before` it, the `currency` of their ledger and whether it was
+    /// `stored`, dropping the oldest one beyond the rollback depth.
+    /// Other ones applied make the history irreversible, so it's cleared.
+    fn track_history(
+        &mut self,
+        key: TxKey,
+        ty: Option<&Tx>,
+        before: Vec<(u32, Option<Account>)>,
+        currency: Option<String>,
+        stored: bool,
+    ) {
+        let Some(depth) = self.config.rollback_depth else {
+            return;
+        };
+        match ty {
+            Some(ty @ (Tx::Deposit | Tx::Withdrawal | Tx::Transfer | Tx::Adjustment)) => {
+                self.history.push_back(Applied {
+                    key,
+                    ty: ty.clone(),
+                    currency,
+                    stored,
+                    accounts: before,
+                });
+                if self.history.len() > depth {
+                    self.history.pop_front();
+                }
+            }
+            _ => self.history.clear(),
+        }
+    }
+
+    /// Stores the transaction applied, stamped, keeping track of the order of storing.
+    fn store(&mut self, mut tx: Transaction) {
+        self.stamp(&mut tx);
+        self.order.push(tx.key());
+        self.transactions.insert(tx.key(), tx);
+    }
+
+    /// Marks transaction being stored with its position in the sequence,
+    /// and with the current time, if needed for the dispute expiry.
+    fn stamp(&self, tx: &mut Transaction) {
+        tx.seq = self.seq;
+        if self.config.dispute_expiry.is_some() {
+            tx.at = Some(self.now());
+        }
+    }
+
+    /// Returns current time of the configured clock, the system one by default.
+    fn now(&self) -> SystemTime {
+        match &self.config.clock {
+            Some(clock) => clock.now(),
+            None => SystemClock.now(),
+        }
+    }
+
+    /// Whether the event repeats the last one applied to its transaction,
+    /// within the redelivery window.
+    fn is_redelivery(&self, tx: &Transaction) -> bool {
+        let Some(window) = self.config.redelivery_window else {
+            return false;
+        };
+        match (&tx.ty, self.last_events.get(&tx.key())) {
+            (Some(ty), Some((last, seq))) => ty == last && self.seq - seq <= window,
+            _ => false,
+        }
+    }
+
+    /// Handles transfer by withdrawing its amount from the client account and depositing
+    /// it to the target one. Either both succeed, or neither account is modified.
+    /// The transfer is stored under the client, as a withdrawal would be.
+    fn transfer(&mut self, mut tx: Transaction) -> Result<(), EngineError> {
+        let amount = tx.amount.ok_or(EngineError::EmptyAmount)?;
+        let target = tx.target.ok_or(EngineError::MissingField("target"))?;
+        if target == tx.client {
+            return Err(EngineError::SelfTransfer);
+        }
+        if let Some(limit) = self.config.max_tx_amount {
+            if amount > limit {
+                return Err(EngineError::AmountTooLarge { amount, limit });
+            }
+        }
+        if self.transactions.contains_key(&tx.key()) {
+            return Err(EngineError::DuplicateTx);
+        }
+        tx.execute();
+        if tx.state() != State::Executed {
+            return Err(EngineError::InvalidState(tx.state()));
+        }
+        self.ensure_memory_budget(target)?;
+        // apply to copies first, so that a failure on either side leaves both intact
+        let account = |id| self.accounts.get(&id).copied().unwrap_or(Account::new(id));
+        let (mut from, mut to) = (account(tx.client), account(target));
+        from.withdraw(amount)?;
+        to.deposit(amount)?;
+
+        self.accounts.insert(from.id, from);
+        self.accounts.insert(to.id, to);
+        self.store(tx);
+        Ok(())
+    }
+
+    /// Unfreezes the client account, e.g. frozen by an erroneous chargeback.
+    /// Fails if the account doesn't exist or is not frozen.
+    fn unlock(&mut self, client: u32) -> Result<(), EngineError> {
+        let acc = self
+            .accounts
+            .get_mut(&client)
+            .ok_or(EngineError::AccountNotFound)?;
+        if !acc.locked {
+            return Err(EngineError::NotFrozen);
+        }
+        acc.unlock();
+        let tag = RunTag(self.config.run_id.as_deref());
+        log::info!("{}account of client {} unlocked", tag, client);
+        Ok(())
+    }
+
+    /// Reverses chargeback of the `key` transaction, reinstating it as executed:
+    /// the amount charged back from a deposit is credited again, and the one kept
+    /// by the client for a withdrawal or a transfer is debited again. Unfreezes
+    /// the account, as the chargeback froze it.
+    /// Fails unless the transaction is charged back, i.e. at the `Reverted` state,
+    /// and as withdraw does if the amount to debit is not available.
+    fn reverse_chargeback(&mut self, key: TxKey) -> Result<(), EngineError> {
+        let tx = self
+            .transactions
+            .get_mut(&key)
+            .ok_or(EngineError::TxNotFound)?;
+        let acc = self
+            .accounts
+            .get_mut(&tx.client)
+            .ok_or(EngineError::AccountNotFound)?;
+        if tx.state() != State::Reverted {
+            return Err(EngineError::InvalidState(tx.state()));
+        }
+        let amount = tx.disputed.or(tx.amount).ok_or(EngineError::EmptyAmount)?;
+        // apply to a copy, for the account to stay frozen if it fails
+        let mut reinstated = *acc;
+        reinstated.unlock();
+        match tx.ty {
+            Some(Tx::Deposit) => reinstated.deposit(amount)?,
+            Some(Tx::Withdrawal) | Some(Tx::Transfer) => reinstated.revoke_credit(amount)?,
+            _ => return Err(EngineError::NotDisputable),
+        };
+        *acc = reinstated;
+        tx.reinstate();
+        tx.disputed = None;
+        let tag = RunTag(self.config.run_id.as_deref());
+        log::info!(
+            "{}chargeback of tx {} of client {} reversed",
+            tag,
+            key.1,
+            key.0
+        );
+        Ok(())
+    }
+
+    /// Unfreezes the account frozen by a chargeback of the `key` withdrawal or transfer,
+    /// if configured to leave such accounts unfrozen.
+    /// The account could not have been frozen before, as the chargeback would've been
+    /// declined then.
+    fn settle_credit_chargeback(&mut self, key: TxKey) {
+        if !self.config.unfrozen_credit_chargebacks {
+            return;
+        }
+        let Some(tx) = self.transactions.get(&key) else {
+            return;
+        };
+        if matches!(tx.ty, Some(Tx::Withdrawal) | Some(Tx::Transfer)) {
+            if let Some(acc) = self.accounts.get_mut(&tx.client) {
+                acc.unlock();
+            }
+        }
+    }
+
+    /// Holds funds on a client account for a reason other than a dispute,
+    /// e.g. pending a review. They stay held until released with `release_funds()`.
+    pub fn hold_funds(
+        &mut self,
+        client: u32,
+        amount: Amount,
+        reason: HoldReason,
+    ) -> Result<(), EngineError> {
+        self.hold_funds_in(client, "", amount, reason)
+    }
+
+    /// Same as `hold_funds()`, for the client account in the currency,
+    /// the implicit one for a blank currency.
+    pub fn hold_funds_in(
+        &mut self,
+        client: u32,
+        currency: &str,
+        amount: Amount,
+        reason: HoldReason,
+    ) -> Result<(), EngineError> {
+        self.account_in_mut(client, currency)?
+            .hold_for(reason, amount)
+            .map(|_| ())
+    }
+
+    /// Releases funds held on a client account with `hold_funds()` for the reason given.
+    pub fn release_funds(
+        &mut self,
+        client: u32,
+        amount: Amount,
+        reason: HoldReason,
+    ) -> Result<(), EngineError> {
+        self.release_funds_in(client, "", amount, reason)
+    }
+
+    /// Same as `release_funds()`, for the client account in the currency,
+    /// the implicit one for a blank currency.
+    pub fn release_funds_in(
+        &mut self,
+        client: u32,
+        currency: &str,
+        amount: Amount,
+        reason: HoldReason,
+    ) -> Result<(), EngineError> {
+        self.account_in_mut(client, currency)?
+            .release_for(reason, amount)
+            .map(|_| ())
+    }
+
+    /// Sets the overdraft limit of a client account: how far its available balance
+    /// may drop below zero upon a withdrawal, zero for no overdraft.
+    pub fn set_overdraft_limit(&mut self, client: u32, limit: Amount) -> Result<(), EngineError> {
+        self.set_overdraft_limit_in(client, "", limit)
+    }
+
+    /// Same as `set_overdraft_limit()`, for the client account in the currency,
+    /// the implicit one for a blank currency.
+    pub fn set_overdraft_limit_in(
+        &mut self,
+        client: u32,
+        currency: &str,
+        limit: Amount,
+    ) -> Result<(), EngineError> {
+        self.account_in_mut(client, currency)?.overdraft_limit = limit;
+        Ok(())
+    }
+
+    /// Returns the client account in the currency, the implicit one for a blank currency,
+    /// failing with `EngineError::AccountNotFound` if there is no such account.
+    fn account_in_mut(&mut self, client: u32, currency: &str) -> Result<&mut Account, EngineError> {
+        let accounts = match currency {
+            "" => Some(&mut self.accounts),
+            currency => self.ledgers.get_mut(currency),
+        };
+        accounts
+            .and_then(|accounts| accounts.get_mut(&client))
+            .ok_or(EngineError::AccountNotFound)
+    }
+
+    /// Counts dispute on the transaction, warning if it exceeds the cycle threshold.
+    fn track_dispute_cycle(&mut self, id: u32, client: u32) {
+        let Some(threshold) = self.config.dispute_cycle_threshold else {
+            return;
+        };
+        let cycles = self.dispute_cycles.entry((client, id)).or_default();
+        *cycles += 1;
+        if *cycles > threshold {
+            let tag = RunTag(self.config.run_id.as_deref());
+            log::warn!(
+                "{}tx {} of client {} disputed {} times, exceeding threshold of {}",
+                tag,
+                id,
+                client,
+                cycles,
+                threshold
+            );
+        }
+    }
+
+    /// Returns client and transaction IDs of the transactions disputed more times
+    /// than the cycle threshold, sorted.
+    pub fn flagged_disputes(&self) -> Vec<TxKey> {
+        let Some(threshold) = self.config.dispute_cycle_threshold else {
+            return vec![];
+        };
+        let mut ids = self
+            .dispute_cycles
+            .iter()
+            .filter(|(_, cycles)| **cycles > threshold)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Applies a manual correction of the client's total balance by a signed `delta`,
+    /// bypassing the availability checks. The adjustment is stored as a transaction
+    /// with the given `id`, along with its `reason`, for the audit.
+    pub fn adjust(
+        &mut self,
+        id: u32,
+        client: u32,
+        delta: i64,
+        reason: impl Into<String>,
+    ) -> Result<(), EngineError> {
+        self.adjust_with(Transaction::adjustment(id, client, delta, reason.into()))
+    }
+
+    /// Applies the executed adjustment as `adjust()` does, tracking the change
+    /// and logging it to the WAL.
+    fn adjust_with(&mut self, tx: Transaction) -> Result<(), EngineError> {
+        let (id, client) = (tx.id, tx.client);
+        let before = self.accounts_before([Some(client), None]);
+        self.apply_adjustment(tx)?;
+        self.touch([Some(client), None]);
+        // applied to the accounts swapped in, as of the implicit currency
+        self.track_history((client, id), Some(&Tx::Adjustment), before, None, true);
+        if let Some(tx) = self.wal.as_ref().and(self.transactions.get(&(client, id))) {
+            self.append_wal(TransactionSnapshot::from(tx));
+        }
+        Ok(())
+    }
+
+    /// Handles adjustment read from input, allowed with admin operations only,
+    /// as `adjust()` does, by the signed amount of the record.
+    fn adjust_tx(&mut self, mut tx: Transaction) -> Result<(), EngineError> {
+        tx.state = Some(TxStateKind::Executed);
+        self.apply_adjustment(tx)
+    }
+
+    /// Applies the executed adjustment to the client's account and stores it,
+    /// failing if its amount doesn't fit the signed delta `Account::adjust()` takes.
+    fn apply_adjustment(&mut self, tx: Transaction) -> Result<(), EngineError> {
+        let (id, client) = (tx.id, tx.client);
+        let (amount, debit) = adjustment_amount(&tx)?;
+        if self.transactions.contains_key(&(client, id)) {
+            return Err(EngineError::DuplicateTx);
+        }
+        self.ensure_memory_budget(client)?;
+        let mut acc = self
+            .accounts
+            .get(&client)
+            .copied()
+            .unwrap_or(Account::new(client));
+        if acc.locked && !self.config.adjust_frozen {
+            return Err(EngineError::AccountFrozen);
+        }
+        acc.adjust_by(amount, debit)?;
+
+        let reason = tx.adjustment.as_ref().map_or("", |adj| &adj.reason);
+        let tag = RunTag(self.config.run_id.as_deref());
+        log::info!(
+            "{}tx {} of client {} adjusted balance by {}{}: {}",
+            tag,
+            id,
+            client,
+            if debit { "-" } else { "" },
+            fmt_scaled_amount(amount, DIGITS),
+            reason
+        );
+        self.accounts.insert(client, acc);
+        self.store(tx);
+        Ok(())
+    }
+
+    /// Writes stored transactions as CSV to `out`, sorted by ID, then by client,
+    /// including manual adjustments with their reasons.
+    pub fn export_transactions<W: io::Write>(&self, out: W) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(out);
+        let mut txs = self.transactions.values().collect::<Vec<_>>();
+        txs.sort_unstable_by_key(|tx| (tx.id, tx.client));
+        for tx in txs {
+            wtr.serialize(TransactionSer::<DIGITS>::from(tx))?
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Processes transactions from the CSV file, and writes resulting accounts table
+    /// to `out`. Given a directory, processes all `*.csv` files within it instead,
+    /// `*.csv.gz` ones included, one after another in lexical order of their names,
+    /// skipping other files. Compressed files get decompressed, see `open_file()`.
+    pub fn run<W: io::Write>(
+        &mut self,
+        file_path: &OsString,
+        out: W,
+    ) -> Result<RunReport, Box<dyn Error>> {
+        if !Path::new(file_path).is_dir() {
+            let file = self.open_file(file_path)?;
+            return self.run_reader(file, out);
+        }
+        let mut summary = Summary::default();
+        for path in csv_files(file_path)? {
+            let file = self.open_file(path)?;
+            summary.extend(self.ingest(file));
+        }
+        self.report_run(summary, out, self.config.format)
+    }
+
+    /// Opens the input file, decompressing it as configured, or as its name suggests.
+    pub fn open_file(&self, path: impl AsRef<Path>) -> io::Result<Box<dyn io::Read>> {
+        let path = path.as_ref();
+        let compression = self
+            .config
+            .compression
+            .unwrap_or_else(|| Compression::of(path));
+        decompress(File::open(path)?, compression)
+    }
+
+    /// Processes transactions from CSV input of any source, e.g. stdin,
+    /// and writes resulting accounts table to `out`.
+    pub fn run_reader<R: io::Read, W: io::Write>(
+        &mut self,
+        input: R,
+        out: W,
+    ) -> Result<RunReport, Box<dyn Error>> {
+        self.run_reader_with_format(input, out, self.config.format)
+    }
+
+    /// Same as `run_reader`, but writes accounts table in the given format
+    /// instead of the configured one.
+    pub fn run_reader_with_format<R: io::Read, W: io::Write>(
+        &mut self,
+        input: R,
+        out: W,
+        format: OutputFormat,
+    ) -> Result<RunReport, Box<dyn Error>> {
+        let summary = self.ingest(input);
+        self.report_run(summary, out, format)
+    }
+
+    /// Writes accounts table in the given format to `out`, finishing the run
+    /// with the `summary`.
+    fn report_run<W: io::Write>(
+        &self,
+        summary: Summary,
+        out: W,
+        format: OutputFormat,
+    ) -> Result<RunReport, Box<dyn Error>> {
+        let mut report = self.report(summary);
+
+        let start = Instant::now();
+        self.write_accounts_as(out, format)?;
+        if let Some(timing) = &mut report.timing {
+            timing.output = start.elapsed();
+        }
+        Ok(report)
+    }
+
+    /// Writes accounts table to `out`, filtered, grouped and formatted as configured,
+    /// with held funds breakdown and subtotal rows, if asked for.
+    /// Unless ordered otherwise, accounts go in ascending client order.
+    pub fn write_accounts<W: io::Write>(&self, out: W) -> Result<(), Box<dyn Error>> {
+        self.write_accounts_as(out, self.config.format)
+    }
+
+    fn write_accounts_as<W: io::Write>(
+        &self,
+        mut out: W,
+        format: OutputFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        // accounts of the implicit currency go first, then those of the others,
+        // each currency getting a subtotal row of its own
+        let ledgers = self.all_ledgers();
+        let multi_currency = ledgers.len() > 1;
+        let mut rows = vec![];
+        for (currency, accounts) in ledgers {
+            let accounts = if self.config.held_only {
+                held(accounts)
+            } else if let Some(grouping) = self.config.grouping {
+                grouped(accounts, grouping)
+            } else {
+                sorted(accounts)
+            };
+            let mut ledger_rows = accounts
+                .into_iter()
+                .filter_map(|client| self.row(client))
+                .map(|row| match multi_currency {
+                    true => row.with_currency(currency),
+                    false => row,
+                })
+                .collect::<Vec<_>>();
+            if self.config.currency_subtotals && !ledger_rows.is_empty() {
+                ledger_rows.push(AccountSer::subtotal(&ledger_rows));
+            }
+            rows.append(&mut ledger_rows);
+        }
+        match format {
+            OutputFormat::Csv => {
+                // the header is written by hand, for warnings to follow it
+                let mut wtr = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .flexible(true)
+                    .from_writer(out);
+                for (i, row) in rows.into_iter().enumerate() {
+                    if i == 0 {
+                        wtr.write_record(row.columns())?;
+                    }
+                    if let Some(warning) = self.overflow_warning(&row) {
+                        wtr.write_record([format!("# {}", warning)])?;
+                    }
+                    wtr.serialize(row)?
+                }
+                wtr.flush()?;
+            }
+            OutputFormat::Json => {
+                rows.iter().for_each(|row| self.log_overflow(row));
+                serde_json::to_writer_pretty(&mut out, &rows)?;
+                out.write_all(b"\n")?;
+                out.flush()?;
+            }
+            // flushed line by line, for consumers to start reading before it's all written
+            OutputFormat::Ndjson => {
+                for row in rows {
+                    self.log_overflow(&row);
+                    serde_json::to_writer(&mut out, &row)?;
+                    out.write_all(b"\n")?;
+                    out.flush()?;
+                }
+            }
+            OutputFormat::Sql => {
+                let table = self.config.sql_table.as_deref().unwrap_or("accounts");
+                for row in rows {
+                    if let Some(warning) = self.overflow_warning(&row) {
+                        writeln!(out, "-- {}", warning)?;
+                    }
+                    writeln!(out, "{}", row.to_sql_insert(table))?;
+                }
+                out.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns row of the accounts table for the account, as configured,
+    /// or `None` if it's to be left out, see `HeldOverflow::Skip`.
+    fn row(&self, client: &Account) -> Option<AccountSer<DIGITS>> {
+        let mut row = AccountSer::<DIGITS>::from(*client);
+        if self.config.held_breakdown {
+            row = row.with_held_breakdown(client);
+        }
+        if self.config.signed_available {
+            row = row.with_signed_available(client);
+        }
+        if self.config.last_seq {
+            row = row.with_last_seq(client);
+        }
+        match self.config.held_overflow {
+            _ if row.is_balanced() => Some(row),
+            Some(HeldOverflow::Skip) => {
+                log::warn!(
+                    "{}account of client {} left out of the output: held exceeds total",
+                    RunTag(self.config.run_id.as_deref()),
+                    client.id
+                );
+                None
+            }
+            Some(HeldOverflow::Clamp) => Some(row.with_held_clamped()),
+            Some(HeldOverflow::Warn) | None => Some(row),
+        }
+    }
+
+    /// Returns warning to precede the row of the accounts table with,
+    /// if it's unbalanced and configured so, see `HeldOverflow::Warn`.
+    fn overflow_warning(&self, row: &AccountSer<DIGITS>) -> Option<String> {
+        match (self.config.held_overflow, row.client()) {
+            (Some(HeldOverflow::Warn), Some(client)) if !row.is_balanced() => Some(format!(
+                "client {}: held exceeds total (available + held != total)",
+                client
+            )),
+            _ => None,
+        }
+    }
+
+    /// Logs the warning for the row, in formats having no comments.
+    fn log_overflow(&self, row: &AccountSer<DIGITS>) {
+        if let Some(warning) = self.overflow_warning(row) {
+            log::warn!("{}{}", RunTag(self.config.run_id.as_deref()), warning);
+        }
+    }
+
+    /// Writes a single row of the accounts table, for the `id` client, to `out`,
+    /// preceded with the header if asked to. Held funds are broken out if configured so,
+    /// and nothing is written if the row is to be left out, see `HeldOverflow::Skip`.
+    /// Fails with `EngineError::AccountNotFound` if there is no such client.
+    pub fn emit_account<W: io::Write>(
+        &self,
+        id: u32,
+        out: W,
+        header: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.emit_account_in(id, "", out, header)
+    }
+
+    /// Same as `emit_account()`, for the client account in the currency, the row
+    /// carrying it, or for the implicit one, with no currency column, if it's blank.
+    pub fn emit_account_in<W: io::Write>(
+        &self,
+        id: u32,
+        currency: &str,
+        out: W,
+        header: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let client = self
+            .get_account_in(&id, currency)
+            .ok_or(EngineError::AccountNotFound)?;
+        let Some(row) = self.row(client) else {
+            return Ok(());
+        };
+        let row = match currency {
+            "" => row,
+            currency => row.with_currency(currency),
+        };
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_writer(out);
+        if header {
+            wtr.write_record(row.columns())?;
+        }
+        if let Some(warning) = self.overflow_warning(&row) {
+            wtr.write_record([format!("# {}", warning)])?;
+        }
+        wtr.serialize(row)?;
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Reads transactions from CSV input and processes them one by one.
+    /// Entries which can't be read or processed are dropped, and counted in the summary.
+    /// In deferred disputes mode, makes a final pass over the deferred ones
+    /// after reading all input.
+    pub fn ingest<R: io::Read>(&mut self, input: R) -> Summary {
+        self.process_stream(input, |_| ())
+    }
+
+    /// Same as `ingest`, but invokes `on_update` with the affected account
+    /// after each transaction processed, e.g. for live updates to be pushed on.
+    /// Transfers report both accounts, the debited one first.
+    /// The same account is reported as many times as it's updated.
+    pub fn process_stream<R, F>(&mut self, input: R, mut on_update: F) -> Summary
+    where
+        R: io::Read,
+        F: FnMut(&Account),
+    {
+        let start = Instant::now();
+        let mut parsing = Duration::ZERO;
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(input);
+        // ignores failed to be parsed entries,
+        // and rejects ones missing required fields
+        let headers = rdr.headers().cloned().unwrap_or_default();
+        let columns = self
+            .config
+            .column_map
+            .as_ref()
+            .map(|map| map.index(&headers));
+        let parser = self.config.amount_parser.clone();
+        let default = DecimalParser::<DIGITS>::default();
+        let parser = parser.as_deref().unwrap_or(&default);
+        let strict_arity = self.config.strict_arity;
+        let keep_rejects = self.config.keep_rejects;
+        #[cfg(feature = "hmac")]
+        let signing_key = self.config.signing_key.clone();
+        #[cfg(feature = "hmac")]
+        let signing_key = signing_key.as_ref();
+        let mut errors = ReadErrors::default();
+        let txs = rdr.records().filter_map(|row| {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    errors.malformed(e.position().map(|p| p.line()));
+                    return None;
+                }
+            };
+            let line = row.position().map(|p| p.line());
+            let Some(entry) = read_record(&row, &headers, columns.as_ref()) else {
+                errors.malformed(line);
+                return None;
+            };
+            let entry = entry.parse_with(parser);
+            let id = entry.tx.unwrap_or_default();
+            let (expected, found) = (headers.len(), row.len());
+            let short_event = found < expected && entry.ty.as_ref().is_some_and(Tx::is_event);
+            let reject = keep_rejects.then(|| Reject::of_record(&entry, DIGITS));
+            if strict_arity && found != expected && !short_event {
+                let e = EngineError::ArityMismatch { expected, found };
+                errors.reject(id, e, line, reject);
+                return None;
+            }
+            #[cfg(feature = "hmac")]
+            if let Some(Err(e)) = signing_key.map(|key| key.verify_row(&headers, &row)) {
+                errors.reject(id, e, line, reject);
+                return None;
+            }
+            match Transaction::try_from(entry).and_then(Self::received) {
+                Ok(tx) => Some((line, tx)),
+                Err(e) => {
+                    errors.reject(id, e, line, reject);
+                    None
+                }
+            }
+        });
+        let txs = Timed {
+            inner: txs,
+            spent: self.config.timing.then_some(&mut parsing),
+        };
+        let summary = self.process_read(txs, &mut on_update);
+        self.finish_read(summary, errors, start, parsing)
+    }
+
+    /// Reads transactions from NDJSON input, one JSON object per line, with the same
+    /// fields as CSV columns, e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`,
+    /// and processes them as `ingest` does. Amounts may be given as JSON numbers as well,
+    /// taken as they are written, with no floating point rounding. Blank lines are skipped,
+    /// and lines which are not such objects are counted as parse errors.
+    pub fn ingest_ndjson<R: io::Read>(&mut self, input: R) -> Summary {
+        let start = Instant::now();
+        let mut parsing = Duration::ZERO;
+        let parser = self.config.amount_parser.clone();
+        let default = DecimalParser::<DIGITS>::default();
+        let parser = parser.as_deref().unwrap_or(&default);
+        let keep_rejects = self.config.keep_rejects;
+        let mut errors = ReadErrors::default();
+        let txs = io::BufReader::new(input)
+            .lines()
+            .zip(1..)
+            .filter_map(|(text, line)| {
+                let Ok(text) = text else {
+                    errors.malformed(Some(line));
+                    return None;
+                };
+                if text.trim().is_empty() {
+                    return None;
+                }
+                let Ok(entry) = serde_json::from_str::<JsonRecord>(&text) else {
+                    errors.malformed(Some(line));
+                    return None;
+                };
+                let entry = entry.parse_with(parser);
+                let id = entry.tx.unwrap_or_default();
+                let reject = keep_rejects.then(|| Reject::of_record(&entry, DIGITS));
+                match Transaction::try_from(entry).and_then(Self::received) {
+                    Ok(tx) => Some((Some(line), tx)),
+                    Err(e) => {
+                        errors.reject(id, e, Some(line), reject);
+                        None
+                    }
+                }
+            });
+        let txs = Timed {
+            inner: txs,
+            spent: self.config.timing.then_some(&mut parsing),
+        };
+        let summary = self.process_read(txs, &mut |_| ());
+        self.finish_read(summary, errors, start, parsing)
+    }
+
+    /// Processes transactions from NDJSON input, see `ingest_ndjson`, and writes
+    /// resulting accounts table to `out`.
+    pub fn run_ndjson<R: io::Read, W: io::Write>(
+        &mut self,
+        input: R,
+        out: W,
+    ) -> Result<RunReport, Box<dyn Error>> {
+        let summary = self.ingest_ndjson(input);
+        self.report_run(summary, out, self.config.format)
+    }
+
+    /// Processes transactions read from input, in its order, or reordered if configured so.
+    /// In reorder mode with `TieBreak::Reject`, the whole batch may be rejected,
+    /// telling so in the summary.
+    fn process_read<I, F>(&mut self, txs: I, on_update: &mut F) -> Summary
+    where
+        I: Iterator<Item = (Option<u64>, Transaction)>,
+        F: FnMut(&Account),
+    {
+        match self.config.reorder {
+            None => self.process_all(txs, on_update),
+            Some(tie_break) => {
+                let mut txs = txs.collect::<Vec<_>>();
+                // stable sort, keeping input order among equal keys
+                txs.sort_by_key(|(_, tx)| (tx.id, tx.ty.as_ref().is_some_and(Tx::is_event)));
+                if tie_break == TieBreak::Reject {
+                    if let Some(id) = duplicate_id(txs.iter().map(|(_, tx)| tx)) {
+                        return Summary {
+                            declined: txs.len(),
+                            rejected_on: Some(id),
+                            ..Default::default()
+                        };
+                    }
+                }
+                self.process_all(txs, on_update)
+            }
+        }
+    }
+
+    /// Adds the entries failed to be read to the summary of processing the input,
+    /// along with the time spent, if recorded.
+    fn finish_read(
+        &self,
+        mut summary: Summary,
+        errors: ReadErrors,
+        start: Instant,
+        parsing: Duration,
+    ) -> Summary {
+        summary.parse_errors = errors.parse_errors;
+        summary.malformed = errors.malformed;
+        if summary.rejected_on.is_some() {
+            summary.declined += errors.rejected.len();
+            return summary;
+        }
+        for (id, e, line) in errors.rejected {
+            summary.decline(id, e, line);
+        }
+        summary.rejects.extend(errors.rejects);
+        if self.config.timing {
+            summary.timing = Some(Timing {
+                parsing,
+                // parsing is interleaved with processing, so the latter is what's left
+                processing: start.elapsed().saturating_sub(parsing),
+                ..Default::default()
+            });
+        }
+        summary
+    }
+
+    /// Reports what processing the input would do, which of its transactions would
+    /// be processed and which declined, as `ingest` does, but without applying them:
+    /// they're processed by a throwaway engine of the same state and configuration.
+    pub fn validate_reader<R: io::Read>(&self, input: R) -> RunReport {
+        let mut dry = Self::restore(self.snapshot());
+        dry.config = self.config.clone();
+        self.report(dry.ingest(input))
+    }
+
+    /// Processes transactions given, e.g. parsed by the caller, one by one,
+    /// as `ingest` does the ones it reads, deferring disputes if configured.
+    /// They are not reordered though, being processed as they come.
+    /// Returns outcome of processing them, the ones failing to be initialized
+    /// (e.g. deposits of no amount) counted as declined.
+    pub fn process_many<I: IntoIterator<Item = Transaction>>(&mut self, txs: I) -> RunReport {
+        let mut rejected = vec![];
+        let txs = txs.into_iter().filter_map(|tx| {
+            let id = tx.id;
+            let tx = Self::received(tx).map_err(|e| rejected.push((id, e)));
+            tx.ok().map(|tx| (None, tx))
+        });
+        let mut summary = self.process_all(txs, &mut |_| ());
+        for (id, e) in rejected {
+            summary.decline(id, e, None);
+        }
+        self.report(summary)
+    }
+
+    /// Returns report of the run of the outcome given, tagged with the run ID.
+    fn report(&self, summary: Summary) -> RunReport {
+        RunReport {
+            run_id: self.config.run_id.clone(),
+            ..RunReport::from(summary)
+        }
+    }
+
+    /// Initializes transaction as received, for it to be processed,
+    /// see `Transaction::init()`.
+    fn received(mut tx: Transaction) -> Result<Transaction, EngineError> {
+        tx.init(TxStateKind::Received).map(|()| tx)
+    }
+
+    /// Processes initialized transactions one by one, along with their input lines,
+    /// if read from input, deferring disputes if configured,
+    /// reporting accounts updated to `on_update`.
+    /// Errors are ignored, faulty transactions are simply discarded.
+    fn process_all(
+        &mut self,
+        txs: impl IntoIterator<Item = (Option<u64>, Transaction)>,
+        on_update: &mut impl FnMut(&Account),
+    ) -> Summary {
+        let mut summary = Summary::default();
+        let mut deferred: Vec<(Option<u64>, Transaction)> = vec![];
+
+        for (line, tx) in txs {
+            let keys = || deferred.iter().map(|(_, d)| d.key());
+            if self.config.defer_disputes && self.should_defer(&tx, keys()) {
+                deferred.push((line, tx));
+                continue;
+            }
+            let (id, affected) = (tx.id, [Some(tx.client), tx.target]);
+            let currency = tx.currency.clone();
+            let reject = self.config.keep_rejects.then(|| Reject::of(&tx, DIGITS));
+            match self.process(tx) {
+                Ok(()) => {
+                    summary.processed += 1;
+                    self.report_updates(affected, currency.as_deref(), on_update);
+                }
+                Err(e) => {
+                    summary.rejects.extend(reject.map(|r| r.because(&e)));
+                    summary.decline(id, e, line)
+                }
+            }
+        }
+
+        // final pass
+        for (line, tx) in deferred {
+            if !self.transactions.contains_key(&tx.key()) {
+                summary.orphans.push(tx);
+                continue;
+            }
+            let (id, affected) = (tx.id, [Some(tx.client), tx.target]);
+            let currency = tx.currency.clone();
+            let reject = self.config.keep_rejects.then(|| Reject::of(&tx, DIGITS));
+            match self.process(tx) {
+                Ok(()) => {
+                    summary.processed += 1;
+                    summary.deferred += 1;
+                    self.report_updates(affected, currency.as_deref(), on_update);
+                }
+                Err(e) => {
+                    summary.rejects.extend(reject.map(|r| r.because(&e)));
+                    summary.decline(id, e, line)
+                }
+            }
+        }
+
+        summary
+    }
+
+    /// Passes accounts of the clients given in the currency, the ones existing,
+    /// to `on_update`.
+    fn report_updates(
+        &self,
+        clients: [Option<u32>; 2],
+        currency: Option<&str>,
+        on_update: &mut impl FnMut(&Account),
+    ) {
+        let Some(accounts) = self.ledger(currency.unwrap_or_default()) else {
+            return;
+        };
+        for id in clients.into_iter().flatten() {
+            if let Some(account) = accounts.get(&id) {
+                on_update(account)
+            }
+        }
+    }
+
+    /// Whether event is to be deferred until the end of input,
+    /// given keys of the ones deferred already.
+    fn should_defer(&self, tx: &Transaction, mut deferred: impl Iterator<Item = TxKey>) -> bool {
+        match tx.ty {
+            Some(Tx::Dispute) | Some(Tx::Resolve) | Some(Tx::Chargeback)
+                if deferred.any(|key| key == tx.key()) =>
+            {
+                true
+            }
+            Some(Tx::Dispute) => !self.transactions.contains_key(&tx.key()),
+            _ => false,
+        }
+    }
+
+    /// Moves accounts and transactions of `other` engine into this one.
+    /// Engines are expected to have been processing disjoint sets of clients,
+    /// so any client or transaction present in both is an error,
+    /// in which case neither engine is modified.
+    pub fn merge(&mut self, other: Self) -> Result<(), EngineError> {
+        if let Some(id) = other
+            .accounts
+            .keys()
+            .find(|k| self.accounts.contains_key(k))
+        {
+            return Err(EngineError::ClientConflict(*id));
+        }
+        for (currency, accounts) in &other.ledgers {
+            let Some(own) = self.ledgers.get(currency) else {
+                continue;
+            };
+            if let Some(id) = accounts.keys().find(|k| own.contains_key(k)) {
+                return Err(EngineError::ClientConflict(*id));
+            }
+        }
+        if let Some((_, id)) = other
+            .transactions
+            .keys()
+            .find(|k| self.transactions.contains_key(k))
+        {
+            return Err(EngineError::TxConflict(*id));
+        }
+        self.accounts.extend(other.accounts);
+        for (currency, accounts) in other.ledgers {
+            self.ledgers.entry(currency).or_default().extend(accounts);
+        }
+        self.transactions.extend(other.transactions);
+        self.order.extend(other.order);
+        self.dispute_cycles.extend(other.dispute_cycles);
+        self.open_disputes.extend(other.open_disputes);
+        self.client_txs.extend(other.client_txs);
+        self.last_events.extend(other.last_events);
+        // positions of the transactions merged are within the longer sequence
+        self.seq = self.seq.max(other.seq);
+        self.applied = self.applied.max(other.applied);
+        Ok(())
+    }
+
+    /// Moves state of the clients `of` holds for, i.e. their accounts and stored
+    /// transactions, out of this engine into a new one of the same configuration,
+    /// for them to be processed on their own and merged back.
+    /// Positions in the sequence of processed transactions carry on from this one.
+    fn split_off(&mut self, of: impl Fn(u32) -> bool) -> Self {
+        let ledgers = self
+            .ledgers
+            .iter_mut()
+            .map(|(currency, accounts)| (currency.clone(), split_map(accounts, |&id| of(id))))
+            .filter(|(_, accounts)| !accounts.is_empty())
+            .collect();
+        let (order, rest) = mem::take(&mut self.order)
+            .into_iter()
+            .partition(|&(client, _)| of(client));
+        self.order = rest;
+        Self {
+            accounts: split_map(&mut self.accounts, |&id| of(id)),
+            ledgers,
+            transactions: split_map(&mut self.transactions, |&(client, _)| of(client)),
+            order,
+            dispute_cycles: split_map(&mut self.dispute_cycles, |&(client, _)| of(client)),
+            last_events: split_map(&mut self.last_events, |&(client, _)| of(client)),
+            seq: self.seq,
+            applied: self.applied,
+            open_disputes: split_map(&mut self.open_disputes, |&id| of(id)),
+            client_txs: split_map(&mut self.client_txs, |&id| of(id)),
+            config: self.config.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Processes input files concurrently, each into its own engine, running
+    /// up to `threads` of them at once, and merges the results into this one.
+    /// Shard engines are set up with the configuration of this one.
+    /// Returns outcome of processing all of the files, added up.
+    ///
+    /// Files are required to contain disjoint sets of clients (and transaction ids),
+    /// and so are this engine and the files: shards start empty, and clients already
+    /// present in it conflict on merge, see `run_parallel` for that.
+    /// This way no locking is needed, as shards never touch each other's accounts.
+    /// For the same reason, disputes must stay within the client set of their file:
+    /// a dispute referencing transaction from another file is declined, as it
+    /// would be in a file processed on its own.
+    /// Positions of the last transactions applied to the accounts, see
+    /// `Account::last_seq`, are the ones within their shard.
+    /// Shards merged before a failure stay merged.
+    pub fn run_shards(
+        &mut self,
+        paths: &[OsString],
+        threads: usize,
+    ) -> Result<Summary, Box<dyn Error>> {
+        let mut summary = Summary::default();
+        for batch in paths.chunks(threads.max(1)) {
+            let config = &self.config;
+            let shards = thread::scope(|s| {
+                let handles = batch
+                    .iter()
+                    .map(|path| {
+                        s.spawn(move || -> io::Result<(Self, Summary)> {
+                            let mut shard = Self {
+                                config: config.clone(),
+                                ..Default::default()
+                            };
+                            let summary = shard.ingest(shard.open_file(path)?);
+                            Ok((shard, summary))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().expect("shard thread panicked"))
+                    .collect::<Vec<_>>()
+            });
+            for shard in shards {
+                let (shard, shard_summary) = shard?;
+                self.merge(shard)?;
+                summary.extend(shard_summary);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Same as `run_reader`, but processes the input on up to `threads` threads:
+    /// its rows are partitioned by `client % threads` into shards, each processed
+    /// by an engine of its own, set up with the configuration of this one and seeded
+    /// with the existing accounts and stored transactions of its clients, e.g. loaded
+    /// opening balances, and merged into this one for output.
+    ///
+    /// Every transaction, disputes included, only touches the account and the stored
+    /// transactions of its client, and rows of each client keep their order,
+    /// so the output is the one of processing the input serially. Transfers,
+    /// touching two clients, are the exception, and are declined with
+    /// `EngineError::CrossShard`. Limits kept per engine, e.g. memory budget,
+    /// apply to each shard on its own, and so does `Account::last_seq`.
+    /// Transactions applied by the shards can't be rolled back, and neither can
+    /// the ones before them.
+    pub fn run_parallel<R: io::Read, W: io::Write>(
+        &mut self,
+        input: R,
+        out: W,
+        threads: usize,
+    ) -> Result<RunReport, Box<dyn Error>> {
+        let threads = threads.max(1);
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(input);
+        let headers = rdr.headers()?.clone();
+        let mapped = self
+            .config
+            .column_map
+            .as_ref()
+            .map(|map| map.index(&headers));
+        let columns = mapped.unwrap_or_else(|| ColumnMap::default().index(&headers));
+        let parser = self.config.amount_parser.clone();
+        let default = DecimalParser::<DIGITS>::default();
+        let parser = parser.as_deref().unwrap_or(&default);
+
+        let mut shards = (0..threads)
+            .map(|_| {
+                let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(vec![]);
+                wtr.write_record(&headers).map(|()| wtr)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // input lines of the lines of each shard, for the ones reported to be mapped back,
+        // a row taking as many lines as it has line breaks inside, plus one
+        let span = |row: &csv::StringRecord| {
+            1 + row.iter().map(|f| f.matches('\n').count()).sum::<usize>()
+        };
+        let mut origins = vec![vec![0; 1 + span(&headers)]; threads];
+        let mut summary = Summary::default();
+        for row in rdr.records() {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    summary.parse_errors += 1;
+                    summary.malformed.extend(e.position().map(|p| p.line()));
+                    continue;
+                }
+            };
+            let line = row.position().map(|p| p.line());
+            if columns.field(&row, columns.ty) == Some("transfer") {
+                let e = EngineError::CrossShard;
+                let id = columns
+                    .field(&row, columns.tx)
+                    .and_then(|id| id.parse().ok());
+                if self.config.keep_rejects {
+                    if let Some(r) = read_record(&row, &headers, mapped.as_ref()) {
+                        let r = Reject::of_record(&r.parse_with(parser), DIGITS);
+                        summary.rejects.push(r.because(&e));
+                    }
+                }
+                summary.decline(id.unwrap_or_default(), e, line);
+                continue;
+            }
+            // rows of no valid client are declined by any shard alike
+            let client = columns
+                .field(&row, columns.client)
+                .and_then(|c| c.parse::<u32>().ok());
+            let shard = client.map_or(0, |c| c as usize % threads);
+            shards[shard].write_record(&row)?;
+            origins[shard].extend(std::iter::repeat_n(line.unwrap_or_default(), span(&row)));
+        }
+        let inputs = shards
+            .into_iter()
+            .map(|wtr| wtr.into_inner().map_err(|e| e.into_error()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let seeds = (0..threads)
+            .map(|i| self.split_off(|client| client as usize % threads == i))
+            .collect::<Vec<_>>();
+        self.history.clear();
+        let shards = thread::scope(|s| {
+            let handles = seeds
+                .into_iter()
+                .zip(&inputs)
+                .map(|(mut shard, input)| {
+                    s.spawn(move || {
+                        let summary = shard.ingest(input.as_slice());
+                        (shard, summary)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("shard thread panicked"))
+                .collect::<Vec<_>>()
+        });
+        for ((shard, mut shard_summary), origin) in shards.into_iter().zip(origins) {
+            let origin = |line: u64| origin.get(line as usize).copied().unwrap_or(line);
+            for line in shard_summary.lines.iter_mut().flatten() {
+                *line = origin(*line);
+            }
+            for line in &mut shard_summary.malformed {
+                *line = origin(*line);
+            }
+            self.merge(shard)?;
+            summary.extend(shard_summary);
+        }
+        self.report_run(summary, out, self.config.format)
+    }
+
+    impl_transaction_handler!(deposit);
+    impl_transaction_handler!(withdraw);
+    impl_event_handler!(dispute, hold, credit_back, Disputed);
+    impl_event_handler!(resolve, release, revoke_credit, Executed);
+    impl_event_handler!(revert, chargeback, keep_credit, Reverted);
+
+    /// Clears engine state, i.e. accounts and stored transactions,
+    /// keeping all of its configuration. This allows reusing the engine across inputs.
+    pub fn reset(&mut self) {
+        self.accounts.clear();
+        self.ledgers.clear();
+        self.transactions.clear();
+        self.order.clear();
+        self.dispute_cycles.clear();
+        self.last_events.clear();
+        self.open_disputes.clear();
+        self.client_txs.clear();
+        self.history.clear();
+        self.seq = 0;
+        self.applied = 0;
+    }
+
+    /// Reverses the last `n` money-moving transactions applied, newest first: deposits,
+    /// withdrawals, transfers and adjustments, restoring the accounts they touched
+    /// and forgetting them, as if they never came. Events and unlocks are not reversible,
+    /// and neither are the transactions applied before them, for their effects to stay.
+    /// Only up to the rollback depth of the last transactions are kept track of.
+    /// Returns number of the transactions reversed, fewer than `n` if no more are tracked.
+    pub fn rollback(&mut self, n: usize) -> usize {
+        let mut reversed = 0;
+        while reversed < n {
+            let Some(applied) = self.history.pop_back() else {
+                break;
+            };
+            let restore = |engine: &mut Self| {
+                for (id, acc) in applied.accounts {
+                    match acc {
+                        Some(acc) => engine.accounts.insert(id, acc),
+                        None => engine.accounts.remove(&id),
+                    };
+                }
+            };
+            let counted = matches!(applied.ty, Tx::Deposit | Tx::Withdrawal | Tx::Transfer);
+            match &applied.currency {
+                Some(currency) => self.in_ledger(currency, restore),
+                None => restore(self),
+            }
+            if let Some(count) = self.client_txs.get_mut(&applied.key.0).filter(|_| counted) {
+                *count = count.saturating_sub(1);
+            }
+            // an unstored one leaves the key to the transaction stored under it, if any
+            if applied.stored {
+                self.transactions.remove(&applied.key);
+                // the newest ones are reversed, so they're the last stored
+                if let Some(i) = self.order.iter().rposition(|key| *key == applied.key) {
+                    self.order.remove(i);
+                }
+            }
+            reversed += 1;
+        }
+        let tag = RunTag(self.config.run_id.as_deref());
+        log::info!("{}rolled back {} transactions", tag, reversed);
+        reversed
+    }
+
+    /// Returns state of the engine, for it to be restored with `restore()`,
+    /// e.g. after being persisted for a job to resume later.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        let mut accounts = self.accounts.values().copied().collect::<Vec<_>>();
+        accounts.sort_unstable_by_key(|a| a.id);
+        let mut ledgers = self
+            .ledgers
+            .iter()
+            .map(|(currency, ledger)| {
+                let mut accounts = ledger.values().copied().collect::<Vec<_>>();
+                accounts.sort_unstable_by_key(|a| a.id);
+                (currency.clone(), accounts)
+            })
+            .collect::<Vec<_>>();
+        ledgers.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut transactions = self
+            .transactions
+            .values()
+            .map(TransactionSnapshot::from)
+            .collect::<Vec<_>>();
+        transactions.sort_unstable_by_key(|tx| (tx.client, tx.id));
+        let mut dispute_cycles = self
+            .dispute_cycles
+            .iter()
+            .map(|(key, cycles)| (*key, *cycles))
+            .collect::<Vec<_>>();
+        dispute_cycles.sort_unstable();
+        let mut last_events = self
+            .last_events
+            .iter()
+            .map(|(key, (ty, seq))| (*key, ty.clone(), *seq))
+            .collect::<Vec<_>>();
+        last_events.sort_unstable_by_key(|(key, _, _)| *key);
+        EngineSnapshot {
+            accounts,
+            ledgers,
+            transactions,
+            dispute_cycles,
+            last_events,
+            seq: self.seq,
+            applied: self.applied,
+            order: self.order.clone(),
+        }
+    }
+
+    /// Makes engine of the default configuration with the state of the snapshot,
+    /// to be set up as the one it was taken of, and to carry on processing.
+    pub fn restore(snapshot: EngineSnapshot) -> Self {
+        let mut engine = Self::default();
+        engine.load_accounts(snapshot.accounts);
+        engine.ledgers = snapshot
+            .ledgers
+            .into_iter()
+            .map(|(currency, accounts)| {
+                let accounts = accounts.into_iter().map(|acc| (acc.id, acc)).collect();
+                (currency, accounts)
+            })
+            .collect();
+        for tx in snapshot.transactions {
+            let tx = Transaction::from(tx);
+            // open disputes are counted regardless of their limit, set up later
+            if tx.state() == State::Disputed {
+                *engine.open_disputes.entry(tx.client).or_default() += 1;
+            }
+            // so are transactions of the clients, stored ones only
+            if let Some(Tx::Deposit | Tx::Withdrawal | Tx::Transfer) = tx.ty {
+                *engine.client_txs.entry(tx.client).or_default() += 1;
+            }
+            engine.transactions.insert(tx.key(), tx);
+        }
+        engine.order = snapshot.order;
+        // snapshots of no order kept are taken in the order of the sequence
+        if engine.order.len() != engine.transactions.len() {
+            let mut txs = engine.transactions.values().collect::<Vec<_>>();
+            txs.sort_by_key(|tx| (tx.seq, tx.client, tx.id));
+            engine.order = txs.into_iter().map(Transaction::key).collect();
+        }
+        engine.dispute_cycles.extend(snapshot.dispute_cycles);
+        engine.last_events.extend(
+            snapshot
+                .last_events
+                .into_iter()
+                .map(|(key, ty, seq)| (key, (ty, seq))),
+        );
+        engine.seq = snapshot.seq;
+        engine.applied = snapshot.applied;
+        engine
+    }
+
+    /// Replays the write-ahead log at `path`, written by an engine set up with
+    /// `with_wal()`, into this one, a fresh engine set up as that one was, to recover
+    /// its state, e.g. after a crash. It's not appended to while being replayed,
+    /// for the engine to carry on appending to the same log then.
+    /// Returns number of the transactions replayed. Fails if the log is unreadable,
+    /// or on a transaction failing or ending up at another state than logged,
+    /// the ones before it being applied.
+    pub fn replay_wal(&mut self, path: impl AsRef<Path>) -> Result<u64, Box<dyn Error>> {
+        let records = read_wal(path)?;
+        let wal = self.wal.take();
+        let res = records
+            .into_iter()
+            .try_fold(0, |n, record| self.replay_record(record).map(|()| n + 1));
+        self.wal = wal;
+        res
+    }
+
+    fn replay_record(&mut self, record: WalRecord) -> Result<(), Box<dyn Error>> {
+        let mut tx = Transaction::from(record.tx);
+        let key = tx.key();
+        // the declined transactions, not logged, count in the sequence all the same
+        self.seq = record.seq;
+        // adjustments are made as `adjust()` makes them, as the engine declines them
+        // from input unless admin operations are allowed
+        match tx.adjustment {
+            Some(_) => {
+                tx.state = Some(TxStateKind::Executed);
+                self.adjust_with(tx)?
+            }
+            None => {
+                self.seq = self.seq.saturating_sub(1);
+                self.process(tx)?
+            }
+        }
+        let state = self.transactions.get(&key).and_then(|tx| tx.state);
+        if state != record.state {
+            return Err(format!(
+                "WAL record {} of tx {} of client {} replayed to {:?} state, not {:?}",
+                record.seq, key.1, key.0, state, record.state
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Clears engine state and restores its default configuration.
+    pub fn reset_all(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Loads accounts into the engine, e.g. from a prior balances snapshot
+    /// or opening balances, replacing existing accounts with the same IDs.
+    /// Transactions are then applied on top of them.
+    /// Loaded balances can't be disputed, as there are no stored transactions
+    /// behind them: disputes may only reference transactions processed by the engine.
+    pub fn load_accounts(&mut self, accounts: impl IntoIterator<Item = Account>) {
+        self.accounts
+            .extend(accounts.into_iter().map(|acc| (acc.id, acc)))
+    }
+
+    /// Returns changes to the accounts since the `prior` state, sorted by client ID,
+    /// the ones of the implicit currency first, then those of the others, each carrying
+    /// its currency if there are any. Accounts absent in the `prior` are compared
+    /// to a blank account, as are the ones in the other currencies, for the `prior`
+    /// state is the one of the implicit currency, as `read_accounts()` reads it.
+    pub fn deltas(&self, prior: &[Account]) -> Vec<AccountDelta<DIGITS>> {
+        let prior = prior.iter().map(|a| (a.id, a)).collect::<HashMap<_, _>>();
+        let ledgers = self.all_ledgers();
+        let multi_currency = ledgers.len() > 1;
+        let mut deltas = vec![];
+        for (currency, accounts) in ledgers {
+            let mut ledger_deltas = accounts
+                .values()
+                .filter_map(|acc| match prior.get(&acc.id) {
+                    Some(old) if currency.is_empty() => AccountDelta::between(old, acc),
+                    _ => AccountDelta::between(&Account::new(acc.id), acc),
+                })
+                .map(|delta| match multi_currency {
+                    true => delta.with_currency(currency),
+                    false => delta,
+                })
+                .collect::<Vec<_>>();
+            ledger_deltas.sort_by_key(|d| d.client);
+            deltas.append(&mut ledger_deltas);
+        }
+        deltas
+    }
+
+    /// Returns differences of the accounts of the `other` engine from these ones, sorted
+    /// by client ID, e.g. to reconcile a dry run with the actual one, the ones of the
+    /// implicit currency first, then those of the others, by the currency.
+    /// Accounts present in one of the engines only are compared to a blank account.
+    pub fn diff(&self, other: &Engine<DIGITS>) -> Vec<AccountDiff> {
+        let mut clients = [self, other]
+            .into_iter()
+            .flat_map(Engine::all_ledgers)
+            .flat_map(|(currency, accounts)| accounts.keys().map(move |id| (currency, *id)))
+            .collect::<Vec<_>>();
+        clients.sort_unstable();
+        clients.dedup();
+        let account = |engine: &Engine<DIGITS>, currency, id| {
+            engine
+                .ledger(currency)
+                .and_then(|accounts| accounts.get(&id))
+                .copied()
+                .unwrap_or_else(|| Account::new(id))
+        };
+        clients
+            .into_iter()
+            .filter_map(|(currency, id)| {
+                let diff = AccountDiff::between(
+                    &account(self, currency, id),
+                    &account(other, currency, id),
+                )?;
+                Some(match currency.is_empty() {
+                    true => diff,
+                    false => diff.with_currency(currency),
+                })
+            })
+            .collect()
+    }
+
+    /// Approximate memory taken by the given number of stored accounts and transactions,
+    /// the latter being kept track of the order of too.
+    fn footprint(accounts: usize, transactions: usize) -> usize {
+        accounts * mem::size_of::<(u32, Account)>()
+            + transactions * (mem::size_of::<(TxKey, Transaction)>() + mem::size_of::<TxKey>())
+    }
+
+    /// Fails if storing one more transaction, along with a new account for the `client`
+    /// if it doesn't have one yet, would exceed the memory budget.
+    fn ensure_memory_budget(&self, client: u32) -> Result<(), EngineError> {
+        let Some(budget) = self.config.memory_budget else {
+            return Ok(());
+        };
+        let accounts = self.accounts.len()
+            + self.ledgers.values().map(IdMap::len).sum::<usize>()
+            + usize::from(!self.accounts.contains_key(&client));
+        if Self::footprint(accounts, self.transactions.len() + 1) > budget {
+            return Err(EngineError::MemoryBudgetExceeded);
+        }
+        Ok(())
+    }
+
+    fn get_or_create_account(&mut self, id: u32) -> &mut Account {
+        if !&self.accounts.contains_key(&id) {
+            self.accounts.insert(id, Account::new(id));
+        }
+        self.accounts.get_mut(&id).unwrap()
+    }
+
+    pub fn accounts(&self) -> Values<'_, u32, Account> {
+        self.accounts.values()
+    }
+
+    /// Returns stored transactions in the order they were accepted, e.g. for them
+    /// to be replayed. Transactions of engines merged come after the ones of this one.
+    pub fn transactions_in_order(&self) -> impl Iterator<Item = &Transaction> {
+        self.order
+            .iter()
+            .filter_map(|key| self.transactions.get(key))
+    }
+
+    /// Returns balances of the client account, available, held and total ones,
+    /// formatted as in the output table, e.g. `("0.1234", "0", "0.1234")`,
+    /// or `None` if there is no such client.
+    pub fn balance_of(&self, client: &u32) -> Option<(String, String, String)> {
+        self.balance_of_in(client, "")
+    }
+
+    /// Same as `balance_of()`, for the client account in the currency,
+    /// the implicit one for a blank currency.
+    pub fn balance_of_in(&self, client: &u32, currency: &str) -> Option<(String, String, String)> {
+        let acc = self.get_account_in(client, currency)?;
+        let mut row = AccountSer::<DIGITS>::from(*acc);
+        if self.config.signed_available {
+            row = row.with_signed_available(acc);
+        }
+        Some(row.balances())
+    }
+
+    /// Returns top-line figures of the accounts and the stored transactions.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics::default();
+        let ledgers = self.all_ledgers();
+        for acc in ledgers.iter().flat_map(|(_, accounts)| accounts.values()) {
+            metrics.frozen_accounts += usize::from(acc.locked);
+            metrics.balance += acc.total_signed();
+        }
+        for tx in self.transactions.values() {
+            let amount = tx.amount.unwrap_or_default();
+            match tx.ty {
+                Some(Tx::Deposit) => metrics.deposits = metrics.deposits.saturating_add(amount),
+                Some(Tx::Withdrawal) => {
+                    metrics.withdrawals = metrics.withdrawals.saturating_add(amount)
+                }
+                _ => (),
+            }
+            if tx.state() == State::Disputed {
+                let disputed = tx.disputed.unwrap_or(amount);
+                metrics.disputed = metrics.disputed.saturating_add(disputed);
+            }
+        }
+        metrics
+    }
+
+    /// Checks that every locked account is explained by at least one charged back
+    /// (`Reverted`) transaction of its client among the stored ones, in the currency
+    /// of the account. Returns IDs of clients locked without such cause, sorted, if any.
+    /// Note that accounts loaded already locked (e.g. from a snapshot) have no cause
+    /// known to the engine.
+    pub fn verify_freezes(&self) -> Result<(), Vec<u32>> {
+        let reverted = self
+            .transactions
+            .values()
+            .filter(|tx| tx.state() == State::Reverted)
+            .map(|tx| (tx.client, tx.currency.as_deref().unwrap_or_default()))
+            .collect::<HashSet<_>>();
+        let reverted = &reverted;
+        let mut orphans = self
+            .all_ledgers()
+            .into_iter()
+            .flat_map(|(currency, accounts)| {
+                accounts
+                    .values()
+                    .filter(move |acc| acc.locked && !reverted.contains(&(acc.id, currency)))
+            })
+            .map(|acc| acc.id)
+            .collect::<Vec<_>>();
+        if orphans.is_empty() {
+            return Ok(());
+        }
+        orphans.sort_unstable();
+        orphans.dedup();
+        Err(orphans)
+    }
+
+    /// Returns accounts in client ID order.
+    /// Only references are collected and sorted, accounts are not cloned.
+    pub fn accounts_sorted(&self) -> impl Iterator<Item = &Account> {
+        sorted(&self.accounts).into_iter()
+    }
+
+    /// Same as `accounts_sorted()`, for the accounts in the currency,
+    /// the implicit one for a blank currency, none if there are none in it.
+    pub fn accounts_sorted_in(&self, currency: &str) -> impl Iterator<Item = &Account> {
+        self.ledger(currency)
+            .map(sorted)
+            .unwrap_or_default()
+            .into_iter()
+    }
+
+    /// Returns accounts grouped by their `locked` status, each group sorted by client ID.
+    pub fn accounts_grouped(&self, grouping: LockGrouping) -> Vec<&Account> {
+        grouped(&self.accounts, grouping)
+    }
+
+    /// Returns accounts with funds held, sorted by held amount descending,
+    /// then by client ID.
+    pub fn accounts_held(&self) -> Vec<&Account> {
+        held(&self.accounts)
+    }
+
+    /// Same as `accounts_held()`, for the accounts in the currency,
+    /// the implicit one for a blank currency, none if there are none in it.
+    pub fn accounts_held_in(&self, currency: &str) -> Vec<&Account> {
+        self.ledger(currency).map(held).unwrap_or_default()
+    }
+
+    /// Returns currencies there are accounts in, other than the implicit one, sorted.
+    pub fn currencies(&self) -> Vec<&str> {
+        let mut currencies = self.ledgers.keys().map(String::as_str).collect::<Vec<_>>();
+        currencies.sort_unstable();
+        currencies
+    }
+
+    #[cfg(test)]
+    pub fn transactions(&self) -> Values<'_, TxKey, Transaction> {
+        self.transactions.values()
+    }
+
+    #[cfg(test)]
+    pub fn get_account(&self, id: &u32) -> Option<&Account> {
+        self.accounts.get(id)
+    }
+
+    /// Returns accounts in the currency, the implicit one for a blank currency,
+    /// `None` if there are none in it.
+    fn ledger(&self, currency: &str) -> Option<&IdMap<Account>> {
+        match currency {
+            "" => Some(&self.accounts),
+            currency => self.ledgers.get(currency),
+        }
+    }
+
+    /// Returns accounts of all the currencies along with them, the implicit one
+    /// going first as a blank one, then the others in order.
+    fn all_ledgers(&self) -> Vec<(&str, &IdMap<Account>)> {
+        let mut ledgers = vec![("", &self.accounts)];
+        ledgers.extend(
+            self.currencies()
+                .into_iter()
+                .map(|currency| (currency, &self.ledgers[currency])),
+        );
+        ledgers
+    }
+
+    /// Returns account of the client in the currency, the implicit one for a blank
+    /// currency, or `None` if there is no such account.
+    pub fn get_account_in(&self, id: &u32, currency: &str) -> Option<&Account> {
+        self.ledger(currency)?.get(id)
+    }
+}
+
+/// Returns the accounts sorted by client ID.
+fn sorted(accounts: &IdMap<Account>) -> Vec<&Account> {
+    let mut accounts = accounts.values().collect::<Vec<_>>();
+    accounts.sort_unstable_by_key(|a| a.id);
+    accounts
+}
+
+/// Returns the accounts grouped by their `locked` status, each group sorted by client ID.
+fn grouped(accounts: &IdMap<Account>, grouping: LockGrouping) -> Vec<&Account> {
+    let locked_first = grouping == LockGrouping::LockedFirst;
+    let mut accounts = accounts.values().collect::<Vec<_>>();
+    accounts.sort_unstable_by_key(|a| (a.locked != locked_first, a.id));
+    accounts
+}
+
+/// Returns the accounts with funds held, sorted by held amount descending,
+/// then by client ID.
+fn held(accounts: &IdMap<Account>) -> Vec<&Account> {
+    let mut accounts = accounts.values().filter(|a| a.held > 0).collect::<Vec<_>>();
+    accounts.sort_unstable_by_key(|a| (std::cmp::Reverse(a.held), a.id));
+    accounts
+}
+
+/// Returns paths of `*.csv` files in the directory, `*.csv.gz` ones included,
+/// sorted by name.
+fn csv_files(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let csv = name.ends_with(".csv") || name.ends_with(".csv.gz");
+        if csv && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Returns ID shared by several deposits or withdrawals of a client, if any.
+fn duplicate_id<'a>(txs: impl Iterator<Item = &'a Transaction>) -> Option<u32> {
+    let mut seen = HashSet::new();
+    txs.filter(|tx| tx.ty.as_ref().is_some_and(|t| !t.is_event()))
+        .find(|tx| !seen.insert(tx.key()))
+        .map(|tx| tx.id)
+}
+
+/// Returns amount of the adjustment, along with whether it's a debit,
+/// as `Account::adjust_by()` takes them, failing with `EngineError::AmountTooLarge`
+/// if its signed amount doesn't fit in `i64`, as the delta `Account::adjust()` takes.
+fn adjustment_amount(tx: &Transaction) -> Result<(Amount, bool), EngineError> {
+    let delta = tx.signed_amount().ok_or(EngineError::EmptyAmount)?;
+    let amount = tx.amount.unwrap_or_default();
+    i64::try_from(delta).map_err(|_| EngineError::AmountTooLarge {
+        amount,
+        limit: Amount::from(i64::MAX.unsigned_abs()),
+    })?;
+    let debit = tx.adjustment.as_ref().is_some_and(|adj| adj.debit);
+    Ok((amount, debit))
 }
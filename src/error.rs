@@ -0,0 +1,388 @@
+//! Errors of the engine, and compact status codes of the ones it declines
+//! transactions with.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::account::{fmt_amount, HoldReason, OUTPUT_COLUMNS};
+use crate::transaction::{Amount, State};
+
+/// Reasons for the engine to decline a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    /// Account is locked, which happens upon a chargeback.
+    AccountFrozen,
+    /// Balance is not enough to perform the operation.
+    InsufficientFunds,
+    /// Total balance is enough to perform the operation,
+    /// but available is not, as some of the funds are held.
+    FundsHeld { available: Amount, held: Amount },
+    /// Less than the amount to release is held for the reason.
+    NotHeld { amount: Amount, reason: HoldReason },
+    /// Operation would make balance overflow.
+    Overflow,
+    /// Transaction referenced by an event is not found among stored ones.
+    TxNotFound,
+    /// Account referenced by a transaction does not exist.
+    AccountNotFound,
+    /// Client of an event is not the owner of the referenced transaction.
+    /// Not raised by the engine, which looks transactions up by client, so other
+    /// clients' ones are not found; kept for its code to stay reserved.
+    NotOwner,
+    /// Transaction with the same ID has already been processed.
+    DuplicateTx,
+    /// Transaction ended up in a state inappropriate for the operation.
+    InvalidState(State),
+    /// Referenced transaction has already been charged back, so the dispute is over.
+    AlreadyChargedBack,
+    /// Deposit or withdrawal carries no amount.
+    EmptyAmount,
+    /// Referenced transaction can't be disputed.
+    NotDisputable,
+    /// Transaction type is not specified.
+    NoType,
+    /// Record misses a required field.
+    MissingField(&'static str),
+    /// Transaction amount exceeds the configured per-transaction limit.
+    AmountTooLarge { amount: Amount, limit: Amount },
+    /// Client is present in both merged engines.
+    ClientConflict(u32),
+    /// Transaction is present in both merged engines.
+    TxConflict(u32),
+    /// Storing the transaction would exceed the configured memory budget.
+    MemoryBudgetExceeded,
+    /// Transaction type is reserved for the support team, and can't come from input.
+    AdminOnly,
+    /// Transfer targets the very account it debits.
+    SelfTransfer,
+    /// Row has a number of fields other than the header, under strict arity.
+    ArityMismatch { expected: usize, found: usize },
+    /// Account to unlock is not locked.
+    NotFrozen,
+    /// Disputed transaction is older than the dispute window, counted in transactions
+    /// processed since it.
+    DisputeExpired { age: u64, limit: u64 },
+    /// Amount of a partial dispute exceeds the one of the disputed transaction.
+    DisputeExceedsAmount { amount: Amount, original: Amount },
+    /// Disputed transaction is older than the dispute expiry time.
+    DisputeTimedOut { age: Duration, limit: Duration },
+    /// Client has as many disputes open as the limit.
+    TooManyOpenDisputes { limit: u32 },
+    /// Signature of the row is wrong or missing, so it may have been tampered with.
+    SignatureInvalid,
+    /// Transfer is declined in parallel processing, as its clients may be processed
+    /// in different shards.
+    CrossShard,
+    /// Less than the amount to release or to charge back is held,
+    /// e.g. as a part of the funds has been released otherwise.
+    HeldShortfall { amount: Amount, held: Amount },
+    /// Transaction type is not a known one, e.g. one introduced by a partner
+    /// ahead of the engine.
+    UnknownType(String),
+    /// Transaction referenced by a resolve or a chargeback is not under dispute.
+    NotDisputed(State),
+    /// Client has had as many deposits, withdrawals and transfers applied as allowed.
+    LimitExceeded { limit: u32 },
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AccountFrozen => write!(f, "account is frozen"),
+            Self::InsufficientFunds => write!(f, "insufficient total balance"),
+            Self::FundsHeld { available, held } => write!(
+                f,
+                "insufficient available balance: {} available, {} held",
+                fmt_amount(*available),
+                fmt_amount(*held)
+            ),
+            Self::NotHeld { amount, reason } => write!(
+                f,
+                "less than {} is held for {:?}",
+                fmt_amount(*amount),
+                reason
+            ),
+            Self::Overflow => write!(
+                f,
+                "tx makes balance overflow; such enourmous balances are not supported"
+            ),
+            Self::TxNotFound => write!(f, "disputed transaction not found"),
+            Self::AccountNotFound => write!(f, "dispute account does not exist"),
+            Self::NotOwner => write!(f, "dispute account is not the transaction owner"),
+            Self::DuplicateTx => write!(f, "transaction has already been processed"),
+            Self::InvalidState(s) => write!(f, "tx declined in state: {:?}", s),
+            Self::AlreadyChargedBack => write!(f, "transaction has already been charged back"),
+            Self::EmptyAmount => write!(f, "empty amount"),
+            Self::NotDisputable => write!(f, "dispute on this type of transaction is not allowed"),
+            Self::NoType => write!(f, "transaction type not specified"),
+            Self::MissingField(name) => write!(f, "missing required field: {}", name),
+            Self::AmountTooLarge { amount, limit } => write!(
+                f,
+                "tx amount {} exceeds per-transaction limit {}",
+                amount, limit
+            ),
+            Self::ClientConflict(id) => write!(f, "client {} is present in both engines", id),
+            Self::TxConflict(id) => write!(f, "transaction {} is present in both engines", id),
+            Self::MemoryBudgetExceeded => write!(f, "memory budget exceeded"),
+            Self::AdminOnly => write!(f, "transaction type is reserved for the support team"),
+            Self::SelfTransfer => write!(f, "transfer target is the client account itself"),
+            Self::NotFrozen => write!(f, "account is not frozen"),
+            Self::DisputeExpired { age, limit } => write!(
+                f,
+                "transaction is too old to dispute: {} transactions ago, while window is {}",
+                age, limit
+            ),
+            Self::DisputeExceedsAmount { amount, original } => write!(
+                f,
+                "disputed amount {} exceeds transaction amount {}",
+                fmt_amount(*amount),
+                fmt_amount(*original)
+            ),
+            Self::DisputeTimedOut { age, limit } => write!(
+                f,
+                "transaction is too old to dispute: {:?} ago, while expiry is {:?}",
+                age, limit
+            ),
+            Self::TooManyOpenDisputes { limit } => {
+                write!(f, "too many open disputes, the limit is {}", limit)
+            }
+            Self::SignatureInvalid => write!(f, "transaction signature is invalid"),
+            Self::CrossShard => write!(f, "transfers are not supported in parallel processing"),
+            Self::HeldShortfall { amount, held } => write!(
+                f,
+                "less than {} is held: {} held",
+                fmt_amount(*amount),
+                fmt_amount(*held)
+            ),
+            Self::UnknownType(name) => write!(f, "unknown transaction type: {}", name),
+            Self::NotDisputed(s) => write!(f, "tx is not under dispute, but {:?}", s),
+            Self::LimitExceeded { limit } => {
+                write!(f, "client has reached the limit of {} transactions", limit)
+            }
+            Self::ArityMismatch { expected, found } => {
+                write!(f, "row has {} fields, while header has {}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+/// Violations found in balances CSV on validation.
+/// Rows are told by their client, `None` for a subtotal row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputError {
+    /// Columns differ from the ones the engine emits.
+    UnexpectedColumns(Vec<String>),
+    /// Row is malformed, e.g. holds an invalid amount.
+    Malformed { line: u64, reason: String },
+    /// Balances of the row don't add up: `available + held != total`.
+    Unbalanced { line: u64, client: Option<u32> },
+    /// Held funds breakdown of the row doesn't add up to `held`.
+    BreakdownMismatch { line: u64, client: Option<u32> },
+    /// Subtotal row doesn't carry the sums of the account rows before it.
+    SubtotalMismatch { line: u64 },
+    /// Input is not a readable CSV.
+    Csv(String),
+}
+
+/// Name of the row told by its client, for error messages.
+struct Row(Option<u32>);
+
+impl fmt::Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "client {}", id),
+            None => write!(f, "subtotal"),
+        }
+    }
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedColumns(columns) => write!(
+                f,
+                "unexpected columns: {:?}, expected: {:?}",
+                columns, OUTPUT_COLUMNS
+            ),
+            Self::Malformed { line, reason } => write!(f, "line {}: {}", line, reason),
+            Self::Unbalanced { line, client } => write!(
+                f,
+                "line {}: {}: available + held != total",
+                line,
+                Row(*client)
+            ),
+            Self::BreakdownMismatch { line, client } => write!(
+                f,
+                "line {}: {}: held funds breakdown != held",
+                line,
+                Row(*client)
+            ),
+            Self::SubtotalMismatch { line } => {
+                write!(f, "line {}: subtotal != sums of the rows before it", line)
+            }
+            Self::Csv(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+/// Amount string which the `AmountParser` failed to parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmountError(pub String);
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid amount: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+/// Compact status code of an error, for reporting declined transactions in bulk.
+/// Variants carrying details map to the same code regardless of them.
+pub type Code = u16;
+
+impl EngineError {
+    /// Returns compact status code of the error, see `describe()` for its text.
+    pub fn code(&self) -> Code {
+        match self {
+            Self::AccountFrozen => 1,
+            Self::InsufficientFunds => 2,
+            Self::FundsHeld { .. } => 3,
+            Self::Overflow => 4,
+            Self::TxNotFound => 5,
+            Self::AccountNotFound => 6,
+            Self::NotOwner => 7,
+            Self::DuplicateTx => 8,
+            Self::InvalidState(_) => 9,
+            Self::EmptyAmount => 10,
+            Self::NotDisputable => 11,
+            Self::NoType => 12,
+            Self::MissingField(_) => 13,
+            Self::AmountTooLarge { .. } => 14,
+            Self::ClientConflict(_) => 15,
+            Self::TxConflict(_) => 16,
+            Self::AlreadyChargedBack => 17,
+            Self::MemoryBudgetExceeded => 18,
+            Self::AdminOnly => 19,
+            Self::NotHeld { .. } => 20,
+            Self::SelfTransfer => 21,
+            Self::ArityMismatch { .. } => 22,
+            Self::NotFrozen => 23,
+            Self::DisputeExpired { .. } => 24,
+            Self::DisputeExceedsAmount { .. } => 25,
+            Self::DisputeTimedOut { .. } => 26,
+            Self::TooManyOpenDisputes { .. } => 27,
+            Self::SignatureInvalid => 28,
+            Self::CrossShard => 29,
+            Self::HeldShortfall { .. } => 30,
+            Self::UnknownType(_) => 31,
+            Self::NotDisputed(_) => 32,
+            Self::LimitExceeded { .. } => 33,
+        }
+    }
+}
+
+/// Code, short name and description of every `EngineError` variant, in the order of codes.
+const CATALOG: &[(Code, &str, &str)] = &[
+    (1, "AccountFrozen", "account is frozen"),
+    (2, "InsufficientFunds", "insufficient balance"),
+    (
+        3,
+        "FundsHeld",
+        "insufficient available balance, funds are held for dispute",
+    ),
+    (4, "Overflow", "tx makes balance overflow"),
+    (5, "TxNotFound", "disputed transaction not found"),
+    (6, "AccountNotFound", "dispute account does not exist"),
+    (
+        7,
+        "NotOwner",
+        "dispute account is not the transaction owner",
+    ),
+    (8, "DuplicateTx", "transaction has already been processed"),
+    (9, "InvalidState", "tx declined in its current state"),
+    (10, "EmptyAmount", "empty amount"),
+    (
+        11,
+        "NotDisputable",
+        "dispute on this type of transaction is not allowed",
+    ),
+    (12, "NoType", "transaction type not specified"),
+    (13, "MissingField", "missing required field"),
+    (
+        14,
+        "AmountTooLarge",
+        "tx amount exceeds per-transaction limit",
+    ),
+    (15, "ClientConflict", "client is present in both engines"),
+    (16, "TxConflict", "transaction is present in both engines"),
+    (
+        17,
+        "AlreadyChargedBack",
+        "transaction has already been charged back",
+    ),
+    (18, "MemoryBudgetExceeded", "memory budget exceeded"),
+    (
+        19,
+        "AdminOnly",
+        "transaction type is reserved for the support team",
+    ),
+    (20, "NotHeld", "less than the amount is held for the reason"),
+    (
+        21,
+        "SelfTransfer",
+        "transfer target is the client account itself",
+    ),
+    (
+        22,
+        "ArityMismatch",
+        "number of fields in the row does not match the header",
+    ),
+    (23, "NotFrozen", "account is not frozen"),
+    (24, "DisputeExpired", "transaction is too old to dispute"),
+    (
+        25,
+        "DisputeExceedsAmount",
+        "disputed amount exceeds transaction amount",
+    ),
+    (26, "DisputeTimedOut", "transaction dispute has expired"),
+    (
+        27,
+        "TooManyOpenDisputes",
+        "too many open disputes on the account",
+    ),
+    (28, "SignatureInvalid", "transaction signature is invalid"),
+    (
+        29,
+        "CrossShard",
+        "transfers are not supported in parallel processing",
+    ),
+    (30, "HeldShortfall", "less than the amount is held"),
+    (31, "UnknownType", "unknown transaction type"),
+    (32, "NotDisputed", "tx is not under dispute"),
+    (
+        33,
+        "LimitExceeded",
+        "client has reached the limit of transactions",
+    ),
+];
+
+impl EngineError {
+    /// Returns code, short name and description of every variant, in the order of codes,
+    /// e.g. for a legend of error reports.
+    pub fn catalog() -> &'static [(Code, &'static str, &'static str)] {
+        CATALOG
+    }
+}
+
+/// Returns human-readable description of an error code.
+pub fn describe(code: Code) -> &'static str {
+    CATALOG
+        .iter()
+        .find(|(c, _, _)| *c == code)
+        .map_or("unknown error code", |(_, _, text)| text)
+}
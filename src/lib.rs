@@ -0,0 +1,20 @@
+//! Toy Payments Engine.
+//! Reads a series of transactions from CSV, updates client accounts,
+//! handles disputes and chargebacks, and outputs the state of clients accounts.
+
+pub mod account;
+pub mod clock;
+#[cfg(feature = "decimal")]
+pub mod decimal;
+#[cfg(feature = "encoding")]
+pub mod encoding;
+pub mod engine;
+pub mod error;
+#[cfg(feature = "hmac")]
+pub mod hmac;
+pub mod snapshot;
+pub mod transaction;
+pub mod wal;
+
+#[cfg(test)]
+mod tests;
@@ -1,64 +1,385 @@
-use csv::Trim;
-use std::{env, error::Error, ffi::OsString, fs::File, io, process};
+use std::{env, error::Error, ffi::OsString, fs::File, io, path::Path, process, time::Instant};
 
-mod account;
-mod engine;
-mod transaction;
+use toy_payments_engine::account::{read_accounts, validate_output};
+use toy_payments_engine::engine::{
+    decompress, write_errors, write_rejects, Compression, Engine, HeldOverflow, LockGrouping,
+    OutputFormat, RunReport,
+};
+use toy_payments_engine::transaction::{MinorUnitsParser, DECIMALS};
 
-use crate::account::AccountSer;
-use crate::engine::Engine;
-use crate::transaction::*;
+/// Command line options of the processing mode.
+#[derive(Debug, Default)]
+struct Args {
+    /// Path to the transactions file, or to a directory of them, stdin is read if not set.
+    input: Option<OsString>,
+    /// Prior balances snapshot, as in `--since <balances.csv>`, makes us start off it
+    /// and output only the changes.
+    since: Option<OsString>,
+    /// Opening balances, as in `--opening <balances.csv>`, to apply transactions on top of.
+    opening: Option<OsString>,
+    /// Encoding of the input, as in `--encoding latin1`, UTF-8 if not set.
+    encoding: Option<String>,
+    /// Compression of the input, as in `--compression gzip`, told by the names
+    /// of the input files if not set, none for stdin.
+    compression: Option<Compression>,
+    /// Break held funds out by the reason, as in `--held-breakdown`.
+    held_breakdown: bool,
+    /// Output position of the last transaction applied to each account, as in `--last-seq`.
+    last_seq: bool,
+    /// Show available balance of an underwater account negative rather than zero,
+    /// as in `--signed-available`.
+    signed_available: bool,
+    /// Treatment of rows of accounts with more funds held than they have,
+    /// as in `--held-overflow clamp`, output as they are if not set.
+    held_overflow: Option<HeldOverflow>,
+    /// End the accounts of each currency with their subtotals, as in `--currency-subtotals`.
+    currency_subtotals: bool,
+    /// Grouping of the output accounts by their `locked` status,
+    /// as in `--group locked-first`.
+    group: Option<LockGrouping>,
+    /// Output only accounts with funds held, largest first, as in `--held-only`.
+    held_only: bool,
+    /// Format of the accounts table, as in `--format json` or `--format ndjson`, CSV if not set.
+    format: Option<OutputFormat>,
+    /// File to write the accounts table to, as in `--output <balances.csv>`,
+    /// stdout if not set.
+    output: Option<OsString>,
+    /// Errors file to write, as in `--errors <errors.csv>`, listing declined
+    /// transactions and malformed rows of the input.
+    errors: Option<OsString>,
+    /// Table to insert accounts into with `--format sql`, as in `--table balances`.
+    table: Option<String>,
+    /// Keep reading transactions from stdin after the input file, as in `--stdin`,
+    /// until it gets closed.
+    stdin: bool,
+    /// Report time spent in each phase of the run to stderr, as in `--timing`.
+    timing: bool,
+    /// Declined transactions file to write, as in `--rejects <rejects.csv>`,
+    /// for them to be fixed and resubmitted.
+    rejects: Option<OsString>,
+    /// Accept privileged operations, e.g. unlocks, from the input, as in `--admin-ops`.
+    admin_ops: bool,
+    /// Make disputes carrying an amount dispute only that part of their transactions,
+    /// as in `--partial-disputes`.
+    partial_disputes: bool,
+    /// Drop amounts with more decimal digits than supported instead of truncating them,
+    /// or keeping them with `decimal` feature,
+    /// as in `--exact-amounts`.
+    exact_amounts: bool,
+    /// Take amounts for integer counts of cents, as in `--minor-units`.
+    minor_units: bool,
+    /// Take amounts with a currency symbol or grouped digits, as in `$1,234.56`,
+    /// as in `--lenient-amounts`.
+    lenient_amounts: bool,
+    /// File holding the key shared with the producer of the input, as in
+    /// `--signing-key <key.txt>`, for signatures of its rows to be verified.
+    signing_key: Option<OsString>,
+    /// Decline rows with a number of fields other than the header has,
+    /// as in `--strict-arity`.
+    strict_arity: bool,
+    /// Number of threads to process the input on, sharded by client, as in `--threads 4`.
+    threads: Option<usize>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, Box<dyn Error>> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--since") => {
+                    parsed.since = Some(
+                        args.next()
+                            .ok_or("expected path to the balances snapshot")?,
+                    )
+                }
+                Some("--opening") => {
+                    parsed.opening =
+                        Some(args.next().ok_or("expected path to the opening balances")?)
+                }
+                Some("--signing-key") => {
+                    parsed.signing_key = Some(args.next().ok_or("expected path to the key file")?)
+                }
+                Some("--rejects") => {
+                    parsed.rejects = Some(args.next().ok_or("expected path to the rejects file")?)
+                }
+                Some("--output") => {
+                    parsed.output = Some(args.next().ok_or("expected path to the output file")?)
+                }
+                Some("--errors") => {
+                    parsed.errors = Some(args.next().ok_or("expected path to the errors file")?)
+                }
+                Some("--encoding") => {
+                    parsed.encoding = Some(
+                        args.next()
+                            .and_then(|s| s.into_string().ok())
+                            .ok_or("expected encoding name")?,
+                    )
+                }
+                Some("--held-breakdown") => parsed.held_breakdown = true,
+                Some("--last-seq") => parsed.last_seq = true,
+                Some("--signed-available") => parsed.signed_available = true,
+                Some("--currency-subtotals") => parsed.currency_subtotals = true,
+                Some("--stdin") => parsed.stdin = true,
+                Some("--timing") => parsed.timing = true,
+                Some("--held-only") => parsed.held_only = true,
+                Some("--strict-arity") => parsed.strict_arity = true,
+                Some("--exact-amounts") => parsed.exact_amounts = true,
+                Some("--minor-units") => parsed.minor_units = true,
+                Some("--lenient-amounts") => parsed.lenient_amounts = true,
+                Some("--admin-ops") => parsed.admin_ops = true,
+                Some("--partial-disputes") => parsed.partial_disputes = true,
+                Some("--format") => {
+                    let format = args.next().ok_or("expected output format")?;
+                    parsed.format = Some(format.to_str().unwrap_or_default().parse()?)
+                }
+                Some("--table") => {
+                    parsed.table = Some(
+                        args.next()
+                            .and_then(|s| s.into_string().ok())
+                            .ok_or("expected table name")?,
+                    )
+                }
+                Some("--threads") => {
+                    let threads = args.next().ok_or("expected number of threads")?;
+                    parsed.threads = Some(threads.to_str().unwrap_or_default().parse()?)
+                }
+                Some("--held-overflow") => {
+                    let treatment = args.next().ok_or("expected held overflow treatment")?;
+                    parsed.held_overflow = Some(treatment.to_str().unwrap_or_default().parse()?)
+                }
+                Some("--compression") => {
+                    let compression = args.next().ok_or("expected compression")?;
+                    parsed.compression = Some(compression.to_str().unwrap_or_default().parse()?)
+                }
+                Some("--group") => {
+                    let group = args.next().ok_or("expected grouping")?;
+                    parsed.group = Some(group.to_str().unwrap_or_default().parse()?)
+                }
+                _ if parsed.input.is_none() => parsed.input = Some(arg),
+                _ => return Err(format!("unexpected argument: {:?}", arg).into()),
+            }
+        }
+        Ok(parsed)
+    }
+}
 
 fn run() -> Result<(), Box<dyn Error>> {
-    let mut engine = Engine::new();
+    let args = Args::parse(env::args_os().skip(1))?;
+    let mut engine = Engine::new()
+        .with_timing(args.timing)
+        .with_held_breakdown(args.held_breakdown)
+        .with_last_seq(args.last_seq)
+        .with_signed_available(args.signed_available)
+        .with_currency_subtotals(args.currency_subtotals)
+        .with_held_only(args.held_only)
+        .with_strict_arity(args.strict_arity)
+        .with_admin_ops(args.admin_ops)
+        .with_partial_disputes(args.partial_disputes)
+        .with_rejects(args.rejects.is_some());
+    if let Some(treatment) = args.held_overflow {
+        engine = engine.with_held_overflow(treatment);
+    }
+    if let Some(format) = args.format {
+        engine = engine.with_format(format);
+    }
+    if let Some(table) = &args.table {
+        engine = engine.with_sql_table(table);
+    }
+    if let Some(grouping) = args.group {
+        engine = engine.with_grouping(grouping);
+    }
+    if let Some(compression) = args.compression {
+        engine = engine.with_compression(compression);
+    }
+    if args.lenient_amounts && (args.exact_amounts || args.minor_units) {
+        return Err("--lenient-amounts goes without --exact-amounts and --minor-units".into());
+    }
+    engine = engine.with_lenient_amounts(args.lenient_amounts);
+    match (args.exact_amounts, args.minor_units) {
+        (true, true) => return Err("--exact-amounts goes without --minor-units".into()),
+        (true, false) => engine = with_exact_amounts(engine)?,
+        (false, true) => {
+            engine = engine.with_amount_parser(MinorUnitsParser::<DECIMALS>::default())
+        }
+        (false, false) => (),
+    }
+    if let Some(path) = &args.signing_key {
+        engine = with_signing_key(engine, path)?;
+    }
+    if let Some(path) = &args.opening {
+        engine.load_accounts(read_accounts(File::open(path)?)?);
+    }
 
-    let file_path = get_first_arg()?;
-    let file = File::open(file_path)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .trim(Trim::All)
-        .flexible(true)
-        .from_reader(file);
-    // input
-    // ignores failed to be parsed entries
-    for entry in rdr.deserialize().flatten() {
-        // load
-        let mut tx: Transaction = entry;
-        let s = Box::new(Received);
-        if tx.init(s).is_ok() {
-            // process
-            // infalible run, we ignore errors,
-            // faulty transactions are simply discarded
-            let _ = engine.process(tx);
+    let out: Box<dyn io::Write> = match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let dir = args.input.as_ref().filter(|path| Path::new(path).is_dir());
+    let report = match dir {
+        // a directory of daily exports, or alike
+        Some(dir) if args.since.is_none() && !args.stdin && args.encoding.is_none() => {
+            engine.run(dir, out)?
+        }
+        Some(_) => {
+            return Err("directory input goes without --since, --stdin and --encoding".into())
         }
+        None => {
+            let input = match &args.input {
+                Some(file_path) => {
+                    open_input(engine.open_file(file_path)?, args.encoding.as_deref())?
+                }
+                None => open_input(stdin(&args)?, args.encoding.as_deref())?,
+            };
+            match args.threads {
+                Some(_) if args.since.is_some() || args.stdin => {
+                    return Err("--threads goes without --since and --stdin".into())
+                }
+                Some(threads) => engine.run_parallel(input, out, threads)?,
+                None if args.since.is_none() && !args.stdin => engine.run_reader(input, out)?,
+                None => run_with_options(&mut engine, &args, input, out)?,
+            }
+        }
+    };
+    if let Some(path) = &args.rejects {
+        write_rejects(&report.rejects, File::create(path)?)?;
+    }
+    if let Some(path) = &args.errors {
+        write_errors(&report, File::create(path)?)?;
+    }
+    if let Some(timing) = report.timing {
+        eprintln!(
+            "timing: parsing {:?}, processing {:?}, output {:?}",
+            timing.parsing, timing.processing, timing.output
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs the engine with input and output set up as the options require:
+/// deltas tailing from stdin, changes since prior snapshot.
+fn run_with_options(
+    engine: &mut Engine,
+    args: &Args,
+    input: impl io::Read,
+    mut out: impl io::Write,
+) -> Result<RunReport, Box<dyn Error>> {
+    let prior = match &args.since {
+        Some(path) => Some(read_accounts(File::open(path)?)?),
+        None => None,
+    };
+    if let Some(prior) = &prior {
+        engine.load_accounts(prior.iter().copied());
+    }
+    // input: base file first, then the deltas tailing from stdin, if asked
+    // and not read already
+    let mut summary = engine.ingest(input);
+    if args.stdin && args.input.is_some() {
+        summary.extend(engine.ingest(open_input(stdin(args)?, args.encoding.as_deref())?));
     }
+    let mut report = RunReport::from(summary);
+    let start = Instant::now();
 
     // output
-    let mut wtr = csv::WriterBuilder::new()
-        .has_headers(true)
-        .from_writer(io::stdout());
-    for client in engine.accounts() {
-        wtr.serialize(AccountSer::from(*client))?
+    match prior {
+        Some(prior) => {
+            let mut wtr = csv::Writer::from_writer(&mut out);
+            for delta in engine.deltas(&prior) {
+                wtr.serialize(delta)?
+            }
+            wtr.flush()?;
+        }
+        None => engine.write_accounts(&mut out)?,
+    }
+    if let Some(timing) = &mut report.timing {
+        timing.output = start.elapsed();
     }
-    wtr.flush()?;
 
-    Ok(())
+    Ok(report)
 }
 
-/// Returns the first positional argument sent to this process. If there are no
-/// positional arguments, then this returns an error.
-fn get_first_arg() -> Result<OsString, Box<dyn Error>> {
-    match env::args_os().nth(1) {
-        None => Err(From::from("expected 1 argument, but got none")),
-        Some(file_path) => Ok(file_path),
+/// Returns stdin, decompressed as asked.
+fn stdin(args: &Args) -> io::Result<Box<dyn io::Read>> {
+    decompress(io::stdin(), args.compression.unwrap_or(Compression::Plain))
+}
+
+/// Wraps input into a reader transcoding it from the given encoding to UTF-8.
+#[cfg(feature = "encoding")]
+fn open_input(
+    file: impl io::Read + 'static,
+    encoding: Option<&str>,
+) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+    use toy_payments_engine::encoding::transcoding_reader;
+
+    match encoding {
+        None => Ok(Box::new(file)),
+        Some(label) => Ok(Box::new(
+            transcoding_reader(file, label).ok_or(format!("unknown encoding: {}", label))?,
+        )),
     }
 }
 
+#[cfg(not(feature = "encoding"))]
+fn open_input(
+    file: impl io::Read + 'static,
+    encoding: Option<&str>,
+) -> Result<Box<dyn io::Read>, Box<dyn Error>> {
+    match encoding {
+        None => Ok(Box::new(file)),
+        Some(_) => Err("built without `encoding` feature, only UTF-8 input is supported".into()),
+    }
+}
+
+/// Makes the engine parse input amounts exactly, dropping ones with more digits
+/// than its precision.
+#[cfg(feature = "decimal")]
+fn with_exact_amounts(engine: Engine) -> Result<Engine, Box<dyn Error>> {
+    use toy_payments_engine::decimal::ExactParser;
+
+    Ok(engine.with_amount_parser(ExactParser::<DECIMALS>))
+}
+
+#[cfg(not(feature = "decimal"))]
+fn with_exact_amounts(_engine: Engine) -> Result<Engine, Box<dyn Error>> {
+    Err("built without `decimal` feature, amounts can't be parsed exactly".into())
+}
+
+/// Makes the engine verify signatures of the input rows with the key read from the file,
+/// its trailing line break aside.
+#[cfg(feature = "hmac")]
+fn with_signing_key(engine: Engine, path: &OsString) -> Result<Engine, Box<dyn Error>> {
+    let mut key = std::fs::read(path)?;
+    while key.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+        key.pop();
+    }
+    Ok(engine.with_signing_key(key))
+}
+
+#[cfg(not(feature = "hmac"))]
+fn with_signing_key(_engine: Engine, _path: &OsString) -> Result<Engine, Box<dyn Error>> {
+    Err("built without `hmac` feature, signatures can't be verified".into())
+}
+
+/// Validates balances CSV given as the second positional argument,
+/// as in `validate-output <balances.csv>`.
+fn validate() -> Result<(), Box<dyn Error>> {
+    let file_path = env::args_os()
+        .nth(2)
+        .ok_or("expected path to the balances file")?;
+    let file = File::open(file_path)?;
+    let rows = validate_output(file)?;
+    println!("ok: {} rows", rows);
+
+    Ok(())
+}
+
 fn main() {
-    if let Err(err) = run() {
+    let res = match env::args_os().nth(1) {
+        Some(mode) if mode == "validate-output" => validate(),
+        _ => run(),
+    };
+    if let Err(err) = res {
         println!("{}", err);
         process::exit(1);
     }
 }
-
-#[cfg(test)]
-mod tests;
@@ -0,0 +1,87 @@
+//! Checkpoints of engine state, for long-running or resumable jobs to persist it
+//! and resume later, see `Engine::snapshot()` and `Engine::restore()`.
+
+use std::time::SystemTime;
+
+use crate::account::Account;
+use crate::transaction::{Adjustment, Amount, Transaction, Tx, TxKey, TxStateKind};
+
+/// Engine state: accounts and stored transactions, along with the bookkeeping
+/// of disputes and redeliveries, sorted by client, then by transaction ID.
+/// Amounts are integers of the engine scale, or decimals of it with `decimal` feature,
+/// so it is to be restored into an engine of the same one.
+/// Engine configuration is not a part of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct EngineSnapshot {
+    pub accounts: Vec<Account>,
+    /// Accounts in currencies other than the implicit one, by the currency.
+    #[serde(default)]
+    pub ledgers: Vec<(String, Vec<Account>)>,
+    pub transactions: Vec<TransactionSnapshot>,
+    /// Number of disputes per transaction, if tracked.
+    pub dispute_cycles: Vec<(TxKey, u32)>,
+    /// Last event applied to each transaction, and its position in the sequence,
+    /// if tracked.
+    pub last_events: Vec<(TxKey, Tx, u64)>,
+    /// Number of transactions processed.
+    pub seq: u64,
+    /// Number of transactions applied.
+    #[serde(default)]
+    pub applied: u64,
+    /// Keys of the stored transactions, in the order they were stored.
+    #[serde(default)]
+    pub order: Vec<TxKey>,
+}
+
+/// Stored transaction, along with its state, which input records don't carry.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TransactionSnapshot {
+    pub id: u32,
+    pub ty: Option<Tx>,
+    pub client: u32,
+    pub amount: Option<Amount>,
+    pub target: Option<u32>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    pub adjustment: Option<Adjustment>,
+    pub disputed: Option<Amount>,
+    pub state: Option<TxStateKind>,
+    pub seq: u64,
+    pub at: Option<SystemTime>,
+}
+
+impl From<&Transaction> for TransactionSnapshot {
+    fn from(tx: &Transaction) -> Self {
+        TransactionSnapshot {
+            id: tx.id,
+            ty: tx.ty.clone(),
+            client: tx.client,
+            amount: tx.amount,
+            target: tx.target,
+            currency: tx.currency.clone(),
+            adjustment: tx.adjustment.as_deref().cloned(),
+            disputed: tx.disputed,
+            state: tx.state,
+            seq: tx.seq,
+            at: tx.at,
+        }
+    }
+}
+
+impl From<TransactionSnapshot> for Transaction {
+    fn from(tx: TransactionSnapshot) -> Self {
+        Transaction {
+            id: tx.id,
+            ty: tx.ty.clone(),
+            client: tx.client,
+            amount: tx.amount,
+            target: tx.target,
+            currency: tx.currency,
+            adjustment: tx.adjustment.map(Box::new),
+            disputed: tx.disputed,
+            state: tx.state,
+            seq: tx.seq,
+            at: tx.at,
+        }
+    }
+}
@@ -1,7 +1,17 @@
-use super::*;
-use crate::account::Account;
+use csv::Trim;
+use std::{ffi::OsString, fs::File};
+
+use crate::account::{
+    fmt_amount, fmt_scaled_amount, read_accounts, validate_output, Account, AccountDiff,
+    AccountSer, HoldReason,
+};
+use crate::engine::{Engine, HeldOverflow, LockGrouping, Metrics, OutputFormat, Summary, TieBreak};
+use crate::error::{describe, AmountError, EngineError, OutputError};
+use crate::transaction::*;
 use test_utils::*;
 
+// amounts of `decimal` feature keep the digits beyond the precision
+#[cfg(not(feature = "decimal"))]
 #[test]
 fn amount_deserializing_works() {
     let mut env = Env::new();
@@ -25,7 +35,7 @@ deposit, 7, 7, 0.0001
 # those should fail on parsing
 # [5 fails]
 deposit, 4, 5, -1
-deposit, 4, 6, 10e20
+deposit, 4, 6, 10e40
 deposit, 4, 7,
 deposit, 4, 8
 deposit, 4, 9, {2}.{3}
@@ -33,10 +43,10 @@ deposit, 4, 9, {2}.{3}
 # [1 fail]
 deposit, 7, 7, 0.00009
 ",
-        u64::MAX / 10_000,
-        u64::MAX % 10_000,
-        u64::MAX / 10_000,
-        u64::MAX % 10_000 + 1,
+        MAX_AMOUNT / 10_000,
+        MAX_AMOUNT % 10_000,
+        MAX_AMOUNT / 10_000,
+        MAX_AMOUNT % 10_000 + 1,
     );
     // process all transactions
     env.process(&data);
@@ -49,11 +59,46 @@ deposit, 7, 7, 0.00009
     assert_eq!(env.acc(2).total, 1234);
     assert_eq!(env.acc(3).total, 12345);
     assert_eq!(env.acc(4).total, 10000);
-    assert_eq!(env.acc(5).total, u64::MAX);
-    assert_eq!(env.acc(6).total, u64::MAX);
+    assert_eq!(env.acc(5).total, MAX_AMOUNT);
+    assert_eq!(env.acc(6).total, MAX_AMOUNT);
     assert_eq!(env.acc(7).total, 1);
 }
 
+#[test]
+fn process_returns_new_balances() {
+    let mut engine = Engine::new();
+    let tx = Transaction::builder().deposit(1, 1, "10").unwrap();
+    let acc = engine.process_with_result(tx).unwrap();
+    assert_eq!((acc.id, acc.total), (1, 10 * SCALE));
+    assert_eq!(engine.get_account(&1), Some(&acc));
+
+    // the account debited by a transfer
+    let tx = Transaction::builder().transfer(1, 2, "4", 2).unwrap();
+    assert_eq!(engine.process_with_result(tx).unwrap().total, 6 * SCALE);
+    let tx = Transaction::builder().deposit(1, 3, "5").unwrap();
+    engine.process_with_result(tx).unwrap();
+    let acc = engine
+        .process_with_result(Transaction::builder().dispute(1, 3))
+        .unwrap();
+    assert_eq!((acc.total, acc.held), (11 * SCALE, 5 * SCALE));
+
+    // declined ones are reported as they are
+    let tx = Transaction::builder().withdrawal(1, 4, "12").unwrap();
+    assert_eq!(
+        engine.process_with_result(tx),
+        Err(EngineError::InsufficientFunds)
+    );
+
+    // the account in the currency of the transaction
+    let mut tx = Transaction::builder().deposit(1, 5, "3").unwrap();
+    tx.currency = Some("EUR".into());
+    let acc = engine.process_with_result(tx).unwrap();
+    assert_eq!((acc.total, acc.held), (3 * SCALE, units(0)));
+    assert_eq!(engine.get_account_in(&1, "EUR"), Some(&acc));
+    assert_eq!(engine.get_account(&1).unwrap().total, 11 * SCALE);
+}
+
+#[cfg(not(feature = "decimal"))]
 #[test]
 fn deposit_and_withdrawal_work() {
     let mut env = Env::new();
@@ -140,6 +185,353 @@ deposit, 3, 4, 70
     assert_eq!(acc.total, 4200000);
 }
 
+#[test]
+fn holds_fail_rather_than_clamp() {
+    // held funds breakdown inconsistent with held ones, as loaded
+    let mut acc = Account {
+        total: MAX_AMOUNT,
+        held_manual: MAX_AMOUNT,
+        ..Account::new(1)
+    };
+    assert_eq!(
+        acc.hold_for(HoldReason::Manual, units(1)),
+        Err(EngineError::Overflow)
+    );
+    assert_eq!((acc.held, acc.held_manual), (units(0), MAX_AMOUNT));
+    // the hold itself can't overflow, as held funds never exceed total balance
+    assert_eq!(
+        acc.hold_for(HoldReason::Fraud, units(1)),
+        Ok(MAX_AMOUNT - units(1))
+    );
+
+    let mut acc = Account {
+        total: units(10),
+        held: units(3),
+        ..Account::new(1)
+    };
+    let shortfall = Err(EngineError::HeldShortfall {
+        amount: units(5),
+        held: units(3),
+    });
+    assert_eq!(acc.release(units(5)), shortfall);
+    assert_eq!(acc.chargeback(units(5)), shortfall);
+    assert_eq!(
+        (acc.total, acc.held, acc.locked),
+        (units(10), units(3), false)
+    );
+
+    // total balance dropped below held funds, e.g. upon an adjustment, is overdrawn
+    let mut acc = Account {
+        total: units(2),
+        held: units(3),
+        ..Account::new(1)
+    };
+    assert_eq!(acc.chargeback(units(3)), Ok(units(0)));
+    assert_eq!(
+        (acc.total_signed(), acc.held, acc.locked),
+        (signed_units(-1), units(0), true)
+    );
+}
+
+#[test]
+fn balance_of_works() {
+    let mut env = Env::new();
+    env.process(
+        "type, client, tx, amount\ndeposit, 1, 1, 0.1234\ndeposit, 1, 2, 2.5\ndispute, 1, 2,",
+    );
+    let strings = |b: (&str, &str, &str)| (b.0.to_owned(), b.1.to_owned(), b.2.to_owned());
+    assert_eq!(
+        env.engine.balance_of(&1),
+        Some(strings(("0.1234", "2.5", "2.6234")))
+    );
+    assert_eq!(env.engine.balance_of(&2), None);
+
+    // of the engine precision, and of the sign if configured so
+    let mut engine = Engine::<8>::default().with_signed_available(true);
+    engine.load_accounts([Account {
+        total: units(1),
+        held: units(3),
+        ..Account::new(1)
+    }]);
+    assert_eq!(
+        engine.balance_of(&1),
+        Some(strings(("-0.00000002", "0.00000003", "0.00000001")))
+    );
+}
+
+#[test]
+fn metrics_work() {
+    let mut env = Env::with_engine(Engine::new().with_partial_disputes(true));
+    let data = "\
+type, client, tx, amount
+deposit, 3, 1, 1300.4233
+deposit, 3, 2, 420
+chargeback, 3, 1, 0
+dispute, 3, 1,
+chargeback, 3, 1, 0
+withdrawal, 3, 3, 100000
+deposit, 3, 4, 70
+";
+    env.process(data);
+    assert_eq!(
+        env.engine.metrics(),
+        Metrics {
+            deposits: units(17_204_233),
+            withdrawals: units(0),
+            disputed: units(0),
+            frozen_accounts: 1,
+            balance: signed_units(4_200_000),
+        }
+    );
+
+    // disputes count while they're open, for the part disputed
+    env.process(
+        "\
+type, client, tx, amount
+deposit, 1, 5, 10
+withdrawal, 1, 6, 4
+deposit, 2, 7, 5
+dispute, 1, 5, 2
+dispute, 2, 7,
+resolve, 2, 7,
+",
+    );
+    let metrics = env.engine.metrics();
+    assert_eq!(
+        (metrics.deposits, metrics.withdrawals, metrics.disputed),
+        (units(17_204_233) + 15 * SCALE, 4 * SCALE, 2 * SCALE)
+    );
+    assert_eq!(metrics.frozen_accounts, 1);
+    assert_eq!(metrics.balance, signed((420 + 6 + 5) * SCALE));
+}
+
+#[test]
+fn metrics_cover_every_currency() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10,
+deposit, 1, 2, 5, EUR
+deposit, 2, 3, 7, USD
+dispute, 2, 3,, USD
+chargeback, 2, 3,, USD
+";
+    env.process(data);
+    let metrics = env.engine.metrics();
+    assert_eq!(metrics.deposits, 22 * SCALE);
+    assert_eq!(metrics.frozen_accounts, 1);
+    assert_eq!(metrics.balance, signed(15 * SCALE));
+}
+
+#[test]
+fn state_transitions_work() {
+    use TxStateKind::*;
+
+    type Transition = fn(TxStateKind) -> TxStateKind;
+    let execute: Transition = TxStateKind::execute;
+    let dispute: Transition = TxStateKind::dispute;
+    let resolve: Transition = TxStateKind::resolve;
+    let revert: Transition = TxStateKind::revert;
+    let reinstate: Transition = TxStateKind::reinstate;
+    // (from, transition, to), invalid transitions keep the state as is
+    let cases = [
+        (Received, execute, Executed),
+        (Received, dispute, Received),
+        (Received, resolve, Received),
+        (Received, revert, Received),
+        (Received, reinstate, Received),
+        (Executed, execute, Executed),
+        (Executed, dispute, Disputed),
+        (Executed, resolve, Executed),
+        (Executed, revert, Executed),
+        (Executed, reinstate, Executed),
+        (Disputed, execute, Disputed),
+        (Disputed, dispute, Disputed),
+        (Disputed, resolve, Executed),
+        (Disputed, revert, Reverted),
+        (Disputed, reinstate, Disputed),
+        (Reverted, execute, Reverted),
+        (Reverted, dispute, Reverted),
+        (Reverted, resolve, Reverted),
+        (Reverted, revert, Reverted),
+        (Reverted, reinstate, Executed),
+    ];
+    for (i, (from, transition, to)) in cases.into_iter().enumerate() {
+        assert_eq!(transition(from), to, "case {}", i);
+    }
+
+    // same for a transaction, driven through its whole lifecycle
+    let mut tx = read_txs("type, client, tx, amount\ndeposit, 1, 1, 1")
+        .pop()
+        .unwrap();
+    assert_eq!(tx.state(), State::Received);
+    tx.dispute();
+    assert_eq!(tx.state(), State::Received);
+    tx.execute();
+    assert_eq!(tx.state(), State::Executed);
+    tx.dispute();
+    assert_eq!(tx.state(), State::Disputed);
+    tx.resolve();
+    assert_eq!(tx.state(), State::Executed);
+    tx.dispute();
+    tx.revert();
+    assert_eq!(tx.state(), State::Reverted);
+    tx.resolve();
+    assert_eq!(tx.state(), State::Reverted);
+    tx.reinstate();
+    assert_eq!(tx.state(), State::Executed);
+}
+
+#[test]
+fn transaction_state_is_serialized() {
+    let mut tx = read_txs("type, client, tx, amount\ndeposit, 1, 1, 1.5")
+        .pop()
+        .unwrap();
+    tx.execute();
+    tx.dispute();
+    let json = serde_json::to_string(&tx).unwrap();
+    assert_eq!(
+        json,
+        r#"{"tx":1,"type":"deposit","client":1,"amount":"1.5","state":"disputed"}"#
+    );
+
+    let mut tx = serde_json::from_str::<Transaction>(&json).unwrap();
+    assert_eq!(tx.state(), State::Disputed);
+    assert_eq!(tx.amount, Some(units(15_000)));
+    tx.resolve();
+    assert_eq!(tx.state(), State::Executed);
+}
+
+#[test]
+fn dispute_of_withdrawn_funds_is_declined() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 100
+withdrawal, 1, 2, 100
+";
+    env.process(data);
+
+    let dispute = read_txs("type, client, tx, amount\ndispute, 1, 1,")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(dispute), Err(EngineError::InsufficientFunds));
+    assert_eq!(
+        env.acc(1),
+        Account {
+            last_seq: 2,
+            ..Account::new(1)
+        }
+    );
+    // the deposit is not disputed, so there is nothing to resolve
+    let resolve = read_txs("type, client, tx, amount\nresolve, 1, 1,")
+        .pop()
+        .unwrap();
+    assert_eq!(
+        env.process_tx(resolve),
+        Err(EngineError::NotDisputed(State::Executed))
+    );
+}
+
+#[test]
+fn events_of_undisputed_transactions_are_declined() {
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 1, 2, 5\ndispute, 1, 2,");
+    assert_eq!(env.acc(1).held, 5 * SCALE);
+
+    // the deposit never disputed has nothing held to release or to take
+    for event in ["resolve", "chargeback"] {
+        let tx = read_txs(&format!("type, client, tx, amount\n{}, 1, 1,", event))
+            .pop()
+            .unwrap();
+        assert_eq!(
+            env.process_tx(tx),
+            Err(EngineError::NotDisputed(State::Executed))
+        );
+        let acc = env.acc(1);
+        assert_eq!(
+            (acc.total, acc.held, acc.locked),
+            (15 * SCALE, 5 * SCALE, false)
+        );
+    }
+    // the disputed one is resolved as usual
+    env.process("type, client, tx, amount\nresolve, 1, 2,");
+    assert_eq!(env.acc(1).held, 0);
+}
+
+#[test]
+fn partial_disputes_work() {
+    let mut env = Env::with_engine(Engine::new().with_partial_disputes(true));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 1, 10.0001
+dispute, 1, 1, 4
+";
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!((acc.available(), acc.held), (11 * SCALE, 4 * SCALE));
+
+    // the held part is what's charged back, the rest stays available
+    let chargeback = read_txs("type, client, tx, amount\nchargeback, 1, 1,")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(chargeback), Ok(()));
+    let acc = env.acc(1);
+    assert_eq!((acc.available(), acc.held), (11 * SCALE, units(0)));
+    assert!(acc.locked);
+
+    // resolve releases the part held, whatever amount it carries
+    let mut env = Env::with_engine(Engine::new().with_partial_disputes(true));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+dispute, 1, 1, 2.5
+resolve, 1, 1, 1
+dispute, 1, 1,
+";
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!((acc.available(), acc.held), (units(0), 10 * SCALE));
+
+    let e = EngineError::DisputeExceedsAmount {
+        amount: units(100_001),
+        original: units(100_000),
+    };
+    assert_eq!(
+        e.to_string(),
+        "disputed amount 10.0001 exceeds transaction amount 10"
+    );
+    assert_eq!(
+        describe(e.code()),
+        "disputed amount exceeds transaction amount"
+    );
+}
+
+#[test]
+fn event_amounts_are_advisory() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 1, 3.5
+";
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!((acc.available(), acc.held), (5 * SCALE, 10 * SCALE));
+
+    // nor do amounts of the events following it count
+    env.process("type, client, tx, amount\nchargeback, 1, 1, 1000");
+    let acc = env.acc(1);
+    assert_eq!((acc.total, acc.held), (5 * SCALE, units(0)));
+
+    // they're dropped as the events are read, 0 being no amount
+    let txs = read_txs("type, client, tx, amount\ndispute, 1, 1, 0\nresolve, 1, 1, 7");
+    assert!(txs.iter().all(|tx| tx.amount.is_none()));
+}
+
 #[test]
 fn ignores_faulty_records() {
     let mut env = Env::new();
@@ -166,71 +558,3820 @@ dispute, a, b, c, d, f
     assert_eq!(acc.total, 100_000_000);
 }
 
-#[cfg(test)]
-mod test_utils {
-    use super::*;
-    use csv::ReaderBuilder;
+#[test]
+fn held_breakdown_works() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 2,
+";
+    env.process(data);
+    env.engine
+        .hold_funds(1, units(30_000), HoldReason::Manual)
+        .expect("manual hold should succeed");
+    let acc = env.acc(1);
+    assert_eq!(
+        (acc.held, acc.held_dispute(), acc.held_manual),
+        (units(80_000), units(50_000), units(30_000))
+    );
+    assert_eq!(acc.available(), 70_000);
 
-    #[derive(Debug)]
-    pub struct Env {
-        engine: Engine,
-    }
+    // breakdown columns sum up to held
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.serialize(AccountSer::<DECIMALS>::from(acc).with_held_breakdown(&acc))
+        .unwrap();
+    let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        out,
+        "\
+client,available,held,held_dispute,held_manual,held_fraud,total,locked
+1,7,8,5,3,0,15,false
+"
+    );
 
-    impl Env {
-        pub fn new() -> Self {
-            Env {
-                engine: Engine::new(),
-            }
-        }
+    // and they are not output unless asked
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.serialize(AccountSer::<DECIMALS>::from(acc)).unwrap();
+    let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+    assert_eq!(out, "client,available,held,total,locked\n1,7,8,15,false\n");
 
-        pub fn process(&mut self, data: &str) {
-            for t in read_txs(data) {
-                if let Err(e) = self.process_tx(t) {
-                    println!("ERR: {e}")
-                }
-            }
-        }
+    // no more is released than held for the reason
+    assert!(env
+        .engine
+        .release_funds(1, units(30_000), HoldReason::Fraud)
+        .is_err());
+    env.engine
+        .release_funds(1, units(30_000), HoldReason::Manual)
+        .expect("manual hold should be released");
+    let acc = env.acc(1);
+    assert_eq!(
+        (acc.held, acc.held_dispute(), acc.held_manual),
+        (units(50_000), units(50_000), units(0))
+    );
+}
 
-        pub fn process_tx(&mut self, tx: Transaction) -> Result<(), String> {
-            self.engine.process(tx)
-        }
+#[test]
+fn last_seq_works() {
+    let mut env = Env::with_engine(Engine::new().with_last_seq(true));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 1, 3, 50
+deposit, 3, 4, 1
+dispute, 2, 2,
+withdrawal, 3, 5, 1
+dispute, 1, 9,
+";
+    env.process(data);
+    // declined ones don't count
+    let seqs = |env: &Env| [1, 2, 3].map(|id| env.acc(id).last_seq);
+    assert_eq!(seqs(&env), [1, 4, 5]);
+    // transfers touch both of the accounts
+    let tx = Transaction::builder().transfer(1, 6, "1", 3).unwrap();
+    env.process_tx(tx).unwrap();
+    env.engine.adjust(7, 2, 10_000, "goodwill").unwrap();
+    assert_eq!(seqs(&env), [6, 7, 6]);
 
-        pub fn tx_count(&self) -> usize {
-            self.engine.transactions().len()
-        }
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    let csv = String::from_utf8(out).unwrap();
+    assert_eq!(
+        csv,
+        "\
+client,available,held,total,locked,last_seq
+1,9,0,9,false,6
+2,1,5,6,false,7
+3,1,0,1,false,6
+"
+    );
+    assert!(validate_output(csv.as_bytes()).is_ok());
 
-        pub fn acc(&self, id: u32) -> Account {
-            *self
-                .engine
-                .get_account(&id)
-                .expect("account should have been created")
-        }
+    // carried on from a snapshot
+    let mut env = Env::with_engine(Engine::restore(env.engine.snapshot()));
+    env.process("type, client, tx, amount\ndeposit, 3, 8, 1");
+    assert_eq!(seqs(&env), [6, 7, 8]);
+}
 
-        pub fn balances(&self) -> Vec<(u32, u64)> {
-            let mut balances = self
-                .engine
-                .accounts()
-                .map(|v| (v.id, v.total))
-                .collect::<Vec<_>>();
+#[test]
+fn output_validation_works() {
+    let data = "\
+client,available,held,total,locked
+1,1.5,0,1.5,false
+3,30000,10000,40000,false
+";
+    assert_eq!(validate_output(data.as_bytes()), Ok(2));
 
-            balances.sort_by(|a, b| a.0.cmp(&b.0));
-            balances
-        }
+    // tampered total
+    let data = "\
+client,available,held,total,locked
+1,1.5,0,1.5,false
+3,30000,10000,40001,false
+";
+    assert_eq!(
+        validate_output(data.as_bytes()),
+        Err(OutputError::Unbalanced {
+            line: 3,
+            client: Some(3)
+        })
+    );
+
+    // tampered amount
+    let data = "\
+client,available,held,total,locked
+1,1.5,0,1.5,false
+3,30000,-10000,40000,false
+";
+    assert!(matches!(
+        validate_output(data.as_bytes()),
+        Err(OutputError::Malformed { line: 3, .. })
+    ));
+
+    // held funds breakdown, if output, sums up to held
+    let data = "\
+client,available,held,held_dispute,held_manual,held_fraud,total,locked
+1,7,8,5,3,0,15,false
+";
+    assert_eq!(validate_output(data.as_bytes()), Ok(1));
+    let data = "\
+client,available,held,held_dispute,held_manual,held_fraud,total,locked
+1,7,8,5,2,0,15,false
+";
+    assert_eq!(
+        validate_output(data.as_bytes()),
+        Err(OutputError::BreakdownMismatch {
+            line: 2,
+            client: Some(1)
+        })
+    );
+
+    // missing column
+    let data = "\
+client,available,total,locked
+1,1.5,1.5,false
+";
+    assert!(matches!(
+        validate_output(data.as_bytes()),
+        Err(OutputError::UnexpectedColumns(_))
+    ));
+}
+
+#[test]
+fn directory_runs_in_lexical_order() {
+    // deposit, then its dispute, then chargeback, each in a file of its day,
+    // along with a note, which is not to be read
+    let mut engine = Engine::new();
+    let mut out = vec![];
+    let report = engine
+        .run(&OsString::from("./fixtures/daily"), &mut out)
+        .unwrap();
+    assert_eq!((report.processed, report.declined.len()), (5, 0));
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+client,available,held,total,locked
+1,5,0,5,true
+2,1,0,1,false
+"
+    );
+}
+
+#[test]
+fn merge_works() {
+    let mut left = Engine::new();
+    left.ingest("type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 2, 2, 5".as_bytes());
+    let mut right = Engine::new();
+    right.ingest("type, client, tx, amount\ndeposit, 3, 3, 7\ndeposit, 3, 4, 1".as_bytes());
+
+    left.merge(right).unwrap();
+    let total = |e: &Engine, id| e.get_account(&id).map(|a| a.total);
+    assert_eq!(
+        [1, 2, 3].map(|id| total(&left, id)),
+        [Some(10 * SCALE), Some(5 * SCALE), Some(8 * SCALE)]
+    );
+    assert_eq!(left.transactions().len(), 4);
+    // transactions merged are disputable as the ones processed here
+    left.ingest("type, client, tx, amount\ndispute, 3, 4,".as_bytes());
+    assert_eq!(left.get_account(&3).unwrap().held, SCALE);
+
+    // partitions which are not disjoint are not merged
+    let mut other = Engine::new();
+    other.ingest("type, client, tx, amount\ndeposit, 4, 5, 2\ndeposit, 2, 6, 3".as_bytes());
+    assert_eq!(left.merge(other), Err(EngineError::ClientConflict(2)));
+    assert_eq!(total(&left, 2), Some(5 * SCALE));
+    assert_eq!(total(&left, 4), None);
+    assert_eq!(left.transactions().len(), 4);
+}
+
+#[test]
+fn shards_run_concurrently() {
+    let paths = ["a", "b", "c"]
+        .iter()
+        .map(|s| OsString::from(format!("./fixtures/shards/{s}.csv")))
+        .collect::<Vec<_>>();
+
+    let mut sequential = Engine::new();
+    let mut expected = Summary::default();
+    for path in &paths {
+        expected.extend(sequential.ingest(File::open(path).unwrap()));
     }
+    let mut sharded = Engine::new();
+    let summary = sharded.run_shards(&paths, 2).unwrap();
+    assert_eq!(
+        (summary.processed, summary.declined),
+        (expected.processed, expected.declined)
+    );
 
-    pub fn read_txs(csv: &str) -> Vec<Transaction> {
-        let mut rdr = ReaderBuilder::new()
-            .trim(Trim::All)
-            .flexible(true)
-            .from_reader(csv.as_bytes());
-        let mut txs = vec![];
-        for entry in rdr.deserialize().flatten() {
-            let mut tx: Transaction = entry;
-            let s = Box::new(Received);
-            if tx.init(s).is_ok() {
-                txs.push(tx)
-            }
-        }
-        txs
+    // positions of the last transactions applied are within the shard
+    let sorted = |e: &Engine| {
+        let mut accs = e
+            .accounts()
+            .map(|a| Account { last_seq: 0, ..*a })
+            .collect::<Vec<_>>();
+        accs.sort_by_key(|a| a.id);
+        accs
+    };
+    assert_eq!(sorted(&sharded).len(), 5);
+    assert_eq!(sorted(&sharded), sorted(&sequential));
+    assert_eq!(
+        sharded.transactions().len(),
+        sequential.transactions().len()
+    );
+
+    // shards with overlapping clients are refused
+    let paths = [paths[0].clone(), paths[0].clone()];
+    assert!(Engine::new().run_shards(&paths, 2).is_err());
+}
+
+#[test]
+fn parallel_run_works() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 20
+deposit, 3, 3, 30.5
+deposit, 4, 4, 4
+withdrawal, 2, 5, 5
+dispute, 1, 1,
+deposit, 5, 6, 1
+dispute, 3, 3,
+chargeback, 3, 3,
+resolve, 1, 1,
+withdrawal, 4, 7, 40
+dispute, 5, 6,
+deposit, 6, 8, 6
+";
+    let mut serial = vec![];
+    let expected = Engine::new()
+        .run_reader(data.as_bytes(), &mut serial)
+        .unwrap();
+    for threads in [1, 3, 8] {
+        let mut parallel = vec![];
+        let report = Engine::new()
+            .run_parallel(data.as_bytes(), &mut parallel, threads)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(parallel).unwrap(),
+            String::from_utf8(serial.clone()).unwrap()
+        );
+        assert_eq!(report.processed, expected.processed);
+        assert_eq!(report.declined, expected.declined);
+    }
+
+    // transfers are declined, as clients may be in different shards
+    let data = "\
+type, client, tx, amount, target
+deposit, 1, 1, 10,
+transfer, 1, 2, 5, 2
+";
+    let mut out = vec![];
+    let report = Engine::new()
+        .with_rejects(true)
+        .run_parallel(data.as_bytes(), &mut out, 2)
+        .unwrap();
+    assert_eq!(report.declined, vec![(2, EngineError::CrossShard)]);
+    assert_eq!(report.rejects.len(), 1);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+client,available,held,total,locked
+1,10,0,10,false
+"
+    );
+}
+
+#[test]
+fn parallel_run_keeps_engine_state() {
+    // opening balances and stored transactions, disputed in the input
+    let preload = |engine: &mut Engine| {
+        engine.load_accounts([Account {
+            total: units(50_000),
+            ..Account::new(2)
+        }]);
+        engine
+            .run_reader(
+                "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10,
+deposit, 3, 2, 30,
+deposit, 3, 3, 3, EUR
+"
+                .as_bytes(),
+                vec![],
+            )
+            .unwrap();
+    };
+    let data = "\
+type, client, tx, amount, currency
+dispute, 1, 1,,
+deposit, 2, 4, 2,
+withdrawal, 3, 5, 1, EUR
+deposit, 4, 6, 4,
+chargeback, 1, 1,,
+";
+    let mut serial_engine = Engine::new();
+    preload(&mut serial_engine);
+    let mut serial = vec![];
+    serial_engine
+        .run_reader(data.as_bytes(), &mut serial)
+        .unwrap();
+    for threads in [1, 2, 3] {
+        let mut engine = Engine::new();
+        preload(&mut engine);
+        let mut parallel = vec![];
+        let report = engine
+            .run_parallel(data.as_bytes(), &mut parallel, threads)
+            .unwrap();
+        assert!(report.declined.is_empty());
+        assert_eq!(
+            String::from_utf8(parallel).unwrap(),
+            String::from_utf8(serial.clone()).unwrap()
+        );
+        assert_eq!(engine.transactions().len(), 6);
+    }
+}
+
+#[test]
+fn held_overflow_works() {
+    let accounts = [
+        Account {
+            total: units(50_000),
+            ..Account::new(1)
+        },
+        // inconsistent: more funds held than the total
+        Account {
+            total: units(30_000),
+            held: units(50_000),
+            ..Account::new(2)
+        },
+    ];
+    let output = |engine: Engine, format| {
+        let mut engine = engine.with_format(format);
+        engine.load_accounts(accounts);
+        let mut out = vec![];
+        engine.write_accounts(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    // output as is by default, not passing validation
+    let csv = output(Engine::new(), OutputFormat::Csv);
+    assert_eq!(
+        csv,
+        "\
+client,available,held,total,locked
+1,5,0,5,false
+2,0,5,3,false
+"
+    );
+    assert!(matches!(
+        validate_output(csv.as_bytes()),
+        Err(OutputError::Unbalanced {
+            client: Some(2),
+            ..
+        })
+    ));
+
+    let engine = Engine::new().with_held_overflow(HeldOverflow::Warn);
+    assert_eq!(
+        output(
+            Engine::new().with_held_overflow(HeldOverflow::Warn),
+            OutputFormat::Csv
+        ),
+        "\
+client,available,held,total,locked
+1,5,0,5,false
+# client 2: held exceeds total (available + held != total)
+2,0,5,3,false
+"
+    );
+    assert_eq!(
+        output(engine, OutputFormat::Sql),
+        "\
+INSERT INTO accounts (client, available, held, total, locked) VALUES (1, '5', '0', '5', FALSE);
+-- client 2: held exceeds total (available + held != total)
+INSERT INTO accounts (client, available, held, total, locked) VALUES (2, '0', '5', '3', FALSE);
+"
+    );
+
+    let engine = Engine::new().with_held_overflow(HeldOverflow::Skip);
+    assert_eq!(
+        output(engine, OutputFormat::Csv),
+        "\
+client,available,held,total,locked
+1,5,0,5,false
+"
+    );
+
+    let engine = Engine::new()
+        .with_held_overflow(HeldOverflow::Clamp)
+        .with_held_breakdown(true);
+    let csv = output(engine, OutputFormat::Csv);
+    assert_eq!(
+        csv,
+        "\
+client,available,held,held_dispute,held_manual,held_fraud,total,locked
+1,5,0,0,0,0,5,false
+2,0,3,3,0,0,3,false
+"
+    );
+    assert_eq!(validate_output(csv.as_bytes()), Ok(2));
+
+    // logged in formats having no comments, tagged with the run ID
+    init_log_capture();
+    let engine = Engine::new()
+        .with_held_overflow(HeldOverflow::Warn)
+        .with_run_id("overflow-warn");
+    output(engine, OutputFormat::Ndjson);
+    let engine = Engine::new()
+        .with_held_overflow(HeldOverflow::Skip)
+        .with_run_id("overflow-skip");
+    output(engine, OutputFormat::Csv);
+    assert_eq!(
+        captured_logs("[overflow-"),
+        vec![
+            "[overflow-warn] client 2: held exceeds total (available + held != total)",
+            "[overflow-skip] account of client 2 left out of the output: held exceeds total"
+        ]
+    );
+}
+
+#[test]
+fn unstored_withdrawals_work() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 20
+withdrawal, 1, 3, 4
+withdrawal, 2, 4, 25
+dispute, 1, 1,
+withdrawal, 2, 5, 5
+resolve, 1, 1,
+deposit, 3, 6, 3
+dispute, 3, 6,
+chargeback, 3, 6,
+withdrawal, 1, 7, 1
+";
+    let mut stored = Engine::new();
+    let expected = stored.ingest(data.as_bytes());
+    let mut unstored = Engine::new().with_store_withdrawals(false);
+    let summary = unstored.ingest(data.as_bytes());
+    assert_eq!(
+        (summary.processed, summary.declined),
+        (expected.processed, expected.declined)
+    );
+    let sorted = |e: &Engine| {
+        let mut accs = e.accounts().copied().collect::<Vec<_>>();
+        accs.sort_by_key(|a| a.id);
+        accs
+    };
+    assert_eq!(sorted(&unstored), sorted(&stored));
+    // deposits only
+    assert_eq!(stored.transactions().len(), 6);
+    assert_eq!(unstored.transactions().len(), 3);
+
+    // withdrawals can't be disputed then
+    let data = "\
+type, client, tx, amount
+dispute, 1, 3,
+";
+    let summary = unstored.ingest(data.as_bytes());
+    assert_eq!(summary.errors, vec![(3, EngineError::TxNotFound)]);
+    assert_eq!(stored.ingest(data.as_bytes()).processed, 1);
+}
+
+#[test]
+fn presized_engine_works() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 20
+withdrawal, 1, 3, 4
+dispute, 2, 2,
+";
+    let mut engine = Engine::with_capacity(2, 3);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!((summary.processed, summary.declined), (4, 0));
+    assert_eq!(engine.get_account(&1).unwrap().total, 60_000);
+    assert_eq!(engine.get_account(&2).unwrap().held, 200_000);
+    assert_eq!(engine.transactions().len(), 3);
+}
+
+#[test]
+fn process_many_works() {
+    let mut transfer = Transaction::new(Tx::Transfer, 1, 4, Some(units(20_000)));
+    transfer.target = Some(2);
+    let txs = vec![
+        Transaction::new(Tx::Deposit, 1, 1, Some(units(100_000))),
+        Transaction::new(Tx::Deposit, 2, 2, Some(units(50_000))),
+        Transaction::new(Tx::Withdrawal, 1, 3, Some(units(30_000))),
+        transfer,
+        Transaction::new(Tx::Dispute, 2, 2, None),
+        // declined: no amount, and an unknown transaction
+        Transaction::new(Tx::Deposit, 3, 5, None),
+        Transaction::new(Tx::Resolve, 1, 9, None),
+    ];
+    let mut engine = Engine::new();
+    let report = engine.process_many(txs);
+    assert_eq!(report.processed, 5);
+    assert_eq!(
+        report.declined,
+        vec![(9, EngineError::TxNotFound), (5, EngineError::EmptyAmount)]
+    );
+    let acc = engine.get_account(&1).unwrap();
+    assert_eq!((acc.total, acc.held), (units(50_000), units(0)));
+    let acc = engine.get_account(&2).unwrap();
+    assert_eq!((acc.total, acc.held), (units(70_000), units(50_000)));
+    assert!(engine.get_account(&3).is_none());
+
+    // the same as of reading them
+    let data = "\
+type, client, tx, amount, target
+deposit, 1, 1, 10,
+deposit, 2, 2, 5,
+withdrawal, 1, 3, 3,
+transfer, 1, 4, 2, 2
+dispute, 2, 2,
+";
+    let mut read = Engine::new();
+    read.ingest(data.as_bytes());
+    assert_eq!(read.get_account(&1), engine.get_account(&1));
+    assert_eq!(read.get_account(&2), engine.get_account(&2));
+}
+
+#[test]
+fn transaction_builder_works() {
+    let b = Transaction::builder();
+    let mut engine = Engine::new();
+    engine.process(b.deposit(1, 1, "10.5").unwrap()).unwrap();
+    engine.process(b.withdrawal(1, 2, "0.25").unwrap()).unwrap();
+    engine.process(b.deposit(2, 3, "1").unwrap()).unwrap();
+    engine
+        .process(b.transfer(1, 4, "0.25", 2).unwrap())
+        .unwrap();
+    engine.process(b.dispute(2, 3)).unwrap();
+    engine.process(b.chargeback(2, 3)).unwrap();
+    assert_eq!(engine.get_account(&1).unwrap().total, 100_000);
+    let acc = engine.get_account(&2).unwrap();
+    assert_eq!((acc.total, acc.locked), (units(2_500), true));
+    assert_eq!(
+        engine.process(b.withdrawal(1, 5, "11").unwrap()),
+        Err(EngineError::InsufficientFunds)
+    );
+
+    // transactions of no valid amount are refused, as read ones are
+    for amount in ["", "0", "abc", "-1"] {
+        assert_eq!(
+            b.deposit(1, 6, amount).err(),
+            Some(EngineError::EmptyAmount)
+        );
+    }
+    // amounts are parsed at the scale given
+    let tx = TransactionBuilder::<2>.deposit(1, 1, "1.239").unwrap();
+    #[cfg(not(feature = "decimal"))]
+    assert_eq!(tx.amount, Some(123));
+    // keeping the digits beyond it with `decimal` feature
+    #[cfg(feature = "decimal")]
+    assert_eq!(fmt_scaled_amount(tx.amount.unwrap(), 2), "1.239");
+    assert_eq!(tx.state(), State::Received);
+}
+
+#[test]
+fn column_map_works() {
+    let data = "\
+txn_type, account, id, value, note
+deposit, 1, 1, 10, first
+withdrawal, 1, 2, 4,
+dispute, 1, 2, ,
+deposit, x, 3, 1,
+deposit, 2, 4, 3, second
+";
+    let columns = ColumnMap::new("txn_type", "account", "id", "value");
+    let mut engine = Engine::new().with_column_map(columns.clone());
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!((summary.processed, summary.parse_errors), (4, 1));
+    assert_eq!(summary.malformed, vec![5]);
+    let balances = |e: &Engine| [1, 2].map(|id| e.get_account(&id).map(|a| a.total));
+    assert_eq!(balances(&engine), [Some(10 * SCALE), Some(3 * SCALE)]);
+
+    // so are they in parallel processing
+    let mut engine = Engine::new().with_column_map(columns);
+    engine.run_parallel(data.as_bytes(), vec![], 2).unwrap();
+    assert_eq!(balances(&engine), [Some(10 * SCALE), Some(3 * SCALE)]);
+
+    // while the standard columns are missing
+    let mut engine = Engine::new();
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.processed, 0);
+    assert_eq!(balances(&engine), [None, None]);
+}
+
+#[test]
+fn ndjson_input_works() {
+    let data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}
+{"type": "deposit", "client": 1, "tx": 2, "amount":
+
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": 2.0001}
+"#;
+    let mut engine = Engine::new();
+    let mut out = vec![];
+    let report = engine.run_ndjson(data.as_bytes(), &mut out).unwrap();
+    assert_eq!((report.processed, report.parse_errors), (2, 1));
+    assert_eq!(report.malformed, vec![2]);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n1,8.4999,0,8.4999,false\n"
+    );
+
+    // declined ones are reported with their lines, as CSV ones are
+    let data = r#"{"type": "withdrawal", "client": 1, "tx": 4, "amount": 100}
+{"type": "deposit", "client": 1, "tx": 5}
+{"type": "dispute", "client": 1, "tx": 3}
+"#;
+    let summary = engine.ingest_ndjson(data.as_bytes());
+    assert_eq!(
+        summary.errors,
+        vec![
+            (4, EngineError::InsufficientFunds),
+            (5, EngineError::EmptyAmount)
+        ]
+    );
+    assert_eq!(summary.lines, vec![Some(1), Some(2)]);
+    assert_eq!(engine.get_account(&1).unwrap().total, 105_000);
+}
+
+#[test]
+fn validation_works() {
+    let engine = Engine::new();
+    let input = File::open("./fixtures/in/dispute_and_resolve.csv").unwrap();
+    let report = engine.validate_reader(input);
+    assert!(report.processed > 0);
+    assert_eq!(engine.accounts().count(), 0);
+    assert_eq!(engine.transactions().len(), 0);
+
+    // outcomes depend on the current state, which stays as it is
+    let mut engine = Engine::new().with_max_tx_amount(50 * SCALE);
+    engine.ingest("type, client, tx, amount\ndeposit, 1, 1, 10\n".as_bytes());
+    let before = engine.snapshot();
+    let data = "\
+type, client, tx, amount
+withdrawal, 1, 2, 5
+withdrawal, 1, 3, 6
+deposit, 1, 1, 1
+deposit, 2, 4, 60
+deposit, 2, 5, 3
+";
+    let report = engine.validate_reader(data.as_bytes());
+    assert_eq!(report.processed, 2);
+    assert_eq!(
+        report.declined,
+        vec![
+            (3, EngineError::InsufficientFunds),
+            (1, EngineError::DuplicateTx),
+            (
+                4,
+                EngineError::AmountTooLarge {
+                    amount: 60 * SCALE,
+                    limit: 50 * SCALE
+                }
+            ),
+        ]
+    );
+    assert_eq!(engine.snapshot(), before);
+    // and the same is done if applied
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.errors, report.declined);
+}
+
+#[test]
+fn per_tx_amount_limit_works() {
+    let mut env = Env::with_engine(Engine::new().with_max_tx_amount(1_000_000 * SCALE));
+    let data = "\
+type, client, tx, amount
+# below and at the limit: ok
+deposit, 1, 1, 999999.9999
+deposit, 1, 2, 1000000
+# above the limit: declined
+deposit, 1, 3, 1000000.0001
+deposit, 2, 4, 5000000
+";
+    env.process(data);
+    assert_eq!(env.acc(1).total, 19_999_999_999);
+    assert!(env.engine.get_account(&2).is_none());
+
+    let data = "\
+type, client, tx, amount
+# below and at the limit: ok
+withdrawal, 1, 5, 999999.9999
+withdrawal, 1, 6, 1000000
+";
+    env.process(data);
+    assert_eq!(env.acc(1).total, 0);
+
+    let data = "\
+type, client, tx, amount
+withdrawal, 1, 7, 1000000.0001
+";
+    let tx = read_txs(data).pop().unwrap();
+    assert_eq!(
+        env.process_tx(tx),
+        Err(EngineError::AmountTooLarge {
+            amount: units(10000000001),
+            limit: units(10000000000)
+        })
+    );
+    assert_eq!(env.tx_count(), 4);
+
+    // shards are limited the same way
+    let paths = [OsString::from("./fixtures/shards/a.csv")];
+    let mut unlimited = Engine::new();
+    unlimited.run_shards(&paths, 1).unwrap();
+    let mut limited = Engine::new().with_max_tx_amount(units(1));
+    let summary = limited.run_shards(&paths, 1).unwrap();
+    assert!(unlimited.transactions().len() > 0);
+    assert_eq!(limited.transactions().len(), 0);
+    assert_eq!((summary.processed, summary.declined), (0, 4));
+}
+
+#[cfg(feature = "trace")]
+#[tracing_test::traced_test]
+#[test]
+fn processing_is_traced() {
+    let mut env = Env::with_engine(Engine::new().with_run_id("traced"));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 20
+dispute, 1, 1,
+";
+    env.process(data);
+
+    // records of each transaction are in its span, carrying its IDs and type,
+    // a declined one being a warning
+    assert!(logs_contain(
+        "DEBUG processing_is_traced:tx{tx=1 client=1 ty=Some(Deposit)}: \
+         toy_payments_engine::engine: [traced] tx 1 of client 1 processed"
+    ));
+    assert!(logs_contain(
+        "WARN processing_is_traced:tx{tx=2 client=1 ty=Some(Withdrawal)}: \
+         toy_payments_engine::engine: [traced] tx 2 of client 1 declined: \
+         insufficient total balance"
+    ));
+    // and an event carries the transaction it targets
+    assert!(logs_contain(
+        "DEBUG processing_is_traced:tx{tx=1 client=1 ty=Some(Dispute) \
+         original_ty=Some(Deposit) original_amount=\"10\"}: \
+         toy_payments_engine::engine: [traced] tx 1 of client 1 processed"
+    ));
+}
+
+#[test]
+fn run_id_tags_log_records() {
+    init_log_capture();
+    let mut env = Env::with_engine(Engine::new().with_run_id("run-7f3a"));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 20
+";
+    env.process(data);
+
+    let logs = captured_logs("[run-7f3a] ");
+    assert_eq!(
+        logs,
+        vec![
+            "[run-7f3a] tx 1 of client 1 processed",
+            "[run-7f3a] tx 2 of client 1 declined: insufficient total balance"
+        ]
+    );
+}
+
+#[test]
+fn run_id_tags_error_records() {
+    use crate::engine::write_errors;
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 20
+deposit, 1, x, 1
+";
+    let errors = |engine: Engine| {
+        let mut out = vec![];
+        write_errors(&engine.validate_reader(data.as_bytes()), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(
+        errors(Engine::new().with_run_id("run-9c1e")),
+        "\
+run_id,line,tx,code,error
+run-9c1e,3,2,2,insufficient total balance
+run-9c1e,4,,,malformed row
+"
+    );
+    // no such column for runs of no ID
+    assert_eq!(
+        errors(Engine::new()),
+        "\
+line,tx,code,error
+3,2,2,insufficient total balance
+4,,,malformed row
+"
+    );
+}
+
+#[test]
+fn deltas_since_snapshot_work() {
+    let snapshot = "\
+client,available,held,total,locked
+1,100,0,100,false
+2,50,0,50,false
+3,10,5,15,false
+";
+    let prior = read_accounts(snapshot.as_bytes()).unwrap();
+    let mut env = Env::new();
+    env.engine.load_accounts(prior.iter().copied());
+    // no change of the balances reported
+    env.engine.set_overdraft_limit(3, 5 * SCALE).unwrap();
+
+    let data = "\
+type, client, tx, amount
+withdrawal, 1, 1, 40
+deposit, 4, 2, 7.5
+# declined: no change
+withdrawal, 2, 3, 60
+";
+    env.process(data);
+
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for delta in env.engine.deltas(&prior) {
+        wtr.serialize(delta).unwrap();
+    }
+    let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        out,
+        "\
+client,old_available,new_available,old_held,new_held,old_total,new_total,old_locked,new_locked
+1,100,60,0,0,100,60,false,false
+4,0,7.5,0,0,0,7.5,false,false
+"
+    );
+}
+
+#[test]
+fn deltas_cover_every_currency() {
+    let prior =
+        read_accounts("client,available,held,total,locked\n1,10,0,10,false\n".as_bytes()).unwrap();
+    let mut env = Env::new();
+    env.engine.load_accounts(prior.iter().copied());
+    let data = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 5,
+deposit, 1, 2, 3, EUR
+";
+    env.process(data);
+
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for delta in env.engine.deltas(&prior) {
+        wtr.serialize(delta).unwrap();
+    }
+    assert_eq!(
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+        "\
+client,currency,old_available,new_available,old_held,new_held,old_total,new_total,old_locked,new_locked
+1,,10,15,0,0,10,15,false,false
+1,EUR,0,3,0,0,0,3,false,false
+"
+    );
+}
+
+#[test]
+fn accounts_of_every_currency_are_accessible() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 5,
+deposit, 1, 2, 3, EUR
+deposit, 2, 3, 4, EUR
+";
+    env.process(data);
+    let engine = &mut env.engine;
+    assert_eq!(engine.get_account_in(&1, "").unwrap().total, units(50_000));
+    assert_eq!(
+        engine.get_account_in(&1, "EUR").unwrap().total,
+        units(30_000)
+    );
+    assert!(engine.get_account_in(&1, "USD").is_none());
+
+    engine
+        .hold_funds_in(2, "EUR", units(10_000), HoldReason::Manual)
+        .unwrap();
+    assert_eq!(
+        engine.hold_funds_in(2, "", units(10_000), HoldReason::Manual),
+        Err(EngineError::AccountNotFound)
+    );
+    engine
+        .set_overdraft_limit_in(1, "EUR", units(20_000))
+        .unwrap();
+    assert_eq!(
+        engine.get_account_in(&1, "EUR").unwrap().overdraft_limit,
+        units(20_000)
+    );
+    assert_eq!(
+        engine.get_account_in(&1, "").unwrap().overdraft_limit,
+        units(0)
+    );
+
+    let balance = |b: (&str, &str, &str)| Some((b.0.to_owned(), b.1.to_owned(), b.2.to_owned()));
+    assert_eq!(engine.balance_of_in(&2, "EUR"), balance(("3", "1", "4")));
+    assert_eq!(engine.balance_of_in(&2, ""), None);
+    let ids = |accounts: Vec<&Account>| accounts.iter().map(|a| a.id).collect::<Vec<_>>();
+    assert_eq!(ids(engine.accounts_sorted_in("EUR").collect()), vec![1, 2]);
+    assert_eq!(ids(engine.accounts_held_in("EUR")), vec![2]);
+    assert!(engine.accounts_held_in("").is_empty());
+    assert!(engine.accounts_sorted_in("USD").next().is_none());
+
+    let mut out = vec![];
+    engine.emit_account_in(2, "EUR", &mut out, true).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,currency,available,held,total,locked\n2,EUR,3,1,4,false\n"
+    );
+    assert!(engine.emit_account_in(2, "", vec![], true).is_err());
+
+    engine
+        .release_funds_in(2, "EUR", units(10_000), HoldReason::Manual)
+        .unwrap();
+    assert_eq!(engine.get_account_in(&2, "EUR").unwrap().held, units(0));
+}
+
+#[test]
+fn deltas_of_overdrawn_accounts_work() {
+    let prior =
+        read_accounts("client,available,held,total,locked\n1,-5,0,-5,false\n".as_bytes()).unwrap();
+    let mut env = Env::new();
+    env.engine.load_accounts(prior.iter().copied());
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 3");
+
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for delta in env.engine.deltas(&prior) {
+        wtr.serialize(delta).unwrap();
+    }
+    assert_eq!(
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+        "\
+client,old_available,new_available,old_held,new_held,old_total,new_total,old_locked,new_locked
+1,-5,-2,0,0,-5,-2,false,false
+"
+    );
+}
+
+#[test]
+fn engines_get_diffed() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 100
+deposit, 2, 2, 50
+";
+    let mut before = Env::new();
+    before.process(data);
+    let mut after = Env::with_engine(Engine::restore(before.engine.snapshot()));
+    after.process("type, client, tx, amount\nwithdrawal, 1, 3, 40");
+
+    assert_eq!(
+        before.engine.diff(&after.engine),
+        vec![AccountDiff {
+            client: 1,
+            currency: None,
+            before_total: signed(100 * SCALE),
+            after_total: signed(60 * SCALE),
+            before_held: units(0),
+            after_held: units(0),
+            before_locked: false,
+            after_locked: false,
+        }]
+    );
+    assert!(after.engine.diff(&after.engine).is_empty());
+
+    // a client missing on either side is compared to a blank account
+    let diff = Engine::new().diff(&before.engine);
+    assert_eq!(diff.iter().map(|d| d.client).collect::<Vec<_>>(), [1, 2]);
+    assert_eq!(
+        (diff[1].before_total, diff[1].after_total),
+        (signed_units(0), signed(50 * SCALE))
+    );
+    let diff = before.engine.diff(&Engine::new());
+    assert_eq!(
+        (diff[1].before_total, diff[1].after_total),
+        (signed(50 * SCALE), signed_units(0))
+    );
+
+    // bookkeeping, e.g. the last transaction applied or the overdraft limit, is not a difference
+    let mut other = Env::with_engine(Engine::restore(before.engine.snapshot()));
+    other.engine.set_overdraft_limit(2, 5 * SCALE).unwrap();
+    other.engine.load_accounts([Account {
+        last_seq: 10,
+        ..before.acc(1)
+    }]);
+    assert!(before.engine.diff(&other.engine).is_empty());
+    // while drawing on the overdraft is
+    other.process("type, client, tx, amount\nwithdrawal, 2, 3, 52");
+    let diff = before.engine.diff(&other.engine);
+    assert_eq!(
+        (diff[0].client, diff[0].after_total),
+        (2, -signed(2 * SCALE))
+    );
+}
+
+#[test]
+fn engines_get_diffed_in_every_currency() {
+    let mut before = Env::new();
+    before.process("type, client, tx, amount, currency\ndeposit, 1, 1, 10, EUR");
+    let mut after = Env::with_engine(Engine::restore(before.engine.snapshot()));
+    after.process("type, client, tx, amount, currency\nwithdrawal, 1, 2, 4, EUR");
+
+    let diff = before.engine.diff(&after.engine);
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].currency.as_deref(), Some("EUR"));
+    assert_eq!(
+        (diff[0].before_total, diff[0].after_total),
+        (signed(10 * SCALE), signed(6 * SCALE))
+    );
+    assert!(after.engine.diff(&after.engine).is_empty());
+}
+
+#[test]
+fn withdrawal_dispute_works() {
+    let deposit_and_withdraw = "\
+type, client, tx, amount
+deposit, 1, 1, 100
+withdrawal, 1, 2, 30
+dispute, 1, 2,
+";
+    // dispute credits withdrawn funds back, available at once
+    let mut env = Env::new();
+    env.process(deposit_and_withdraw);
+    let acc = env.acc(1);
+    assert_eq!(acc.total, 1_000_000);
+    assert_eq!(acc.held, 0);
+    assert_eq!(acc.available(), 1_000_000);
+
+    // resolve takes the credit back
+    let data = "\
+type, client, tx, amount
+resolve, 1, 2,
+";
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!(acc.total, 700_000);
+    assert_eq!(acc.held, 0);
+    assert_eq!(acc.available(), 700_000);
+    assert!(!acc.locked);
+
+    // chargeback makes the credit permanent
+    let mut env = Env::new();
+    env.process(deposit_and_withdraw);
+    let data = "\
+type, client, tx, amount
+chargeback, 1, 2,
+";
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!(acc.total, 1_000_000);
+    assert_eq!(acc.held, 0);
+    assert_eq!(acc.available(), 1_000_000);
+    assert!(acc.locked);
+
+    // the credit spent pending the resolution can't be taken back
+    let mut env = Env::new();
+    env.process(deposit_and_withdraw);
+    let data = "\
+type, client, tx, amount
+withdrawal, 1, 3, 100
+resolve, 1, 2,
+";
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!((acc.total, acc.available()), (units(0), units(0)));
+    assert!(!acc.locked);
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn latin1_input_works() {
+    use crate::encoding::transcoding_reader;
+
+    // "Café" memo in latin-1
+    let data: &[u8] = b"\
+type, client, tx, amount, memo
+deposit, 1, 1, 10, Caf\xe9
+";
+    // not a valid UTF-8, so the record gets dropped
+    let mut engine = Engine::new();
+    engine.ingest(data);
+    assert!(engine.get_account(&1).is_none());
+
+    let mut engine = Engine::new();
+    engine.ingest(transcoding_reader(data, "latin1").unwrap());
+    assert_eq!(engine.get_account(&1).unwrap().total, 100_000);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_input_works() {
+    use crate::engine::{decompress, Compression};
+    use flate2::read::MultiGzDecoder;
+    use std::io::{self, Read};
+
+    let run = |path: &str| {
+        let mut out = vec![];
+        Engine::new().run(&OsString::from(path), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let plain = run("./fixtures/in/basic.csv");
+    // compressed blocks, and a stored one, of a member carrying its file name
+    assert_eq!(run("./fixtures/gzip/basic.csv.gz"), plain);
+    assert_eq!(run("./fixtures/gzip/stored.csv.gz"), plain);
+
+    // back-references reaching far back, across blocks
+    let mut workload = String::from("type, client, tx, amount\n");
+    for i in 1..=5000 {
+        let ty = if i % 3 == 0 { "withdrawal" } else { "deposit" };
+        let line = format!(
+            "{}, {}, {}, {}.{:04}\n",
+            ty,
+            i % 97,
+            i,
+            i % 100,
+            i * 7 % 10000
+        );
+        workload.push_str(&line);
+    }
+    let gz = std::fs::read("./fixtures/gzip/workload.csv.gz").unwrap();
+    let mut text = String::new();
+    MultiGzDecoder::new(gz.as_slice())
+        .read_to_string(&mut text)
+        .unwrap();
+    assert_eq!(text, workload);
+
+    // members concatenated are read one after another
+    let gz = std::fs::read("./fixtures/gzip/basic.csv.gz").unwrap();
+    let mut text = String::new();
+    MultiGzDecoder::new([gz.as_slice(), &gz].concat().as_slice())
+        .read_to_string(&mut text)
+        .unwrap();
+    let basic = std::fs::read_to_string("./fixtures/in/basic.csv").unwrap();
+    assert_eq!(text, basic.repeat(2));
+
+    // damaged or cut short streams fail
+    let mut damaged = gz.clone();
+    *damaged.last_mut().unwrap() ^= 1;
+    let e = MultiGzDecoder::new(damaged.as_slice())
+        .read_to_end(&mut vec![])
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    let e = MultiGzDecoder::new(&gz[..gz.len() - 4])
+        .read_to_end(&mut vec![])
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+    // compression told explicitly, whatever the name
+    let file = File::open("./fixtures/gzip/basic.csv.gz").unwrap();
+    let mut out = vec![];
+    Engine::new()
+        .run_reader(decompress(file, Compression::Gzip).unwrap(), &mut out)
+        .unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), plain);
+
+    // shards are decompressed as well
+    let shards = |path: &str| {
+        let mut engine = Engine::new();
+        let summary = engine.run_shards(&[OsString::from(path)], 2).unwrap();
+        let mut out = vec![];
+        engine.write_accounts(&mut out).unwrap();
+        (summary.processed, String::from_utf8(out).unwrap())
+    };
+    assert_eq!(
+        shards("./fixtures/gzip/basic.csv.gz"),
+        shards("./fixtures/in/basic.csv")
+    );
+}
+
+#[test]
+fn deferred_disputes_work() {
+    let data = "\
+type, client, tx, amount
+dispute, 1, 1,
+dispute, 2, 2,
+resolve, 2, 2,
+deposit, 1, 1, 100
+deposit, 2, 3, 200
+# never comes
+dispute, 3, 4,
+";
+    // declined right away by default
+    let mut engine = Engine::new();
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.declined, 4);
+    assert_eq!(engine.get_account(&1).unwrap().held, 0);
+
+    let mut engine = Engine::new().with_deferred_disputes(true);
+    let summary = engine.ingest(data.as_bytes());
+    // applied after the final pass
+    assert_eq!(summary.deferred, 1);
+    assert_eq!(engine.get_account(&1).unwrap().held, 1_000_000);
+    // stay orphaned
+    let orphans = summary.orphans.iter().map(|t| t.id).collect::<Vec<_>>();
+    assert_eq!(orphans, vec![2, 2, 4]);
+    assert_eq!(engine.get_account(&2).unwrap().held, 0);
+}
+
+#[test]
+fn scientific_amounts_work() {
+    assert_eq!(parse_amount("1.5e3"), Some(units(15_000_000)));
+    assert_eq!(parse_amount("1.5E+3"), Some(units(15_000_000)));
+    assert_eq!(parse_amount("1e-2"), Some(units(100)));
+    assert_eq!(parse_amount(".25e1"), Some(units(25_000)));
+    assert_eq!(parse_amount("12345e-4"), Some(units(12_345)));
+    // digits beyond the precision are truncated, down to zero
+    #[cfg(not(feature = "decimal"))]
+    {
+        assert_eq!(parse_amount("1.23456e-1"), Some(1_234));
+        assert_eq!(parse_amount("9e-5"), Some(0));
+        assert_eq!(parse_amount("1e-100"), Some(0));
+        assert_eq!(parse_amount("1e-9223372036854775808"), Some(0));
+    }
+    // or kept with `decimal` feature, as far as `Decimal` keeps them
+    #[cfg(feature = "decimal")]
+    {
+        let parse = |s| parse_amount(s).map(fmt_amount);
+        assert_eq!(parse("1.23456e-1").as_deref(), Some("0.123456"));
+        assert_eq!(parse("9e-5").as_deref(), Some("0.00009"));
+        assert_eq!(parse("1e-100"), None);
+        assert_eq!(parse("1e-9223372036854775808"), None);
+    }
+    assert_eq!(parse_amount("0e100"), Some(units(0)));
+    // overflowing the ceiling
+    #[cfg(not(feature = "bigamount"))]
+    assert_eq!(parse_amount("1e30"), None);
+    assert_eq!(parse_amount("1e40"), None);
+    assert_eq!(parse_amount("1e100"), None);
+    assert_eq!(parse_amount("1e9223372036854775807"), None);
+    // malformed
+    for s in [
+        "e3", ".e3", "1e", "1e1.5", "1ee3", "-1e3", "1e3e3", "1.-5e3",
+    ] {
+        assert_eq!(parse_amount(s), None, "{}", s);
+    }
+
+    let mut env = Env::new();
+    env.process(
+        "\
+type, client, tx, amount
+deposit, 1, 1, 1.5e3
+withdrawal, 1, 2, 1e-2
+",
+    );
+    assert_eq!(env.acc(1).total, 14_999_900);
+}
+
+#[test]
+fn rounding_modes_work() {
+    use RoundingMode::*;
+    let round = |s, mode| parse_rounded_amount(s, 4, mode);
+    let rounded = |n| Some(units(n));
+    assert_eq!(round("1.99995", Truncate), rounded(19_999));
+    assert_eq!(round("1.99995", HalfUp), rounded(20_000));
+    assert_eq!(round("1.99995", HalfEven), rounded(20_000));
+    assert_eq!(round("1.23455", Truncate), rounded(12_345));
+    assert_eq!(round("1.23455", HalfUp), rounded(12_346));
+    assert_eq!(round("1.23455", HalfEven), rounded(12_346));
+    // ties go to the even digit, anything above the half goes up
+    assert_eq!(round("1.23445", HalfUp), rounded(12_345));
+    assert_eq!(round("1.23445", HalfEven), rounded(12_344));
+    assert_eq!(round("1.234450001", HalfEven), rounded(12_345));
+    assert_eq!(round("1.23444999", HalfUp), rounded(12_344));
+    // below the precision altogether
+    assert_eq!(round("0.00005", HalfUp), rounded(1));
+    assert_eq!(round("0.00005", HalfEven), rounded(0));
+    assert_eq!(round("5e-6", HalfUp), rounded(0));
+    // the carry may not overflow the ceiling
+    let max = format!("{}5", MAX_DECIMAL);
+    assert_eq!(round(&max, Truncate), Some(MAX_AMOUNT));
+    assert_eq!(round(&max, HalfUp), None);
+
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.99995
+deposit, 2, 2, 1.23455
+";
+    let mut engine = Engine::new().with_rounding(HalfUp);
+    engine.ingest(data.as_bytes());
+    assert_eq!(engine.get_account(&1).unwrap().total, 20_000);
+    assert_eq!(engine.get_account(&2).unwrap().total, 12_346);
+    // the default is truncating
+    let mut engine = Engine::new();
+    engine.ingest(data.as_bytes());
+    #[cfg(not(feature = "decimal"))]
+    {
+        assert_eq!(engine.get_account(&1).unwrap().total, 19_999);
+        assert_eq!(engine.get_account(&2).unwrap().total, 12_345);
+    }
+    // or keeping the digits with `decimal` feature
+    #[cfg(feature = "decimal")]
+    assert_eq!(
+        engine.balance_of(&2).map(|b| b.2),
+        Some("1.23455".to_owned())
+    );
+}
+
+#[test]
+fn max_amount_is_the_ceiling() {
+    assert_eq!(MAX_DECIMAL, fmt_amount(MAX_AMOUNT));
+    assert_eq!(parse_amount(MAX_DECIMAL), Some(MAX_AMOUNT));
+    let (int, frac) = MAX_DECIMAL.split_once('.').unwrap();
+    let frac = frac.parse::<u32>().unwrap() + 1;
+    assert_eq!(parse_amount(&format!("{}.{:04}", int, frac)), None);
+
+    let mut env = Env::new();
+    let data = format!(
+        "\
+type, client, tx, amount
+deposit, 1, 1, {MAX_DECIMAL}
+deposit, 1, 2, 0.0001
+"
+    );
+    env.process(&data);
+    assert_eq!(env.acc(1).total, MAX_AMOUNT);
+    assert_eq!(env.tx_count(), 1);
+}
+
+// amounts of `decimal` feature keep the digits beyond the precision
+#[cfg(not(feature = "decimal"))]
+#[test]
+fn amounts_of_other_scales_round_trip() {
+    // cents: digits beyond are truncated
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10.239
+withdrawal, 1, 2, 0.2
+deposit, 2, 3, 0.01
+";
+    let mut engine = Engine::<2>::default();
+    engine.ingest(data.as_bytes());
+    assert_eq!(engine.get_account(&1).unwrap().total, 1003);
+    let mut out = vec![];
+    engine.emit_account(1, &mut out, false).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "1,10.03,0,10.03,false\n");
+    let mut out = vec![];
+    engine.emit_account(2, &mut out, false).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "2,0.01,0,0.01,false\n");
+
+    // crypto: the ceiling is lower, as more of `u64` goes to the fraction
+    let max = fmt_scaled_amount(MAX_AMOUNT, 8);
+    #[cfg(not(feature = "bigamount"))]
+    assert_eq!(max, "184467440737.09551615");
+    assert_eq!(parse_scaled_amount(&max, 8), Some(MAX_AMOUNT));
+    let above = format!("{}", MAX_AMOUNT / scale(8) + 1);
+    assert_eq!(parse_scaled_amount(&above, 8), None);
+    let data = format!(
+        "\
+type, client, tx, amount
+deposit, 1, 1, 0.12345678
+deposit, 2, 2, {max}
+deposit, 2, 3, 0.00000001
+"
+    );
+    let mut engine = Engine::<8>::default();
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!((summary.processed, summary.declined), (2, 1));
+    let mut out = vec![];
+    engine.write_accounts(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let mut rows = out.lines().skip(1).collect::<Vec<_>>();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![
+            "1,0.12345678,0,0.12345678,false".to_owned(),
+            format!("2,{max},0,{max},false")
+        ]
+    );
+
+    // and read back in the same scale
+    let mut totals = csv::Reader::from_reader(out.as_bytes())
+        .deserialize::<AccountSer<8>>()
+        .map(|row| Account::from(row.unwrap()).total)
+        .collect::<Vec<_>>();
+    totals.sort();
+    assert_eq!(totals, vec![12_345_678, MAX_AMOUNT]);
+}
+
+#[test]
+fn output_grouping_by_lock_works() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 4, 1, 10
+deposit, 2, 2, 10
+deposit, 3, 3, 10
+deposit, 1, 4, 10
+deposit, 5, 5, 10
+dispute, 4, 1,
+chargeback, 4, 1,
+dispute, 1, 4,
+chargeback, 1, 4,
+";
+    env.process(data);
+
+    let ids = |g| {
+        env.engine
+            .accounts_grouped(g)
+            .iter()
+            .map(|a| (a.id, a.locked))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        ids(LockGrouping::UnlockedFirst),
+        vec![(2, false), (3, false), (5, false), (1, true), (4, true)]
+    );
+    assert_eq!(
+        ids(LockGrouping::LockedFirst),
+        vec![(1, true), (4, true), (2, false), (3, false), (5, false)]
+    );
+}
+
+#[test]
+fn reset_keeps_configuration() {
+    let mut env = Env::with_engine(Engine::new().with_max_tx_amount(100 * SCALE));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 100
+deposit, 1, 2, 100.0001
+";
+    env.process(data);
+    assert_eq!(env.acc(1).total, 1_000_000);
+
+    // state is cleared, but the limit is still there
+    env.engine.reset();
+    assert_eq!(env.tx_count(), 0);
+    assert!(env.engine.get_account(&1).is_none());
+    env.process(data);
+    assert_eq!(env.acc(1).total, 1_000_000);
+    assert_eq!(env.tx_count(), 1);
+
+    // both are cleared
+    env.engine.reset_all();
+    env.process(data);
+    assert_eq!(env.acc(1).total, 2_000_001);
+    assert_eq!(env.tx_count(), 2);
+}
+
+#[test]
+fn withdrawal_chargeback_freezes_unless_configured_otherwise() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 100
+withdrawal, 1, 2, 30
+dispute, 1, 2,
+chargeback, 1, 2,
+deposit, 1, 3, 5
+";
+    // frozen like a deposit chargeback, with the credit kept and the refund declined
+    let mut env = Env::new();
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!(
+        (acc.total, acc.held, acc.available()),
+        (100 * SCALE, units(0), 100 * SCALE)
+    );
+    assert!(acc.locked);
+    assert_eq!(env.engine.verify_freezes(), Ok(()));
+
+    // left open, the refund applies on top of the kept credit
+    let mut env = Env::with_engine(Engine::new().with_withdrawal_chargeback_freeze(false));
+    env.process(data);
+    let acc = env.acc(1);
+    assert_eq!(
+        (acc.total, acc.held, acc.available()),
+        (105 * SCALE, units(0), 105 * SCALE)
+    );
+    assert!(!acc.locked);
+    // still final for the transaction
+    let tx = read_txs("type, client, tx, amount\ndispute, 1, 2,")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::AlreadyChargedBack));
+
+    // deposit chargebacks freeze regardless
+    env.process("type, client, tx, amount\ndispute, 1, 3,\nchargeback, 1, 3,");
+    let acc = env.acc(1);
+    assert_eq!((acc.total, acc.held), (100 * SCALE, units(0)));
+    assert!(acc.locked);
+}
+
+#[test]
+fn withdrawal_tells_held_from_insufficient_funds() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 100
+deposit, 1, 2, 50
+dispute, 1, 1,
+";
+    env.process(data);
+
+    // total is enough, but most of it is held
+    let tx = read_txs("type, client, tx, amount\nwithdrawal, 1, 3, 120")
+        .pop()
+        .unwrap();
+    let e = env.process_tx(tx).unwrap_err();
+    assert_eq!(
+        e,
+        EngineError::FundsHeld {
+            available: 50 * SCALE,
+            held: 100 * SCALE
+        }
+    );
+    assert_eq!(
+        e.to_string(),
+        "insufficient available balance: 50 available, 100 held"
+    );
+
+    // total is not enough either
+    let tx = read_txs("type, client, tx, amount\nwithdrawal, 1, 4, 150.0001")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::InsufficientFunds));
+    assert_eq!(env.acc(1).total, 1_500_000);
+}
+
+#[test]
+fn reorder_tie_breaks_work() {
+    let data = "\
+type, client, tx, amount
+withdrawal, 1, 4, 12
+dispute, 1, 1,
+deposit, 1, 3, 10
+deposit, 1, 3, 7
+deposit, 1, 1, 5
+";
+    // reordered as 1 (deposit), 1 (dispute), 3 (of 10), 3 (of 7), 4
+    let mut engine = Engine::new().with_reorder(TieBreak::InputOrder);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.processed, 3);
+    assert_eq!(summary.declined, 2);
+    let acc = engine.get_account(&1).unwrap();
+    assert_eq!(acc.total, 150_000);
+    assert_eq!(acc.held, 50_000);
+
+    let mut engine = Engine::new().with_reorder(TieBreak::Reject);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.rejected_on, Some(3));
+    assert_eq!(summary.declined, 5);
+    assert_eq!(engine.accounts().len(), 0);
+}
+
+#[test]
+fn freezes_verification_works() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 10
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+    env.process(data);
+    assert_eq!(env.engine.verify_freezes(), Ok(()));
+
+    let mut locked = Account::new(3);
+    locked.lock();
+    env.engine.load_accounts([locked]);
+    assert_eq!(env.engine.verify_freezes(), Err(vec![3]));
+}
+
+#[test]
+fn freezes_are_verified_in_every_currency() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10,
+deposit, 1, 2, 5, EUR
+dispute, 1, 2,, EUR
+chargeback, 1, 2,, EUR
+";
+    env.process(data);
+    assert!(env.engine.get_account_in(&1, "EUR").unwrap().locked);
+    assert_eq!(env.engine.verify_freezes(), Ok(()));
+
+    // a chargeback in EUR doesn't explain a freeze in another currency
+    let mut locked = Account::new(1);
+    locked.lock();
+    env.engine.load_accounts([locked]);
+    assert_eq!(env.engine.verify_freezes(), Err(vec![1]));
+}
+
+#[test]
+fn missing_fields_are_rejected() {
+    // missing `tx`, used to default to 0
+    let data = "\
+type, client, amount
+deposit, 1, 10
+";
+    let mut engine = Engine::new();
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.declined, 1);
+    assert!(engine.get_account(&1).is_none());
+
+    // missing `client`
+    let data = "\
+type, tx, amount
+deposit, 1, 10
+";
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.declined, 1);
+    assert_eq!(engine.accounts().len(), 0);
+
+    let mut rdr = csv::Reader::from_reader(data.as_bytes());
+    let record: Record = rdr.deserialize().next().unwrap().unwrap();
+    assert_eq!(
+        Transaction::try_from(record).unwrap_err(),
+        EngineError::MissingField("client")
+    );
+}
+
+#[test]
+fn strict_arity_rejects_misaligned_rows() {
+    // a money row missing a field, and one with an extra field, read by position
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 5
+dispute, 1, 1
+deposit, 7, 100, 1, 2
+";
+    let mut engine = Engine::new();
+    let summary = engine.ingest(data.as_bytes());
+    // the short withdrawal has no amount
+    assert_eq!((summary.processed, summary.declined), (3, 1));
+
+    let mut engine = Engine::new().with_strict_arity(true);
+    let summary = engine.ingest(data.as_bytes());
+    // short event row is fine
+    assert_eq!(summary.processed, 2);
+    assert_eq!(
+        summary.errors,
+        vec![
+            (
+                5,
+                EngineError::ArityMismatch {
+                    expected: 4,
+                    found: 3
+                }
+            ),
+            (
+                100,
+                EngineError::ArityMismatch {
+                    expected: 4,
+                    found: 5
+                }
+            ),
+        ]
+    );
+    assert_eq!(engine.get_account(&1).unwrap().held, 10 * SCALE);
+    assert!(engine.get_account(&7).is_none());
+}
+
+#[test]
+fn single_account_is_emitted() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 7, 2, 2.5
+deposit, 7, 3, 1
+dispute, 7, 3,
+";
+    env.process(data);
+
+    let mut out = vec![];
+    env.engine.emit_account(7, &mut out, false).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "7,2.5,1,3.5,false\n");
+
+    let mut out = vec![];
+    env.engine.emit_account(7, &mut out, true).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n7,2.5,1,3.5,false\n"
+    );
+
+    assert!(env.engine.emit_account(2, vec![], true).is_err());
+}
+
+#[test]
+fn accounts_sorted_are_in_client_order() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 30, 1, 1
+deposit, 7, 2, 1
+deposit, 1000, 3, 1
+deposit, 1, 4, 1
+deposit, 12, 5, 1
+";
+    env.process(data);
+
+    let ids = env
+        .engine
+        .accounts_sorted()
+        .map(|a| a.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![1, 7, 12, 30, 1000]);
+}
+
+#[test]
+fn output_is_in_client_order() {
+    let data = "\
+type, client, tx, amount
+deposit, 3, 1, 3
+deposit, 1, 2, 1
+deposit, 2, 3, 2
+";
+    let mut out = vec![];
+    Engine::new().run_reader(data.as_bytes(), &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+client,available,held,total,locked
+1,1,0,1,false
+2,2,0,2,false
+3,3,0,3,false
+"
+    );
+}
+
+#[test]
+fn error_catalog_covers_every_variant() {
+    use std::time::Duration;
+
+    // fails to compile once a variant is added, for it to be put into the catalog
+    fn name(e: &EngineError) -> &'static str {
+        match e {
+            EngineError::AccountFrozen => "AccountFrozen",
+            EngineError::InsufficientFunds => "InsufficientFunds",
+            EngineError::FundsHeld { .. } => "FundsHeld",
+            EngineError::NotHeld { .. } => "NotHeld",
+            EngineError::Overflow => "Overflow",
+            EngineError::TxNotFound => "TxNotFound",
+            EngineError::AccountNotFound => "AccountNotFound",
+            EngineError::NotOwner => "NotOwner",
+            EngineError::DuplicateTx => "DuplicateTx",
+            EngineError::InvalidState(_) => "InvalidState",
+            EngineError::AlreadyChargedBack => "AlreadyChargedBack",
+            EngineError::EmptyAmount => "EmptyAmount",
+            EngineError::NotDisputable => "NotDisputable",
+            EngineError::NoType => "NoType",
+            EngineError::MissingField(_) => "MissingField",
+            EngineError::AmountTooLarge { .. } => "AmountTooLarge",
+            EngineError::ClientConflict(_) => "ClientConflict",
+            EngineError::TxConflict(_) => "TxConflict",
+            EngineError::MemoryBudgetExceeded => "MemoryBudgetExceeded",
+            EngineError::AdminOnly => "AdminOnly",
+            EngineError::SelfTransfer => "SelfTransfer",
+            EngineError::ArityMismatch { .. } => "ArityMismatch",
+            EngineError::NotFrozen => "NotFrozen",
+            EngineError::DisputeExpired { .. } => "DisputeExpired",
+            EngineError::DisputeExceedsAmount { .. } => "DisputeExceedsAmount",
+            EngineError::DisputeTimedOut { .. } => "DisputeTimedOut",
+            EngineError::TooManyOpenDisputes { .. } => "TooManyOpenDisputes",
+            EngineError::SignatureInvalid => "SignatureInvalid",
+            EngineError::CrossShard => "CrossShard",
+            EngineError::HeldShortfall { .. } => "HeldShortfall",
+            EngineError::UnknownType(_) => "UnknownType",
+            EngineError::NotDisputed(_) => "NotDisputed",
+            EngineError::LimitExceeded { .. } => "LimitExceeded",
+        }
+    }
+    let variants = [
+        EngineError::AccountFrozen,
+        EngineError::InsufficientFunds,
+        EngineError::FundsHeld {
+            available: units(1),
+            held: units(2),
+        },
+        EngineError::NotHeld {
+            amount: units(1),
+            reason: HoldReason::Manual,
+        },
+        EngineError::Overflow,
+        EngineError::TxNotFound,
+        EngineError::AccountNotFound,
+        EngineError::NotOwner,
+        EngineError::DuplicateTx,
+        EngineError::InvalidState(State::Received),
+        EngineError::AlreadyChargedBack,
+        EngineError::EmptyAmount,
+        EngineError::NotDisputable,
+        EngineError::NoType,
+        EngineError::MissingField("client"),
+        EngineError::AmountTooLarge {
+            amount: units(2),
+            limit: units(1),
+        },
+        EngineError::ClientConflict(1),
+        EngineError::TxConflict(1),
+        EngineError::MemoryBudgetExceeded,
+        EngineError::AdminOnly,
+        EngineError::SelfTransfer,
+        EngineError::ArityMismatch {
+            expected: 4,
+            found: 3,
+        },
+        EngineError::NotFrozen,
+        EngineError::DisputeExpired { age: 2, limit: 1 },
+        EngineError::DisputeExceedsAmount {
+            amount: units(2),
+            original: units(1),
+        },
+        EngineError::DisputeTimedOut {
+            age: Duration::from_secs(2),
+            limit: Duration::from_secs(1),
+        },
+        EngineError::TooManyOpenDisputes { limit: 1 },
+        EngineError::SignatureInvalid,
+        EngineError::CrossShard,
+        EngineError::HeldShortfall {
+            amount: units(2),
+            held: units(1),
+        },
+        EngineError::UnknownType("interest".into()),
+        EngineError::NotDisputed(State::Executed),
+        EngineError::LimitExceeded { limit: 1 },
+    ];
+    let catalog = EngineError::catalog();
+    assert_eq!(catalog.len(), variants.len());
+    for e in &variants {
+        let entry = catalog.iter().find(|(code, _, _)| *code == e.code());
+        assert_eq!(
+            entry,
+            Some(&(e.code(), name(e), describe(e.code()))),
+            "{:?}",
+            e
+        );
+    }
+    // codes are unique and in order
+    assert!(catalog.windows(2).all(|w| w[0].0 < w[1].0));
+}
+
+#[test]
+fn error_codes_map_to_descriptions() {
+    let cases = [
+        (EngineError::AccountFrozen, "account is frozen"),
+        (EngineError::InsufficientFunds, "insufficient balance"),
+        (
+            EngineError::FundsHeld {
+                available: units(1),
+                held: units(2),
+            },
+            "insufficient available balance, funds are held for dispute",
+        ),
+        (EngineError::TxNotFound, "disputed transaction not found"),
+        (
+            EngineError::DuplicateTx,
+            "transaction has already been processed",
+        ),
+        (
+            EngineError::MissingField("client"),
+            "missing required field",
+        ),
+        (
+            EngineError::TxConflict(1),
+            "transaction is present in both engines",
+        ),
+    ];
+    for (e, text) in cases {
+        assert_eq!(describe(e.code()), text);
+    }
+    assert_eq!(describe(0), "unknown error code");
+
+    // declined transactions are reported with their codes
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1
+withdrawal, 1, 2, 2
+deposit, , 3, 1
+dispute, 1, 4,
+";
+    let summary = Engine::new().ingest(data.as_bytes());
+    let codes = summary
+        .codes()
+        .map(|(id, code)| (id, describe(code)))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        codes,
+        vec![
+            (2, "insufficient balance"),
+            (4, "disputed transaction not found"),
+            (3, "missing required field"),
+        ]
+    );
+}
+
+#[test]
+fn subtotal_rows_work() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+dispute, 2, 2,
+";
+    env.process(data);
+    let mut rows: Vec<AccountSer> =
+        vec![AccountSer::from(env.acc(1)), AccountSer::from(env.acc(2))];
+    rows.push(AccountSer::subtotal(&rows));
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for row in rows {
+        wtr.serialize(row).unwrap();
+    }
+    let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        out,
+        "\
+client,available,held,total,locked
+1,10,0,10,false
+2,0,5,5,false
+,10,5,15,false
+"
+    );
+    assert_eq!(validate_output(out.as_bytes()), Ok(3));
+
+    // subtotal rows are not read as accounts
+    let accounts = read_accounts(out.as_bytes()).unwrap();
+    assert_eq!(
+        accounts.iter().map(|a| a.id).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+
+    // tampered subtotal
+    let data = "\
+client,available,held,total,locked
+1,10,0,10,false
+2,0,5,5,false
+,10,6,16,false
+";
+    assert_eq!(
+        validate_output(data.as_bytes()),
+        Err(OutputError::SubtotalMismatch { line: 4 })
+    );
+}
+
+#[test]
+fn resolve_after_chargeback_is_declined() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+    env.process(data);
+    let before = env.acc(1);
+
+    let resolve = read_txs("type, client, tx, amount\nresolve, 1, 1,")
+        .pop()
+        .unwrap();
+    let e = env.process_tx(resolve).unwrap_err();
+    assert_eq!(e, EngineError::AlreadyChargedBack);
+    assert_eq!(describe(e.code()), e.to_string());
+    assert_eq!(env.acc(1), before);
+}
+
+#[test]
+fn redelivered_events_have_no_effect() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 1,
+dispute, 1, 1,
+resolve, 1, 1,
+resolve, 1, 1,
+dispute, 1, 2,
+chargeback, 1, 2,
+chargeback, 1, 2,
+";
+    let expected = Account {
+        id: 1,
+        total: 10 * SCALE,
+        locked: true,
+        last_seq: 6,
+        ..Default::default()
+    };
+    // repeats are declined
+    let mut engine = Engine::new();
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!((summary.processed, summary.declined), (6, 3));
+    assert_eq!(
+        summary.errors.iter().map(|(_, e)| e).collect::<Vec<_>>(),
+        vec![
+            &EngineError::InvalidState(State::Disputed),
+            &EngineError::NotDisputed(State::Executed),
+            &EngineError::AlreadyChargedBack,
+        ]
+    );
+    assert_eq!(engine.get_account(&1), Some(&expected));
+
+    // or accepted within the window
+    let mut engine = Engine::new().with_redelivery_window(1);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!((summary.processed, summary.declined), (9, 0));
+    assert_eq!(engine.get_account(&1), Some(&expected));
+
+    // but not beyond it, nor unless it's the last event of the transaction
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+dispute, 1, 1,
+deposit, 1, 2, 5
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 1, 2,
+resolve, 1, 2,
+dispute, 1, 2,
+dispute, 1, 2,
+";
+    let mut engine = Engine::new().with_redelivery_window(1);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!((summary.processed, summary.declined), (8, 1));
+    assert_eq!(engine.get_account(&1).unwrap().held, 5 * SCALE);
+}
+
+#[test]
+fn dispute_window_works() {
+    // tx 1 is disputed 3 transactions after it, tx 2 is 4 transactions after
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+deposit, 1, 3, 1
+dispute, 1, 1,
+deposit, 1, 4, 1
+dispute, 1, 2,
+";
+    let mut engine = Engine::new().with_max_dispute_age(3);
+    let summary = engine.ingest(data.as_bytes());
+    let e = EngineError::DisputeExpired { age: 4, limit: 3 };
+    assert_eq!(summary.errors, vec![(2, e.clone())]);
+    assert_eq!(describe(e.code()), "transaction is too old to dispute");
+    let acc = engine.get_account(&1).unwrap();
+    assert_eq!((acc.total, acc.held), (17 * SCALE, 10 * SCALE));
+
+    // the dispute opened within the window is resolved regardless
+    let resolve = read_txs("type, client, tx, amount\nresolve, 1, 1,")
+        .pop()
+        .unwrap();
+    assert_eq!(engine.process(resolve), Ok(()));
+    assert_eq!(engine.get_account(&1).unwrap().held, 0);
+}
+
+#[test]
+fn dispute_expiry_works() {
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    let clock = MockClock::default();
+    let mut engine = Engine::new()
+        .with_clock(clock.clone())
+        .with_dispute_expiry(Duration::from_secs(60));
+    engine.ingest("type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 1, 2, 5".as_bytes());
+    let dispute = |tx| {
+        read_txs(&format!("type, client, tx, amount\ndispute, 1, {},", tx))
+            .pop()
+            .unwrap()
+    };
+
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(engine.process(dispute(1)), Ok(()));
+    clock.advance(Duration::from_secs(1));
+    let e = engine.process(dispute(2)).unwrap_err();
+    assert_eq!(
+        e,
+        EngineError::DisputeTimedOut {
+            age: Duration::from_secs(61),
+            limit: Duration::from_secs(60)
+        }
+    );
+    assert_eq!(describe(e.code()), "transaction dispute has expired");
+    assert_eq!(engine.get_account(&1).unwrap().held, 10 * SCALE);
+}
+
+#[test]
+fn open_disputes_limit_works() {
+    let mut engine = Engine::new().with_max_open_disputes(2);
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1
+deposit, 1, 2, 2
+deposit, 1, 3, 3
+deposit, 2, 4, 4
+dispute, 1, 1,
+dispute, 1, 2,
+";
+    engine.ingest(data.as_bytes());
+    let event = |ty: &str, client: u32, tx: u32| {
+        read_txs(&format!(
+            "type, client, tx, amount\n{}, {}, {},",
+            ty, client, tx
+        ))
+        .pop()
+        .unwrap()
+    };
+
+    // the third concurrent one is declined, while the ones of others are not
+    let e = engine.process(event("dispute", 1, 3)).unwrap_err();
+    assert_eq!(e, EngineError::TooManyOpenDisputes { limit: 2 });
+    assert_eq!(describe(e.code()), "too many open disputes on the account");
+    assert_eq!(engine.get_account(&1).unwrap().held, 3 * SCALE);
+    assert_eq!(engine.process(event("dispute", 2, 4)), Ok(()));
+
+    // declined resolve doesn't free a slot, but the one applied does
+    assert!(engine.process(event("resolve", 1, 3)).is_err());
+    assert!(engine.process(event("dispute", 1, 3)).is_err());
+    assert_eq!(engine.process(event("resolve", 1, 1)), Ok(()));
+    assert_eq!(engine.process(event("dispute", 1, 3)), Ok(()));
+    assert_eq!(engine.get_account(&1).unwrap().held, 5 * SCALE);
+}
+
+#[test]
+fn transactions_per_client_limit_works() {
+    let mut engine = Engine::new()
+        .with_max_txs_per_client(2)
+        .with_rollback_depth(1);
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1
+withdrawal, 1, 2, 1
+deposit, 1, 3, 3
+deposit, 2, 4, 4
+deposit, 2, 5, 5
+";
+    // the third one of the client is declined, and reported,
+    // while the ones of others are not
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.processed, 4);
+    assert_eq!(
+        summary.errors,
+        vec![(3, EngineError::LimitExceeded { limit: 2 })]
+    );
+    assert_eq!(summary.lines, vec![Some(4)]);
+    assert_eq!(engine.get_account(&1).unwrap().total, 0);
+    assert_eq!(engine.get_account(&2).unwrap().total, 9 * SCALE);
+
+    // rolled back ones free a slot
+    let deposit = |client, id| Transaction::builder().deposit(client, id, "1").unwrap();
+    let e = engine.process(deposit(2, 6)).unwrap_err();
+    assert_eq!(
+        describe(e.code()),
+        "client has reached the limit of transactions"
+    );
+    assert_eq!(engine.rollback(1), 1);
+    assert_eq!(engine.process(deposit(2, 6)), Ok(()));
+    // events of the ones applied are still accepted
+    let dispute = Transaction::builder().dispute(1, 2);
+    assert_eq!(engine.process(dispute), Ok(()));
+
+    // counted on in the engine restored
+    let mut restored: Engine = Engine::restore(engine.snapshot()).with_max_txs_per_client(2);
+    assert!(restored.process(deposit(1, 7)).is_err());
+    assert_eq!(restored.process(deposit(3, 8)), Ok(()));
+}
+
+#[test]
+fn transactions_in_order_work() {
+    let mut engine = Engine::new().with_rollback_depth(1);
+    let data = "\
+type, client, tx, amount
+deposit, 3, 10, 5
+deposit, 1, 2, 10
+withdrawal, 1, 7, 20
+withdrawal, 3, 4, 1
+dispute, 3, 10,
+deposit, 2, 1, 3
+deposit, 1, 8, 1
+";
+    engine.ingest(data.as_bytes());
+    engine.adjust(5, 2, 1, "goodwill").unwrap();
+    let keys = |e: &Engine| {
+        e.transactions_in_order()
+            .map(Transaction::key)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        keys(&engine),
+        vec![(3, 10), (1, 2), (3, 4), (2, 1), (1, 8), (2, 5)]
+    );
+
+    // the ones rolled back are forgotten
+    assert_eq!(engine.rollback(1), 1);
+    assert_eq!(keys(&engine), vec![(3, 10), (1, 2), (3, 4), (2, 1), (1, 8)]);
+
+    // and the order survives snapshots
+    let restored = Engine::restore(engine.snapshot());
+    assert_eq!(keys(&restored), keys(&engine));
+}
+
+#[test]
+fn wal_replay_works() {
+    let path = std::env::temp_dir().join(format!("tpe-wal-{}.ndjson", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let part = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 20
+withdrawal, 1, 3, 4
+dispute, 2, 2,
+withdrawal, 1, 4, 100
+deposit, 1, 5, 3
+";
+    let mut engine = Engine::new().with_wal(&path).unwrap();
+    engine.ingest(part.as_bytes());
+    engine.adjust(6, 1, -5_000, "fee").unwrap();
+    let expected = engine.snapshot();
+    // a crash in the middle of a write leaves the last record cut short
+    drop(engine);
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    std::io::Write::write_all(&mut file, b"{\"seq\":7,\"applied\":7,\"tx\":{").unwrap();
+
+    let mut recovered = Engine::new();
+    // declined transactions are not logged, the cut short record is skipped
+    assert_eq!(recovered.replay_wal(&path).unwrap(), 6);
+    assert_eq!(recovered.snapshot(), expected);
+    let acc = recovered.get_account(&1).unwrap();
+    assert_eq!((acc.total, acc.held), (units(85_000), units(0)));
+    let acc = recovered.get_account(&2).unwrap();
+    assert_eq!((acc.total, acc.held), (20 * SCALE, 20 * SCALE));
+
+    // records are read as they are written
+    let records = crate::wal::read_wal(&path).unwrap();
+    assert_eq!(records[3].state, Some(TxStateKind::Disputed));
+    assert_eq!(records[3].tx.ty, Some(Tx::Dispute));
+
+    // replay diverging from the log fails
+    let mut diverged = Engine::new().with_store_withdrawals(false);
+    assert!(diverged.replay_wal(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn snapshot_round_trip_works() {
+    let first = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 20
+deposit, 1, 3, 4
+dispute, 1, 1,
+dispute, 2, 2,
+";
+    let rest = "\
+type, client, tx, amount
+resolve, 1, 1,
+chargeback, 2, 2,
+withdrawal, 1, 5, 2
+deposit, 1, 4, 1.5
+dispute, 1, 4,
+";
+    let mut single = Engine::new();
+    single.ingest(first.as_bytes());
+    single.ingest(rest.as_bytes());
+
+    let mut engine = Engine::new();
+    engine.ingest(first.as_bytes());
+    // states of the transactions are a part of it, for the events to apply later
+    let snapshot = engine.snapshot();
+    let tx = &snapshot.transactions[0];
+    assert_eq!((tx.client, tx.id), (1, 1));
+    assert_eq!(Transaction::from(tx.clone()).state(), State::Disputed);
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let mut resumed: Engine = Engine::restore(serde_json::from_str(&json).unwrap());
+    resumed.ingest(rest.as_bytes());
+
+    assert_eq!(resumed.snapshot(), single.snapshot());
+    let acc = resumed.get_account(&1).unwrap();
+    assert_eq!((acc.total, acc.held), (units(135_000), units(15_000)));
+    assert!(resumed.get_account(&2).unwrap().locked);
+}
+
+#[test]
+fn rollback_works() {
+    let mut engine = Engine::new().with_rollback_depth(3);
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 1, 3, 3
+";
+    engine.ingest(data.as_bytes());
+    let before = engine.snapshot();
+    let data = "\
+type, client, tx, amount
+deposit, 3, 4, 2
+withdrawal, 1, 5, 1
+withdrawal, 2, 6, 100
+";
+    engine.ingest(data.as_bytes());
+    assert_eq!(engine.get_account(&1).unwrap().total, 6 * SCALE);
+
+    // the declined one is not a part of the history
+    assert_eq!(engine.rollback(2), 2);
+    let after = engine.snapshot();
+    assert_eq!(after.accounts, before.accounts);
+    assert_eq!(after.transactions, before.transactions);
+    assert!(engine.get_account(&3).is_none());
+    // the rest, up to the depth
+    assert_eq!(engine.rollback(5), 1);
+    assert_eq!(engine.get_account(&1).unwrap().total, 10 * SCALE);
+
+    // events are not reversible, and neither is what came before them
+    let data = "\
+type, client, tx, amount
+deposit, 1, 7, 1
+dispute, 1, 7,
+";
+    engine.ingest(data.as_bytes());
+    assert_eq!(engine.rollback(1), 0);
+    assert_eq!(engine.get_account(&1).unwrap().held, SCALE);
+}
+
+#[test]
+fn rollback_of_unstored_withdrawals_works() {
+    let mut engine = Engine::new()
+        .with_rollback_depth(3)
+        .with_store_withdrawals(false);
+    let data = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10, EUR
+deposit, 1, 2, 7,
+withdrawal, 1, 3, 4, EUR
+";
+    engine.ingest(data.as_bytes());
+    assert_eq!(
+        engine.get_account_in(&1, "EUR").unwrap().total,
+        units(60_000)
+    );
+
+    // restored in the ledger of its currency, not in the implicit one
+    assert_eq!(engine.rollback(1), 1);
+    assert_eq!(
+        engine.get_account_in(&1, "EUR").unwrap().total,
+        units(100_000)
+    );
+    assert_eq!(engine.get_account(&1).unwrap().total, units(70_000));
+    assert_eq!(engine.transactions().len(), 2);
+}
+
+#[test]
+fn memory_budget_rejects_new_work() {
+    // room for 3 accounts with a transaction each, kept track of the order of
+    let budget = 3
+        * (std::mem::size_of::<(u32, Account)>()
+            + std::mem::size_of::<(u32, Transaction)>()
+            + std::mem::size_of::<TxKey>());
+    let mut env = Env::with_engine(Engine::new().with_memory_budget(budget));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1
+deposit, 2, 2, 1
+deposit, 3, 3, 1
+";
+    env.process(data);
+    assert_eq!(env.tx_count(), 3);
+
+    let txs = read_txs(
+        "\
+type, client, tx, amount
+deposit, 4, 4, 1
+deposit, 1, 5, 1",
+    );
+    for tx in txs {
+        assert_eq!(env.process_tx(tx), Err(EngineError::MemoryBudgetExceeded));
+    }
+    assert_eq!(env.tx_count(), 3);
+    assert_eq!(env.balances(), vec![(1, SCALE), (2, SCALE), (3, SCALE)]);
+
+    // events don't take memory, so are still accepted
+    env.process("type, client, tx, amount\ndispute, 1, 1,");
+    assert_eq!(env.acc(1).held, SCALE);
+}
+
+#[test]
+fn timing_is_recorded() {
+    let mut data = String::from("type, client, tx, amount\n");
+    for id in 1..=10_000 {
+        data.push_str(&format!("deposit, {}, {}, 1.0\n", id % 100, id));
+    }
+
+    let summary = Engine::new().ingest(data.as_bytes());
+    assert!(summary.timing.is_none());
+
+    let summary = Engine::new().with_timing(true).ingest(data.as_bytes());
+    let timing = summary.timing.unwrap();
+    assert_eq!(summary.processed, 10_000);
+    assert!(!timing.parsing.is_zero());
+    assert!(!timing.processing.is_zero());
+}
+
+#[test]
+fn snapshot_locked_flag_spellings_work() {
+    let snapshot = "\
+client,available,held,total,locked
+1,1,0,1,1
+2,1,0,1,yes
+3,1,0,1,TRUE
+4,1,0,1,true
+5,1,0,1,0
+6,1,0,1,No
+7,1,0,1,False
+";
+    let locked = read_accounts(snapshot.as_bytes())
+        .unwrap()
+        .iter()
+        .map(|a| (a.id, a.locked))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        locked,
+        vec![
+            (1, true),
+            (2, true),
+            (3, true),
+            (4, true),
+            (5, false),
+            (6, false),
+            (7, false)
+        ]
+    );
+
+    let snapshot = "client,available,held,total,locked\n1,1,0,1,maybe\n";
+    assert!(read_accounts(snapshot.as_bytes()).is_err());
+
+    // output stays canonical
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    for acc in
+        read_accounts("client,available,held,total,locked\n1,1,0,1,YES\n".as_bytes()).unwrap()
+    {
+        wtr.serialize(AccountSer::<DECIMALS>::from(acc)).unwrap();
+    }
+    let out = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+    assert_eq!(out, "client,available,held,total,locked\n1,1,0,1,true\n");
+}
+
+#[test]
+fn run_writes_to_given_writer() {
+    let mut out = vec![];
+    let mut engine = Engine::new().with_grouping(LockGrouping::UnlockedFirst);
+    engine
+        .run(&OsString::from("./fixtures/in/basic.csv"), &mut out)
+        .unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,0,2,false
+"
+    );
+}
+
+#[test]
+fn custom_amount_parser_works() {
+    /// Amounts given in whole cents, with a `c` suffix.
+    #[derive(Debug)]
+    struct Cents;
+
+    impl AmountParser for Cents {
+        fn parse(&self, s: &str) -> Result<Amount, AmountError> {
+            s.strip_suffix('c')
+                .and_then(|c| c.parse::<Amount>().ok())
+                .and_then(|c| c.checked_mul(scale(DECIMALS - 2)))
+                .ok_or_else(|| AmountError(s.to_owned()))
+        }
+    }
+
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 150c
+deposit, 1, 2, 1.5
+withdrawal, 1, 3, 25c
+";
+    let mut engine = Engine::new().with_amount_parser(Cents);
+    let summary = engine.ingest(data.as_bytes());
+    let env = Env::with_engine(engine);
+
+    // plain decimal is not an amount for this parser, so that deposit is dropped
+    assert_eq!(summary.processed, 2);
+    assert_eq!(env.acc(1).total, 125 * scale(DECIMALS - 2));
+}
+
+#[test]
+fn minor_units_work() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 150
+deposit, 2, 2, 1.5
+";
+    let mut engine = Engine::new().with_amount_parser(MinorUnitsParser::<DECIMALS>::default());
+    let summary = engine.ingest(data.as_bytes());
+    // there is no decimal parsing, so a decimal is not an amount, and is dropped
+    assert_eq!(summary.processed, 1);
+    assert_eq!(engine.get_account(&1).unwrap().total, 15_000);
+    // while it takes the integer for major units by default
+    let mut engine = Engine::new();
+    engine.ingest(data.as_bytes());
+    assert_eq!(engine.get_account(&1).unwrap().total, 1_500_000);
+
+    // units finer than the engine scale are only taken if not truncated
+    let parser = MinorUnitsParser::<4> { digits: 6 };
+    assert_eq!(parser.parse("1500"), Ok(units(15)));
+    assert_eq!(parser.parse("1501"), Err(AmountError("1501".to_owned())));
+}
+
+#[test]
+fn lenient_amounts_work() {
+    let data = r#"type,client,tx,amount
+deposit,1,1,"1,234.56"
+deposit,2,2,$1.00
+deposit,3,3,"1,2,3.4"
+deposit,4,4,12.5
+"#;
+    let mut engine = Engine::new().with_lenient_amounts(true);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.processed, 3);
+    assert_eq!(engine.get_account(&1).unwrap().total, 12_345_600);
+    assert_eq!(engine.get_account(&2).unwrap().total, 10_000);
+    // improperly grouped digits are no amount
+    assert!(engine.get_account(&3).is_none());
+    assert_eq!(engine.get_account(&4).unwrap().total, 125_000);
+    // while strict parsing takes plain decimals only
+    let mut engine = Engine::new();
+    assert_eq!(engine.ingest(data.as_bytes()).processed, 1);
+    let mut engine = Engine::new()
+        .with_lenient_amounts(true)
+        .with_lenient_amounts(false);
+    assert_eq!(engine.ingest(data.as_bytes()).processed, 1);
+    // and the parser set up otherwise is kept
+    let mut engine = Engine::new()
+        .with_rounding(RoundingMode::HalfUp)
+        .with_lenient_amounts(false);
+    engine.ingest("type,client,tx,amount\ndeposit,1,1,1.99995\n".as_bytes());
+    assert_eq!(engine.get_account(&1).unwrap().total, units(20_000));
+
+    let parser = LenientParser::<4>::default();
+    assert_eq!(parser.parse("€ 1,000,000"), Ok(units(10_000_000_000)));
+    assert_eq!(parser.parse("+1,000.5"), Ok(units(10_005_000)));
+    assert_eq!(parser.parse("1,5e3"), Err(AmountError("1,5e3".to_owned())));
+    for s in [
+        "1,2,3.4",
+        ",123",
+        "1234,567",
+        "1,23",
+        "1,234.5,6",
+        "$$1",
+        "USD1",
+    ] {
+        assert!(parser.parse(s).is_err(), "{}", s);
+    }
+    // locales swapping the decimal point and grouping characters
+    let parser = LenientParser::<4>::decimal_comma();
+    assert_eq!(parser.parse("1.234,56"), Ok(units(12_345_600)));
+    assert_eq!(parser.parse("1 234,5"), Ok(units(12_345_000)));
+    assert_eq!(parser.parse("£0,01"), Ok(units(100)));
+    assert!(parser.parse("1,234.56").is_err());
+}
+
+#[cfg(feature = "decimal")]
+#[test]
+fn exact_amount_parser_works() {
+    use crate::decimal::{to_amount, to_decimal, ExactParser};
+    use rust_decimal::Decimal;
+
+    // excess precision is rejected rather than truncated
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.23456
+deposit, 1, 2, 1.23450
+deposit, 1, 3, -1
+";
+    let mut engine = Engine::new().with_amount_parser(ExactParser::<4>);
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(summary.processed, 1);
+    assert_eq!(engine.get_account(&1).unwrap().total, 12_345);
+
+    let mut engine = Engine::<8>::default().with_amount_parser(ExactParser::<8>);
+    engine.ingest(data.as_bytes());
+    assert_eq!(engine.get_account(&1).unwrap().total, 246_906_000);
+
+    // conversions round-trip
+    let max = to_decimal(MAX_AMOUNT, DECIMALS).unwrap();
+    assert_eq!(max.to_string(), MAX_DECIMAL);
+    assert_eq!(to_amount(max, DECIMALS), Some(MAX_AMOUNT));
+    assert_eq!(to_amount(max + Decimal::new(1, 4), DECIMALS), None);
+    assert_eq!(
+        to_decimal(units(15_000), DECIMALS).unwrap().to_string(),
+        "1.5"
+    );
+}
+
+#[cfg(feature = "decimal")]
+#[test]
+fn decimal_amounts_keep_their_digits() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.23456
+deposit, 1, 2, 0.00001
+withdrawal, 1, 3, 0.000015
+dispute, 1, 2,
+";
+    let mut engine = Engine::new();
+    engine.ingest(data.as_bytes());
+    // balances are of the amounts as they are read, digits beyond the precision included
+    let acc = *engine.get_account(&1).unwrap();
+    assert_eq!(
+        (acc.total.to_string(), acc.held.to_string()),
+        ("12345.55".into(), "0.1".into())
+    );
+    // and output with all of them, with no padding
+    let strings = |b: (&str, &str, &str)| (b.0.to_owned(), b.1.to_owned(), b.2.to_owned());
+    assert_eq!(
+        engine.balance_of(&1),
+        Some(strings(("1.234545", "0.00001", "1.234555")))
+    );
+    // funds short by less than the precision are short all the same
+    let tx = Transaction::builder()
+        .withdrawal(1, 4, "1.2345451")
+        .unwrap();
+    assert!(matches!(
+        engine.process(tx),
+        Err(EngineError::FundsHeld { .. })
+    ));
+
+    // the state is kept with them as well
+    let json = serde_json::to_string(&engine.snapshot()).unwrap();
+    let mut resumed: Engine = Engine::restore(serde_json::from_str(&json).unwrap());
+    assert_eq!(resumed.get_account(&1), Some(&acc));
+    resumed.ingest("type, client, tx, amount\nchargeback, 1, 2,".as_bytes());
+    let mut out = vec![];
+    resumed.write_accounts(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n1,1.234545,0,1.234545,true\n"
+    );
+
+    // adjustments are made by the amounts as they are read too
+    let mut engine = Engine::new().with_admin_ops(true);
+    let data = "type, client, tx, amount\nadjustment, 1, 1, 2.000005\nadjustment, 1, 2, -0.5";
+    assert_eq!(engine.ingest(data.as_bytes()).processed, 2);
+    assert_eq!(
+        fmt_amount(engine.get_account(&1).unwrap().total),
+        "1.500005"
+    );
+}
+
+#[cfg(feature = "hmac")]
+#[test]
+fn signatures_work() {
+    use crate::hmac::SigningKey;
+
+    // RFC 4231, test case 2
+    assert_eq!(
+        SigningKey::new("Jefe").sign(&["what do ya want for nothing?"]),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    let key = SigningKey::new("Jefe");
+    let signature = key.sign(&["what do ya want for nothing?"]);
+    assert!(key.verify(&["what do ya want for nothing?"], &signature.to_uppercase()));
+    assert!(!key.verify(&["what do ya want for nothing?"], &signature[..62]));
+    assert!(!key.verify(&["what do ya want for nothing?"], "+5"));
+
+    let key = SigningKey::new("secret");
+    let data = format!(
+        "\
+type, client, tx, amount, signature
+deposit, 1, 1, 10, {}
+deposit, 1, 2, 100, {}
+withdrawal, 1, 3, 1,
+dispute, 1, 1, , {}
+",
+        key.sign(&["deposit", "1", "1", "10"]),
+        key.sign(&["deposit", "1", "2", "1"]),
+        key.sign(&["dispute", "1", "1", ""]).to_uppercase(),
+    );
+    let mut engine = Engine::new().with_signing_key("secret");
+    let summary = engine.ingest(data.as_bytes());
+
+    // tampered and unsigned rows are declined
+    assert_eq!((summary.processed, summary.declined), (2, 2));
+    assert_eq!(
+        summary.errors,
+        vec![
+            (2, EngineError::SignatureInvalid),
+            (3, EngineError::SignatureInvalid)
+        ]
+    );
+    let acc = engine.get_account(&1).unwrap();
+    assert_eq!((acc.total, acc.held), (10 * SCALE, 10 * SCALE));
+    // while under another key, none of them are valid
+    let mut engine = Engine::new().with_signing_key("other");
+    assert_eq!(engine.ingest(data.as_bytes()).processed, 0);
+}
+
+// amounts of `decimal` feature take precedence over `bigamount` ones
+#[cfg(all(feature = "bigamount", not(feature = "decimal")))]
+#[test]
+fn amounts_beyond_u64_ceiling_work() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1844674407370955.1615
+deposit, 1, 2, 1000000000000000000000.5
+withdrawal, 1, 3, 0.0001
+dispute, 1, 2,
+";
+    let mut engine = Engine::new();
+    let mut out = vec![];
+    engine.run_reader(data.as_bytes(), &mut out).unwrap();
+    let total = u64::MAX as Amount + 10_000_000_000_000_000_000_005_000 - 1;
+    assert_eq!(engine.get_account(&1).unwrap().total, total);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+client,available,held,total,locked
+1,1844674407370955.1614,1000000000000000000000.5,1000001844674407370955.6614,false
+"
+    );
+    // and in the other way, beyond the ceiling of the engine of larger scale
+    let mut engine = Engine::<12>::default();
+    engine.ingest(data.as_bytes());
+    assert_eq!(
+        fmt_scaled_amount(engine.get_account(&1).unwrap().total, 12),
+        "1000001844674407370955.6614"
+    );
+}
+
+#[test]
+fn opening_balances_work() {
+    let opening = "\
+client,available,held,total,locked
+1,100,0,100,false
+2,20,0,20,false
+";
+    let mut env = Env::new();
+    env.engine
+        .load_accounts(read_accounts(opening.as_bytes()).unwrap());
+
+    let data = "\
+type, client, tx, amount
+withdrawal, 1, 1, 40
+withdrawal, 2, 2, 25
+";
+    env.process(data);
+    assert_eq!(env.balances(), vec![(1, 60 * SCALE), (2, 20 * SCALE)]);
+
+    // opening balances are not backed by transactions to dispute
+    let dispute = read_txs("type, client, tx, amount\ndispute, 2, 0,")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(dispute), Err(EngineError::TxNotFound));
+}
+
+#[test]
+fn run_reports_faulty_records() {
+    let mut engine = Engine::new();
+    let report = engine
+        .run(&OsString::from("./fixtures/in/faulty_records.csv"), vec![])
+        .unwrap();
+
+    assert_eq!(report.processed, 1);
+    // malformed types, negative ids, non-numeric fields
+    assert_eq!(report.parse_errors, 4);
+    assert_eq!(report.malformed, vec![3, 5, 9, 11]);
+    // invalid and zero amounts, unknown types, and missing fields
+    let unknown = || EngineError::UnknownType("withdraw".into());
+    assert_eq!(
+        report.declined,
+        vec![
+            (4, unknown()),
+            (5, unknown()),
+            (3, EngineError::EmptyAmount),
+            (4, EngineError::EmptyAmount),
+            (0, EngineError::MissingField("type"))
+        ]
+    );
+    assert_eq!(
+        report.lines,
+        vec![Some(6), Some(8), Some(4), Some(7), Some(10)]
+    );
+}
+
+#[test]
+fn unknown_types_are_reported() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+interest, 1, 2, 0.5
+deposit, 1, 3, 5
+";
+    let mut engine = Engine::new();
+    let report = engine.run_reader(data.as_bytes(), vec![]).unwrap();
+    assert_eq!(report.processed, 2);
+    assert_eq!(report.parse_errors, 0);
+    let e = EngineError::UnknownType("interest".into());
+    assert_eq!(report.declined, vec![(2, e.clone())]);
+    assert_eq!(report.lines, vec![Some(3)]);
+    assert_eq!(e.to_string(), "unknown transaction type: interest");
+    assert_eq!(describe(e.code()), "unknown transaction type");
+    assert_eq!(engine.get_account(&1).unwrap().total, 15 * SCALE);
+
+    // and they're written back as they were read
+    let tx = read_txs(data).remove(1);
+    assert_eq!(tx.ty, Some(Tx::Unknown("interest".into())));
+    assert_eq!(
+        serde_json::to_string(&tx.ty).unwrap(),
+        "\"interest\"".to_string()
+    );
+}
+
+#[test]
+fn declined_lines_are_reported() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+# not a row
+withdrawal, 1, 2, 20
+dispute, 2, 7,
+deposit, 1, 3, 0
+deposit, 1, x, 1
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 1, 4, 1
+";
+    let mut engine = Engine::new();
+    let summary = engine.ingest(data.as_bytes());
+    assert_eq!(
+        summary.errors.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+        vec![2, 7, 4, 3]
+    );
+    assert_eq!(summary.lines, vec![Some(4), Some(5), Some(10), Some(6)]);
+    assert_eq!(summary.malformed, vec![3, 7]);
+
+    // lines of the input are reported when it's processed in parallel as well
+    let mut engine = Engine::new();
+    let report = engine.run_parallel(data.as_bytes(), vec![], 3).unwrap();
+    let mut lines = report
+        .declined
+        .iter()
+        .map(|(id, _)| *id)
+        .zip(report.lines)
+        .collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(
+        lines,
+        vec![(2, Some(4)), (3, Some(6)), (4, Some(10)), (7, Some(5))]
+    );
+    assert_eq!(report.malformed, vec![3, 7]);
+
+    // and transactions not read from input have none
+    let report = Engine::new().process_many([Transaction::builder().dispute(1, 1)]);
+    assert_eq!(report.lines, vec![None]);
+}
+
+#[test]
+fn dispute_cycles_get_flagged() {
+    init_log_capture();
+    let engine = Engine::new()
+        .with_run_id("cycles")
+        .with_dispute_cycle_threshold(2);
+    let mut env = Env::with_engine(engine);
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 10
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 2, 2,
+resolve, 2, 2,
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 1, 1,
+";
+    env.process(data);
+
+    assert_eq!(env.engine.flagged_disputes(), vec![(1, 1)]);
+    let logs = captured_logs("[cycles] ")
+        .into_iter()
+        .filter(|l| l.contains("threshold"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        logs,
+        vec!["[cycles] tx 1 of client 1 disputed 3 times, exceeding threshold of 2"]
+    );
+}
+
+#[test]
+fn dispute_by_another_client_is_declined() {
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    let before = env.acc(1);
+
+    for event in ["dispute", "resolve", "chargeback"] {
+        let tx = read_txs(&format!("type, client, tx, amount\n{}, 2, 1,", event))
+            .pop()
+            .unwrap();
+        assert_eq!(env.process_tx(tx), Err(EngineError::TxNotFound));
+    }
+    assert_eq!(env.acc(1), before);
+    assert!(env.engine.get_account(&2).is_none());
+}
+
+#[test]
+fn tx_ids_are_unique_per_client() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 1, 20
+deposit, 2, 1, 30
+dispute, 2, 1,
+chargeback, 2, 1,
+";
+    env.process(data);
+    // both deposits stored, and the events resolve to the one of their client
+    assert_eq!(env.tx_count(), 2);
+    assert_eq!(
+        env.acc(1),
+        Account {
+            id: 1,
+            total: 10 * SCALE,
+            last_seq: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        env.acc(2),
+        Account {
+            id: 2,
+            locked: true,
+            last_seq: 4,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn held_only_output_works() {
+    let mut env = Env::with_engine(Engine::new().with_held_only(true));
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 2, 2, 20
+deposit, 3, 3, 7
+deposit, 4, 4, 20
+deposit, 5, 5, 3
+dispute, 1, 1,
+dispute, 2, 2,
+dispute, 4, 4,
+dispute, 5, 5,
+resolve, 5, 5,
+";
+    env.process(data);
+
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+client,available,held,total,locked
+2,0,20,20,false
+4,0,20,20,false
+1,0,5,5,false
+"
+    );
+}
+
+#[test]
+fn negative_available_works() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+dispute, 1, 1,
+deposit, 2, 2, 1
+";
+    let mut engine = Engine::new()
+        .with_signed_available(true)
+        .with_currency_subtotals(true);
+    engine.ingest(data.as_bytes());
+    engine.adjust(3, 1, -7 * 10_000, "fee").unwrap();
+    let acc = engine.get_account(&1).unwrap();
+    assert!(acc.held > acc.total);
+    assert_eq!(acc.available(), 0);
+    assert_eq!(acc.available_signed(), -signed(7 * SCALE));
+
+    // shown as such in the output, which passes validation
+    let mut out = vec![];
+    engine.write_accounts(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out.clone()).unwrap(),
+        "\
+client,available,held,total,locked
+1,-7,10,3,false
+2,1,0,1,false
+,-6,10,4,false
+"
+    );
+    assert_eq!(validate_output(out.as_slice()), Ok(3));
+
+    // and clamped to zero unless asked
+    let row = AccountSer::<DECIMALS>::from(*acc);
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.serialize(row).unwrap();
+    assert_eq!(
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap(),
+        "client,available,held,total,locked\n1,0,10,3,false\n"
+    );
+}
+
+#[test]
+fn overdraft_works() {
+    let mut env = Env::with_engine(Engine::new().with_signed_available(true));
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 2, 2, 10");
+    assert_eq!(
+        env.engine.set_overdraft_limit(3, 5 * SCALE),
+        Err(EngineError::AccountNotFound)
+    );
+    env.engine.set_overdraft_limit(1, 5 * SCALE).unwrap();
+
+    // beyond the limit, declined
+    let tx = read_txs("type, client, tx, amount\nwithdrawal, 1, 3, 15.0001")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::InsufficientFunds));
+    assert_eq!(env.acc(1).total, 10 * SCALE);
+    // within it, the total is underwater
+    let tx = read_txs("type, client, tx, amount\nwithdrawal, 1, 4, 14")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Ok(()));
+    let acc = env.acc(1);
+    assert_eq!(acc.total_signed(), -signed(4 * SCALE));
+    assert_eq!(acc.available_signed(), -signed(4 * SCALE));
+    assert_eq!(acc.available(), 0);
+    // as without a limit, which is unchanged
+    let tx = read_txs("type, client, tx, amount\nwithdrawal, 2, 5, 11")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::InsufficientFunds));
+
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out.clone()).unwrap(),
+        "\
+client,available,held,total,locked
+1,-4,0,-4,false
+2,10,0,10,false
+"
+    );
+    assert_eq!(validate_output(out.as_slice()), Ok(2));
+    assert_eq!(
+        read_accounts(out.as_slice()).unwrap()[0].total_signed(),
+        -signed(4 * SCALE)
+    );
+
+    // deposits pay the overdraft off first
+    env.process("type, client, tx, amount\ndeposit, 1, 6, 6");
+    let acc = env.acc(1);
+    assert_eq!((acc.total, acc.overdrawn), (2 * SCALE, units(0)));
+}
+
+#[test]
+fn adjustments_work() {
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    env.process("type, client, tx, amount\ndispute, 1, 1,");
+
+    // positive and negative ones, bypassing availability
+    env.engine.adjust(2, 1, 5 * 10_000, "goodwill").unwrap();
+    env.engine
+        .adjust(3, 1, -12 * 10_000, "fee reversal")
+        .unwrap();
+    let acc = env.acc(1);
+    assert_eq!(acc.total, 3 * SCALE);
+    assert_eq!(acc.held, 10 * SCALE);
+    assert_eq!(acc.available(), 0);
+    assert_eq!(
+        env.engine.adjust(4, 1, -4 * 10_000, "too much"),
+        Err(EngineError::InsufficientFunds)
+    );
+    assert_eq!(
+        env.engine.adjust(3, 1, 1, "duplicate"),
+        Err(EngineError::DuplicateTx)
+    );
+
+    // they are not accepted from input
+    let tx = read_txs("type, client, tx, amount\nadjustment, 1, 5, 100")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::AdminOnly));
+
+    // unless admin operations are allowed, signed as they are read
+    let mut admin = Env::with_engine(Engine::new().with_admin_ops(true));
+    admin.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    admin.process("type, client, tx, amount\nadjustment, 1, 2, 2.5\nadjustment, 1, 3, -4");
+    assert_eq!(admin.acc(1).total, 85_000);
+    let fee = admin.engine.transactions().find(|tx| tx.id == 3).unwrap();
+    assert_eq!(fee.signed_amount(), Some(-signed(4 * SCALE)));
+    let mut engine = Engine::new().with_admin_ops(true);
+    let data = "type, client, tx, amount\nadjustment, 1, 1, 2.5\nadjustment, 1, 2, -1";
+    let summary = engine.process_stream(data.as_bytes(), |_| ());
+    assert_eq!((summary.processed, summary.declined), (2, 0));
+    assert_eq!(engine.get_account(&1).unwrap().total, 15_000);
+    // and a negative amount is malformed for other types
+    admin.process("type, client, tx, amount\ndeposit, 1, 4, -4");
+    assert_eq!(admin.acc(1).total, 85_000);
+    // amounts beyond the signed delta are declined rather than truncated
+    let mut tx = Transaction::new(Tx::Adjustment, 1, 5, Some(units(i64::MAX as u64 + 1)));
+    tx.init(TxStateKind::Received).unwrap();
+    assert_eq!(
+        admin.process_tx(tx),
+        Err(EngineError::AmountTooLarge {
+            amount: units(i64::MAX as u64 + 1),
+            limit: units(i64::MAX as u64),
+        })
+    );
+    assert_eq!(admin.acc(1).total, 85_000);
+
+    // frozen accounts are adjusted only if configured so
+    env.process("type, client, tx, amount\nchargeback, 1, 1,");
+    assert_eq!(env.acc(1).total_signed(), -signed(7 * SCALE));
+    assert_eq!(
+        env.engine.adjust(6, 1, 10_000, "after freeze"),
+        Err(EngineError::AccountFrozen)
+    );
+    let mut env = Env::with_engine(Engine::new().with_frozen_adjustments(true));
+    env.engine.load_accounts([Account {
+        id: 1,
+        locked: true,
+        ..Default::default()
+    }]);
+    env.engine.adjust(6, 1, 10_000, "after freeze").unwrap();
+    assert_eq!(env.acc(1).total, SCALE);
+}
+
+#[test]
+fn adjustments_draw_on_overdraft() {
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    env.engine.set_overdraft_limit(1, 5 * SCALE).unwrap();
+
+    // interest credited, then a fee debited beyond the balance
+    env.engine.adjust(2, 1, 10_000, "interest").unwrap();
+    env.engine.adjust(3, 1, -14 * 10_000, "annual fee").unwrap();
+    let acc = env.acc(1);
+    assert_eq!((acc.total, acc.overdrawn), (units(0), 3 * SCALE));
+    assert_eq!(
+        env.engine.adjust(4, 1, -2 * 10_000 - 1, "beyond the limit"),
+        Err(EngineError::InsufficientFunds)
+    );
+    assert_eq!(env.acc(1).total_signed(), -signed(3 * SCALE));
+
+    // a credit pays the overdraft off first
+    env.engine.adjust(5, 1, 4 * 10_000, "refund").unwrap();
+    let acc = env.acc(1);
+    assert_eq!((acc.total, acc.overdrawn), (SCALE, units(0)));
+
+    // they are signed, and can't be disputed
+    let fee = env.engine.transactions().find(|tx| tx.id == 3).unwrap();
+    assert_eq!(fee.signed_amount(), Some(-signed(14 * SCALE)));
+    let dispute = read_txs("type, client, tx, amount\ndispute, 1, 3,")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(dispute), Err(EngineError::NotDisputable));
+    assert_eq!(
+        env.engine.adjust(6, 2, i64::MIN, "uncovered"),
+        Err(EngineError::InsufficientFunds)
+    );
+}
+
+#[test]
+fn overdrawn_accounts_are_output_balanced() {
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    env.engine.set_overdraft_limit(1, 5 * SCALE).unwrap();
+    env.process("type, client, tx, amount\nwithdrawal, 1, 2, 14");
+
+    // available balance is negative as the total is, even if not asked to be signed
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out.clone()).unwrap(),
+        "client,available,held,total,locked\n1,-4,0,-4,false\n"
+    );
+    assert_eq!(validate_output(out.as_slice()), Ok(1));
+    let accounts = read_accounts(out.as_slice()).unwrap();
+    assert_eq!(accounts[0].total_signed(), -signed(4 * SCALE));
+}
+
+#[test]
+fn currencies_are_kept_apart() {
+    let data = "\
+type, client, tx, amount, currency
+deposit, 1, 1, 10,
+deposit, 1, 2, 5, EUR
+withdrawal, 1, 3, 7, EUR
+deposit, 2, 4, 3, USD
+dispute, 1, 2,, USD
+dispute, 1, 2,, EUR
+";
+    let mut env = Env::with_engine(Engine::new().with_currency_subtotals(true));
+    let mut txs = read_txs(data);
+    let wrong = txs.remove(4);
+    env.process_tx(txs.remove(0)).unwrap();
+    env.process_tx(txs.remove(0)).unwrap();
+    // the EUR balance doesn't cover it, however much there is in the implicit one
+    assert_eq!(
+        env.process_tx(txs.remove(0)),
+        Err(EngineError::InsufficientFunds)
+    );
+    env.process_tx(txs.remove(0)).unwrap();
+    // events refer to transactions of their own currency only
+    assert_eq!(env.process_tx(wrong), Err(EngineError::TxNotFound));
+    env.process_tx(txs.remove(0)).unwrap();
+
+    assert_eq!(env.acc(1).total, 10 * SCALE);
+    let eur = *env.engine.get_account_in(&1, "EUR").unwrap();
+    assert_eq!((eur.total, eur.held), (5 * SCALE, 5 * SCALE));
+    assert_eq!(
+        env.engine.get_account_in(&2, "USD").unwrap().total,
+        3 * SCALE
+    );
+    assert!(env.engine.get_account(&2).is_none());
+    assert_eq!(env.engine.currencies(), ["EUR", "USD"]);
+
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    let csv = String::from_utf8(out).unwrap();
+    assert_eq!(
+        csv,
+        "\
+client,currency,available,held,total,locked
+1,,10,0,10,false
+,,10,0,10,false
+1,EUR,0,5,5,false
+,EUR,0,5,5,false
+2,USD,3,0,3,false
+,USD,3,0,3,false
+"
+    );
+    assert_eq!(validate_output(csv.as_bytes()), Ok(6));
+    // accounts of the implicit currency only are read back
+    let accounts = read_accounts(csv.as_bytes()).unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_eq!((accounts[0].id, accounts[0].total), (1, 10 * SCALE));
+
+    // carried on from a snapshot
+    let restored: Engine = Engine::restore(env.engine.snapshot());
+    assert_eq!(restored.get_account_in(&1, "EUR"), Some(&eur));
+    assert_eq!(restored.currencies(), ["EUR", "USD"]);
+
+    // without currencies, the output is as it was
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+}
+
+#[test]
+fn unlock_unfreezes_account_for_admins_only() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 2,
+chargeback, 1, 2,
+";
+    let deposit = "type, client, tx, amount\ndeposit, 1, 3, 1";
+    let unlock = "type, client, tx, amount\nunlock, 1, 4,";
+
+    // ordinary batches can't unfreeze accounts
+    let mut env = Env::new();
+    env.process(data);
+    let tx = read_txs(unlock).pop().unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::AdminOnly));
+    assert!(env.acc(1).locked);
+
+    let mut env = Env::with_engine(Engine::new().with_admin_ops(true));
+    env.process(data);
+    let tx = read_txs(deposit).pop().unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::AccountFrozen));
+    env.process(unlock);
+    let acc = env.acc(1);
+    assert!(!acc.locked);
+    assert_eq!(acc.total, 10 * SCALE);
+    // deposits are accepted again
+    env.process(deposit);
+    assert_eq!(env.acc(1).total, 11 * SCALE);
+
+    // only frozen and existing accounts are unlocked
+    let tx = read_txs(unlock).pop().unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::NotFrozen));
+    let tx = read_txs("type, client, tx, amount\nunlock, 2, 5,")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::AccountNotFound));
+}
+
+#[test]
+fn chargeback_reversal_reinstates_transaction() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 2,
+chargeback, 1, 2,
+";
+    let reverse = "type, client, tx, amount\nreverse-chargeback, 1, 2,";
+
+    // ordinary batches can't reverse chargebacks
+    let mut env = Env::new();
+    env.process(data);
+    let tx = read_txs(reverse).pop().unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::AdminOnly));
+    assert!(env.acc(1).locked);
+    assert_eq!(env.acc(1).total, 10 * SCALE);
+
+    let mut env = Env::with_engine(Engine::new().with_admin_ops(true));
+    env.process(data);
+    env.process(reverse);
+    let acc = env.acc(1);
+    assert!(!acc.locked);
+    assert_eq!((acc.total, acc.held), (15 * SCALE, units(0)));
+    // the transaction is executed again, so it can be disputed and charged back anew
+    env.process("type, client, tx, amount\ndispute, 1, 2,\nchargeback, 1, 2,");
+    let acc = env.acc(1);
+    assert!(acc.locked);
+    assert_eq!((acc.total, acc.held), (10 * SCALE, units(0)));
+    env.process(reverse);
+    assert_eq!(env.acc(1).total, 15 * SCALE);
+
+    // only charged back transactions are reinstated
+    let tx = read_txs(reverse).pop().unwrap();
+    assert_eq!(
+        env.process_tx(tx),
+        Err(EngineError::InvalidState(State::Executed))
+    );
+    env.process("type, client, tx, amount\ndispute, 1, 1,");
+    let tx = Transaction::builder().reverse_chargeback(1, 1);
+    assert_eq!(
+        env.process_tx(tx),
+        Err(EngineError::InvalidState(State::Disputed))
+    );
+    let tx = Transaction::builder().reverse_chargeback(1, 9);
+    assert_eq!(env.process_tx(tx), Err(EngineError::TxNotFound));
+}
+
+#[test]
+fn partial_and_withdrawal_chargebacks_are_reversed() {
+    let mut env = Env::with_engine(
+        Engine::new()
+            .with_admin_ops(true)
+            .with_partial_disputes(true),
+    );
+    env.process(
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10
+dispute, 1, 1, 4
+chargeback, 1, 1,
+deposit, 2, 2, 10
+withdrawal, 2, 3, 6
+dispute, 2, 3,
+chargeback, 2, 3,
+",
+    );
+    assert_eq!(env.acc(1).total, 6 * SCALE);
+    assert_eq!(env.acc(2).total, 10 * SCALE);
+
+    // only the part charged back is credited again
+    env.process("type, client, tx, amount\nreverse-chargeback, 1, 1,");
+    let acc = env.acc(1);
+    assert!(!acc.locked);
+    assert_eq!(acc.total, 10 * SCALE);
+
+    // the amount kept by the client for a withdrawal is debited again
+    env.process("type, client, tx, amount\nreverse-chargeback, 2, 3,");
+    let acc = env.acc(2);
+    assert!(!acc.locked);
+    assert_eq!(acc.total, 4 * SCALE);
+}
+
+#[test]
+fn adjustments_are_exported() {
+    let mut env = Env::new();
+    env.process("type, client, tx, amount\ndeposit, 1, 1, 10");
+    env.engine.adjust(2, 1, 25_000, "goodwill").unwrap();
+    env.engine.adjust(3, 2, 0, "no-op").unwrap();
+    env.engine.adjust(4, 1, -5_000, "fee, reversed").unwrap();
+
+    let mut out = vec![];
+    env.engine.export_transactions(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+type,client,tx,amount,reason
+deposit,1,1,10,
+adjustment,1,2,2.5,goodwill
+adjustment,2,3,0,no-op
+adjustment,1,4,-0.5,\"fee, reversed\"
+"
+    );
+}
+
+#[test]
+fn ndjson_output_works() {
+    let engine = Engine::new()
+        .with_format(OutputFormat::Ndjson)
+        .with_grouping(LockGrouping::UnlockedFirst);
+    let mut env = Env::with_engine(engine);
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 2, 2, 10
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 3, 3, 0.0001
+";
+    env.process(data);
+
+    let mut out = vec![];
+    env.engine.write_accounts(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let accounts = out
+        .lines()
+        .map(|l| Account::from(serde_json::from_str::<AccountSer>(l).unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        accounts,
+        vec![
+            Account {
+                id: 1,
+                total: units(15_000),
+                ..Default::default()
+            },
+            Account {
+                id: 3,
+                total: units(1),
+                ..Default::default()
+            },
+            Account {
+                id: 2,
+                locked: true,
+                ..Default::default()
+            },
+        ]
+    );
+    assert!(out.ends_with('\n'));
+}
+
+#[test]
+fn json_output_works() {
+    let mut engine = Engine::new().with_grouping(LockGrouping::UnlockedFirst);
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 2, 2, 10
+dispute, 2, 2,
+chargeback, 2, 2,
+";
+    let mut out = vec![];
+    engine
+        .run_reader_with_format(data.as_bytes(), &mut out, OutputFormat::Json)
+        .unwrap();
+    let json = serde_json::from_slice::<serde_json::Value>(&out).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!([
+            {"client": 1, "available": "1.5", "held": "0", "total": "1.5", "locked": false},
+            {"client": 2, "available": "0", "held": "0", "total": "0", "locked": true},
+        ])
+    );
+    // configured format is left as is
+    out.clear();
+    engine.write_accounts(&mut out).unwrap();
+    assert!(out.starts_with(b"client,available,held,total,locked\n"));
+}
+
+#[test]
+fn sql_output_works() {
+    let data = "\
+type, client, tx, amount
+deposit, 1, 1, 1.5
+deposit, 2, 2, 10
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 3, 3, 0.0001
+dispute, 3, 3,
+";
+    let mut engine = Engine::new().with_format(OutputFormat::Sql);
+    let mut out = vec![];
+    engine.run_reader(data.as_bytes(), &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\
+INSERT INTO accounts (client, available, held, total, locked) VALUES (1, '1.5', '0', '1.5', FALSE);
+INSERT INTO accounts (client, available, held, total, locked) VALUES (2, '0', '0', '0', TRUE);
+INSERT INTO accounts (client, available, held, total, locked) VALUES (3, '0', '0.0001', '0.0001', FALSE);
+"
+    );
+
+    // table names other than plain identifiers are quoted, subtotals have no client
+    let engine = engine
+        .with_sql_table(r#"public.my "accounts""#)
+        .with_held_breakdown(true)
+        .with_currency_subtotals(true);
+    let mut out = vec![];
+    engine.write_accounts(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(
+        out.lines().last(),
+        Some(
+            r#"INSERT INTO public."my ""accounts""" (client, available, held, held_dispute, held_manual, held_fraud, total, locked) VALUES (NULL, '1.5', '0.0001', '0.0001', '0', '0', '1.5001', FALSE);"#
+        )
+    );
+}
+
+#[test]
+fn stream_reports_updated_accounts() {
+    let data = "\
+type, client, tx, amount, target
+deposit, 1, 1, 10,
+deposit, 2, 2, 1,
+withdrawal, 2, 3, 5,
+transfer, 1, 4, 4, 2
+dispute, 2, 2,
+resolve, 2, 2,
+";
+    let mut engine = Engine::new();
+    let mut updates = vec![];
+    let summary = engine.process_stream(data.as_bytes(), |a| updates.push((a.id, a.total)));
+    assert_eq!((summary.processed, summary.declined), (5, 1));
+    // declined withdrawal is not reported, transfer is for both accounts
+    assert_eq!(
+        updates,
+        vec![
+            (1, 10 * SCALE),
+            (2, SCALE),
+            (1, 6 * SCALE),
+            (2, 5 * SCALE),
+            (2, 5 * SCALE),
+            (2, 5 * SCALE),
+        ]
+    );
+}
+
+#[test]
+fn stream_reports_accounts_in_their_currency() {
+    let data = "\
+type, client, tx, amount, target, currency
+deposit, 1, 1, 10,,
+deposit, 1, 2, 3,, EUR
+transfer, 1, 3, 1, 2, EUR
+";
+    let mut engine = Engine::new();
+    let mut updates = vec![];
+    let summary = engine.process_stream(data.as_bytes(), |a| updates.push((a.id, a.total)));
+    assert_eq!((summary.processed, summary.declined), (3, 0));
+    // the EUR accounts, not the ones in the implicit currency, or none
+    assert_eq!(
+        updates,
+        vec![(1, 10 * SCALE), (1, 3 * SCALE), (1, 2 * SCALE), (2, SCALE)]
+    );
+}
+
+#[test]
+fn transfers_work() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount, target
+deposit, 1, 1, 10,
+deposit, 2, 2, 1,
+transfer, 1, 3, 4, 2
+transfer, 1, 4, 2, 3
+";
+    env.process(data);
+    assert_eq!(
+        env.balances(),
+        vec![(1, 4 * SCALE), (2, 5 * SCALE), (3, 2 * SCALE)]
+    );
+
+    // insufficient funds leave both accounts intact
+    let tx = read_txs("type, client, tx, amount, target\ntransfer, 1, 5, 4.0001, 2")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::InsufficientFunds));
+    assert_eq!(
+        env.balances(),
+        vec![(1, 4 * SCALE), (2, 5 * SCALE), (3, 2 * SCALE)]
+    );
+
+    // target is required, and is not the client itself
+    let tx = read_txs("type, client, tx, amount, target\ntransfer, 1, 5, 1, 1")
+        .pop()
+        .unwrap();
+    assert_eq!(env.process_tx(tx), Err(EngineError::SelfTransfer));
+    let mut rdr = csv::Reader::from_reader("type,client,tx,amount\ntransfer,1,5,1".as_bytes());
+    let record: Record = rdr.deserialize().next().unwrap().unwrap();
+    assert_eq!(
+        Transaction::try_from(record).unwrap_err(),
+        EngineError::MissingField("target")
+    );
+
+    // disputed like a withdrawal of the client
+    env.process("type, client, tx, amount\ndispute, 1, 3,");
+    assert_eq!(env.acc(1).total, 8 * SCALE);
+    env.process("type, client, tx, amount\nresolve, 1, 3,");
+    assert_eq!(env.acc(1).total, 4 * SCALE);
+    assert_eq!(env.tx_count(), 4);
+}
+
+#[test]
+fn transfers_involving_frozen_accounts_are_declined() {
+    let mut env = Env::new();
+    let data = "\
+type, client, tx, amount, target
+deposit, 1, 1, 10,
+deposit, 2, 2, 5,
+deposit, 2, 3, 1,
+dispute, 2, 3,
+chargeback, 2, 3,
+";
+    env.process(data);
+    assert!(env.acc(2).locked);
+
+    // either side being frozen declines the whole transfer
+    let txs =
+        read_txs("type, client, tx, amount, target\ntransfer, 1, 4, 1, 2\ntransfer, 2, 5, 1, 1");
+    for tx in txs {
+        assert_eq!(env.process_tx(tx), Err(EngineError::AccountFrozen));
+    }
+    assert_eq!(env.balances(), vec![(1, 10 * SCALE), (2, 5 * SCALE)]);
+    assert_eq!(env.tx_count(), 3);
+}
+
+#[cfg(test)]
+mod test_utils {
+    use super::*;
+    use csv::ReaderBuilder;
+    use std::sync::{Mutex, Once};
+
+    #[derive(Debug)]
+    pub struct Env {
+        pub engine: Engine,
+    }
+
+    impl Env {
+        pub fn new() -> Self {
+            Env {
+                engine: Engine::new(),
+            }
+        }
+
+        pub fn with_engine(engine: Engine) -> Self {
+            Env { engine }
+        }
+
+        pub fn process(&mut self, data: &str) {
+            for t in read_txs(data) {
+                if let Err(e) = self.process_tx(t) {
+                    println!("ERR: {e}")
+                }
+            }
+        }
+
+        pub fn process_tx(&mut self, tx: Transaction) -> Result<(), EngineError> {
+            self.engine.process(tx)
+        }
+
+        pub fn tx_count(&self) -> usize {
+            self.engine.transactions().len()
+        }
+
+        pub fn acc(&self, id: u32) -> Account {
+            *self
+                .engine
+                .get_account(&id)
+                .expect("account should have been created")
+        }
+
+        pub fn balances(&self) -> Vec<(u32, Amount)> {
+            let mut balances = self
+                .engine
+                .accounts()
+                .map(|v| (v.id, v.total))
+                .collect::<Vec<_>>();
+
+            balances.sort_by_key(|a| a.0);
+            balances
+        }
+    }
+
+    /// Returns amount of `n` units of the engine scale, e.g. 1.5 of `units(15_000)`,
+    /// for the tests to run with the amounts of `decimal` feature as well.
+    pub fn units(n: u64) -> Amount {
+        Amount::from(n)
+    }
+
+    /// Returns signed balance of `n` units of the engine scale, as `units()` does.
+    pub fn signed_units(n: i64) -> SignedAmount {
+        SignedAmount::from(n)
+    }
+
+    pub fn read_txs(csv: &str) -> Vec<Transaction> {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(csv.as_bytes());
+        let mut txs = vec![];
+        for entry in rdr.deserialize().flatten() {
+            let mut tx: Transaction = entry;
+            let s = TxStateKind::Received;
+            if tx.init(s).is_ok() {
+                txs.push(tx)
+            }
+        }
+        txs
+    }
+
+    static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Logger capturing all records into memory.
+    struct CaptureLog;
+
+    impl log::Log for CaptureLog {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGS.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    pub fn init_log_capture() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLog).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    /// Returns captured log records starting with the `prefix`.
+    /// As tests run in parallel, records of other tests are mixed in.
+    pub fn captured_logs(prefix: &str) -> Vec<String> {
+        LOGS.lock()
+            .unwrap()
+            .iter()
+            .filter(|l| l.starts_with(prefix))
+            .cloned()
+            .collect()
     }
 }
@@ -1,12 +1,25 @@
-use serde::{Deserialize, Deserializer};
+use csv::StringRecord;
+use serde::de::value::StrDeserializer;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_json::value::RawValue;
+use std::borrow::Cow;
 use std::fmt::Debug;
+use std::time::SystemTime;
+
+use crate::account::fmt_scaled_amount;
+use crate::error::{AmountError, EngineError};
 
 /// Types of transactions.
-/// We call first two _transactions_, as we store them into engine,
-/// and we call other three _events_, as they change state of
+/// We call the money-moving ones _transactions_, as we store them into engine,
+/// and we call disputes, resolves and chargebacks _events_, as they change state of
 /// transactions happened before.
-#[derive(Debug, serde::Deserialize, serde::Serialize, Copy, Clone)]
-#[serde(rename_all = "lowercase")]
+/// Serialized as lowercase names, as in the input, types of other names being read
+/// as `Unknown` ones rather than failing the rows; names of other than ASCII
+/// alphanumerics, `-` and `_`, e.g. of comment lines, fail them still.
+/// More types are to come, so matches on them out of the crate are to have
+/// a catch-all arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Tx {
     /// Credit to client account, increases its available (and therefore total) balance.
     /// This is a money-moving _transaction_.
@@ -18,7 +31,8 @@ pub enum Tx {
     /// The transaction disputed is the one specified by its ID in the corresponding csv line.
     /// Therefore a dispute does not has its own transaction ID.
     /// This should result in hold of the amount of the corresponding transaction
-    /// on the client's account.
+    /// on the client's account. For a disputed withdrawal, its amount is credited back
+    /// to the account instead, available pending the resolution.
     /// This is an _event_.
     Dispute,
     /// Resolution to a dispute, which is specified by ID of the transaction being disputed.
@@ -28,14 +42,345 @@ pub enum Tx {
     /// This is an _event_.
     /// not
     Chargeback,
+    /// Manual correction of the client's total balance by a signed amount, e.g. interest
+    /// or a fee, made by support team via `Engine::adjust()`, which may draw on the
+    /// overdraft, but not beyond its limit. It can't be disputed.
+    /// Declined if it comes from the input, unless admin operations are allowed
+    /// with `Engine::with_admin_ops(true)`.
+    /// This is a money-moving _transaction_.
+    Adjustment,
+    /// Debit to client account along with credit of the same amount to the `target`
+    /// client account, applied as a whole or not at all.
+    /// When disputed, it is treated as a withdrawal from the client account.
+    /// This is a money-moving _transaction_.
+    Transfer,
+    /// Unfreezing of the client account, e.g. locked by an erroneous chargeback,
+    /// made by support team. Declined unless the engine is set up with admin operations.
+    /// It moves no money, and is not stored.
+    Unlock,
+    /// Reversal of a chargeback, e.g. an erroneous one, made by support team, which is
+    /// specified by ID of the transaction charged back. It is executed again, and the
+    /// account gets unfrozen. Declined unless the engine is set up with admin operations.
+    /// This is an _event_.
+    ReverseChargeback,
+    /// Type of a name not known to the engine, e.g. one introduced by a partner
+    /// ahead of it, declined with `EngineError::UnknownType`.
+    Unknown(String),
+}
+
+impl Tx {
+    /// Whether this is an _event_, i.e. it refers to an earlier transaction.
+    pub fn is_event(&self) -> bool {
+        matches!(
+            self,
+            Tx::Dispute | Tx::Resolve | Tx::Chargeback | Tx::ReverseChargeback
+        )
+    }
+
+    /// Returns the type of the name, as in the input, `Unknown` if there is none.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "deposit" => Tx::Deposit,
+            "withdrawal" => Tx::Withdrawal,
+            "dispute" => Tx::Dispute,
+            "resolve" => Tx::Resolve,
+            "chargeback" => Tx::Chargeback,
+            "adjustment" => Tx::Adjustment,
+            "transfer" => Tx::Transfer,
+            "unlock" => Tx::Unlock,
+            "reverse-chargeback" => Tx::ReverseChargeback,
+            _ => Tx::Unknown(name.to_string()),
+        }
+    }
+
+    /// Returns name of the type, as in the input.
+    pub fn name(&self) -> &str {
+        match self {
+            Tx::Deposit => "deposit",
+            Tx::Withdrawal => "withdrawal",
+            Tx::Dispute => "dispute",
+            Tx::Resolve => "resolve",
+            Tx::Chargeback => "chargeback",
+            Tx::Adjustment => "adjustment",
+            Tx::Transfer => "transfer",
+            Tx::Unlock => "unlock",
+            Tx::ReverseChargeback => "reverse-chargeback",
+            Tx::Unknown(name) => name,
+        }
+    }
+}
+
+impl serde::Serialize for Tx {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Tx {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl serde::de::Visitor<'_> for NameVisitor {
+            type Value = Tx;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("transaction type name")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Tx, E> {
+                let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+                match !name.is_empty() && name.chars().all(valid) {
+                    true => Ok(Tx::from_name(name)),
+                    false => Err(E::invalid_value(serde::de::Unexpected::Str(name), &self)),
+                }
+            }
+        }
+
+        de.deserialize_str(NameVisitor)
+    }
+}
+
+/// Transaction record, as read from input.
+/// All the fields are optional here, for missing ones to be detected
+/// instead of being defaulted.
+#[derive(Debug)]
+pub struct Record {
+    pub ty: Option<Tx>,
+    pub client: Option<u32>,
+    pub tx: Option<u32>,
+    pub amount: Option<Amount>,
+    /// Client credited by a transfer, blank for other types.
+    pub target: Option<u32>,
+    /// Currency of the transaction, blank for the implicit one.
+    pub currency: Option<String>,
+    /// State of a serialized transaction, `None` for an input one.
+    pub state: Option<TxStateKind>,
+    /// Whether the amount is negative, as only the one of an adjustment may be,
+    /// debiting the account then.
+    pub debit: bool,
+}
+
+/// Transaction record as deserialized, with the amount left unparsed,
+/// for its sign to be told by the type.
+#[derive(Debug, serde::Deserialize)]
+struct RecordDe<'a> {
+    #[serde(rename = "type")]
+    ty: Option<Tx>,
+    client: Option<u32>,
+    tx: Option<u32>,
+    #[serde(default, borrow, deserialize_with = "deser_amount")]
+    amount: Option<&'a str>,
+    #[serde(default)]
+    target: Option<u32>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    state: Option<TxStateKind>,
+}
+
+impl<'de> Deserialize<'de> for Record {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let r = RecordDe::deserialize(de)?;
+        let (debit, amount) = split_debit(r.ty.as_ref(), r.amount);
+        Ok(Record {
+            ty: r.ty,
+            client: r.client,
+            tx: r.tx,
+            amount: amount.and_then(parse_amount),
+            target: r.target,
+            currency: r.currency,
+            state: r.state,
+            debit,
+        })
+    }
+}
+
+/// Splits the sign off the amount of an adjustment, the only one which may be
+/// negative, telling whether it debits the account. Other amounts are left as they are,
+/// a negative one being malformed.
+fn split_debit<'a>(ty: Option<&Tx>, amount: Option<&'a str>) -> (bool, Option<&'a str>) {
+    match amount.and_then(|s| s.strip_prefix('-')) {
+        Some(abs) if ty == Some(&Tx::Adjustment) => (true, Some(abs)),
+        _ => (false, amount),
+    }
+}
+
+/// Transaction record with the amount left unparsed, for an `AmountParser` to handle it.
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct RawRecord<'a> {
+    #[serde(rename = "type")]
+    ty: Option<Tx>,
+    client: Option<u32>,
+    tx: Option<u32>,
+    #[serde(default)]
+    amount: Option<&'a str>,
+    #[serde(default)]
+    target: Option<u32>,
+    #[serde(default)]
+    currency: Option<&'a str>,
+}
+
+impl RawRecord<'_> {
+    /// Makes a record, parsing its amount with the `parser`.
+    /// Malformed amount is dropped, as it is in [`Record`] deserialization,
+    /// and so is a negative one, unless it's the one of an adjustment.
+    pub(crate) fn parse_with(self, parser: &dyn AmountParser) -> Record {
+        let (debit, amount) = split_debit(self.ty.as_ref(), self.amount);
+        Record {
+            ty: self.ty,
+            client: self.client,
+            tx: self.tx,
+            amount: amount.and_then(|s| parser.parse(s).ok()),
+            target: self.target,
+            currency: self.currency.filter(|c| !c.is_empty()).map(String::from),
+            state: None,
+            debit,
+        }
+    }
+}
+
+/// Names of the input columns of the fields of transaction records, for inputs
+/// of non-standard headers, e.g. `txn_type, account, id, value`, see
+/// `Engine::with_column_map()`. Ones not set keep their standard names, as in
+/// `ColumnMap { ty: "txn_type".into(), ..Default::default() }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnMap {
+    pub ty: String,
+    pub client: String,
+    pub tx: String,
+    pub amount: String,
+    /// Column of the client credited by a transfer.
+    pub target: String,
+    /// Column of the currency of the transaction.
+    pub currency: String,
 }
 
+impl Default for ColumnMap {
+    fn default() -> Self {
+        ColumnMap {
+            ty: "type".into(),
+            client: "client".into(),
+            tx: "tx".into(),
+            amount: "amount".into(),
+            target: "target".into(),
+            currency: "currency".into(),
+        }
+    }
+}
+
+impl ColumnMap {
+    /// Makes the map of the four mandatory fields, `target` and `currency` keeping
+    /// their standard names.
+    pub fn new(ty: &str, client: &str, tx: &str, amount: &str) -> Self {
+        ColumnMap {
+            ty: ty.into(),
+            client: client.into(),
+            tx: tx.into(),
+            amount: amount.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns positions of the fields in the rows under the `headers`.
+    pub(crate) fn index(&self, headers: &StringRecord) -> ColumnIndex {
+        let column = |name: &str| headers.iter().position(|h| h == name);
+        ColumnIndex {
+            ty: column(&self.ty),
+            client: column(&self.client),
+            tx: column(&self.tx),
+            amount: column(&self.amount),
+            target: column(&self.target),
+            currency: column(&self.currency),
+        }
+    }
+}
+
+/// Positions of the fields of transaction records in the input rows,
+/// `None` for the ones missing in the header.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ColumnIndex {
+    pub(crate) ty: Option<usize>,
+    pub(crate) client: Option<usize>,
+    pub(crate) tx: Option<usize>,
+    amount: Option<usize>,
+    target: Option<usize>,
+    currency: Option<usize>,
+}
+
+impl ColumnIndex {
+    /// Returns the field of the row, `None` if it's missing or blank.
+    pub(crate) fn field<'a>(&self, row: &'a StringRecord, i: Option<usize>) -> Option<&'a str> {
+        i.and_then(|i| row.get(i)).filter(|f| !f.is_empty())
+    }
+
+    /// Makes a record of the row by the positions of its fields, as deserialization
+    /// makes it by the names of the columns: missing and blank fields are `None`,
+    /// and `None` is returned if any of the present ones is invalid.
+    pub(crate) fn record<'a>(&self, row: &'a StringRecord) -> Option<RawRecord<'a>> {
+        fn id(field: Option<&str>) -> Option<Option<u32>> {
+            field.map(str::parse).transpose().ok()
+        }
+        let ty = self
+            .field(row, self.ty)
+            .map(|ty| Tx::deserialize(StrDeserializer::<serde::de::value::Error>::new(ty)))
+            .transpose()
+            .ok()?;
+        Some(RawRecord {
+            ty,
+            client: id(self.field(row, self.client))?,
+            tx: id(self.field(row, self.tx))?,
+            amount: self.field(row, self.amount),
+            target: id(self.field(row, self.target))?,
+            currency: self.field(row, self.currency),
+        })
+    }
+}
+
+/// Transaction record as read from an NDJSON line, with the amount left unparsed:
+/// either a JSON string or a number, taken as it's written.
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct JsonRecord<'a> {
+    #[serde(rename = "type")]
+    ty: Option<Tx>,
+    client: Option<u32>,
+    tx: Option<u32>,
+    #[serde(default, borrow)]
+    amount: Option<&'a RawValue>,
+    #[serde(default)]
+    target: Option<u32>,
+    #[serde(default, borrow)]
+    currency: Option<Cow<'a, str>>,
+}
+
+impl JsonRecord<'_> {
+    /// Makes a record, parsing its amount with the `parser`, as `RawRecord` does.
+    pub(crate) fn parse_with(self, parser: &dyn AmountParser) -> Record {
+        let amount = self.amount.map(|raw| {
+            let raw = raw.get();
+            serde_json::from_str::<&str>(raw).unwrap_or(raw)
+        });
+        RawRecord {
+            ty: self.ty,
+            client: self.client,
+            tx: self.tx,
+            amount,
+            target: self.target,
+            currency: self.currency.as_deref(),
+        }
+        .parse_with(parser)
+    }
+}
+
+/// Key of a stored transaction: client and transaction IDs.
+pub type TxKey = (u32, u32);
+
 /// Client transaction.
 /// Implemented as a simple state machine.
 #[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "Record")]
 pub struct Transaction {
     /// Transaction ID, unique, one per client.
-    #[serde(rename = "tx", default)]
+    #[serde(rename = "tx")]
     pub id: u32,
     /// Transaction type.
     #[serde(rename = "type")]
@@ -46,40 +391,256 @@ pub struct Transaction {
     /// We store balances as integers for simpler operations,
     /// as only precision to 10^-4 needed,
     /// we store it as <amount>*10^4.
-    /// This allows dealing with balances up to ~1.84 quadrillion (`u64::MAX/10^4`),
-    /// which should be quite enough.
-    #[serde(default, deserialize_with = "deser_amount")]
-    pub amount: Option<u64>,
-    /// Transaction state.
+    /// This allows dealing with balances up to ~1.84 quadrillion (`MAX_DECIMAL`),
+    /// which should be quite enough. Engines of other precision store it
+    /// as <amount>*10^DIGITS, with the ceiling lowered or raised accordingly.
+    /// Serialized as a decimal, as it is read.
+    #[serde(serialize_with = "ser_amount")]
+    pub amount: Option<Amount>,
+    /// ID of the client Account credited by a transfer, `None` for other types.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<u32>,
+    /// Currency of the accounts the transaction applies to, `None` for the implicit
+    /// one; events apply only to transactions of their currency.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    /// Direction and reason of a manual adjustment, `None` for other types.
+    #[serde(skip)]
+    pub adjustment: Option<Box<Adjustment>>,
+    /// Portion of the amount under dispute, held or credited back,
+    /// `None` unless disputed.
+    #[serde(skip)]
+    pub disputed: Option<Amount>,
+    /// Transaction state, serialized for a deserialized transaction to come back in it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) state: Option<TxStateKind>,
+    /// Position in the sequence of transactions processed by the engine,
+    /// set once it's stored, for its age to be told.
     #[serde(skip)]
-    state: Option<Box<dyn TxState + 'static>>,
+    pub seq: u64,
+    /// Time it was stored at, tracked if disputes expire in time.
+    #[serde(skip)]
+    pub at: Option<SystemTime>,
+}
+
+/// Helper struct for stored transactions export, with amounts of `DIGITS` decimal digits.
+#[derive(Debug, serde::Serialize)]
+pub struct TransactionSer<'a, const DIGITS: u32 = DECIMALS> {
+    #[serde(rename = "type")]
+    ty: Option<Tx>,
+    client: u32,
+    tx: u32,
+    /// Formatted amount, negative for debiting adjustments.
+    amount: String,
+    /// Reason of an adjustment, empty for other types.
+    reason: &'a str,
+}
+
+impl<'a, const DIGITS: u32> From<&'a Transaction> for TransactionSer<'a, DIGITS> {
+    fn from(tx: &'a Transaction) -> Self {
+        let sign = match &tx.adjustment {
+            Some(adj) if adj.debit => "-",
+            _ => "",
+        };
+        TransactionSer {
+            ty: tx.ty.clone(),
+            client: tx.client,
+            tx: tx.id,
+            amount: tx
+                .amount
+                .map(|a| format!("{}{}", sign, fmt_scaled_amount(a, DIGITS)))
+                .unwrap_or_default(),
+            reason: tx.adjustment.as_ref().map_or("", |adj| &adj.reason),
+        }
+    }
+}
+
+/// Declined transaction, as written out for resubmission, along with the reason.
+/// Columns are the ones of the input, so that the rows could be fixed and fed back
+/// as they are, the trailing `reason` being ignored then.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Reject {
+    #[serde(rename = "type")]
+    pub ty: Option<Tx>,
+    pub client: Option<u32>,
+    pub tx: Option<u32>,
+    /// Formatted amount, empty if there is none.
+    pub amount: String,
+    pub target: Option<u32>,
+    /// Currency, written out only if any of the rejects has one, see `write_rejects()`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    pub reason: String,
+}
+
+impl Reject {
+    /// Makes reject of the transaction, with amounts of the given number of decimal digits,
+    /// and the reason to be set with `because()`.
+    pub fn of(tx: &Transaction, decimals: u32) -> Self {
+        Reject {
+            ty: tx.ty.clone(),
+            client: Some(tx.client),
+            tx: Some(tx.id),
+            amount: tx
+                .amount
+                .map(|a| fmt_scaled_amount(a, decimals))
+                .unwrap_or_default(),
+            target: tx.target,
+            currency: tx.currency.clone(),
+            reason: String::new(),
+        }
+    }
+
+    /// Makes reject of the record, which didn't make a transaction,
+    /// with amounts of the given number of decimal digits.
+    pub fn of_record(r: &Record, decimals: u32) -> Self {
+        let sign = if r.debit { "-" } else { "" };
+        Reject {
+            ty: r.ty.clone(),
+            client: r.client,
+            tx: r.tx,
+            amount: r
+                .amount
+                .map(|a| format!("{}{}", sign, fmt_scaled_amount(a, decimals)))
+                .unwrap_or_default(),
+            target: r.target,
+            currency: r.currency.clone(),
+            reason: String::new(),
+        }
+    }
+
+    /// Sets the reason of the reject to the error.
+    pub fn because(self, e: &EngineError) -> Self {
+        Reject {
+            reason: e.to_string(),
+            ..self
+        }
+    }
+}
+
+/// Details of a manual adjustment, recorded for the audit.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Adjustment {
+    /// Whether the adjustment debits the account, rather than credits it.
+    pub debit: bool,
+    /// Reason of the correction.
+    pub reason: String,
+}
+
+/// Fails on records missing any of the required fields: type, client, and tx,
+/// as well as on transfers missing the target. Adjustments carry their direction,
+/// with no reason given.
+impl TryFrom<Record> for Transaction {
+    type Error = EngineError;
+
+    fn try_from(r: Record) -> Result<Self, Self::Error> {
+        let ty = r.ty.ok_or(EngineError::MissingField("type"))?;
+        let client = r.client.ok_or(EngineError::MissingField("client"))?;
+        let id = r.tx.ok_or(EngineError::MissingField("tx"))?;
+        let target = match ty {
+            Tx::Transfer => Some(r.target.ok_or(EngineError::MissingField("target"))?),
+            _ => None,
+        };
+        let adjustment = (ty == Tx::Adjustment).then(|| {
+            Box::new(Adjustment {
+                debit: r.debit,
+                reason: String::new(),
+            })
+        });
+        Ok(Transaction {
+            ty: Some(ty),
+            client,
+            id,
+            amount: r.amount,
+            target,
+            currency: r.currency.filter(|c| !c.is_empty()),
+            adjustment,
+            state: r.state,
+            seq: 0,
+            at: None,
+            disputed: None,
+        })
+    }
 }
 
 macro_rules! declare_transitions {
     ($($transition:ident),+) => {
             $(
             pub fn $transition(&mut self) {
-                if let Some(s) = self.state.take() {
-                    self.state = Some(s.$transition())
-                }
+                self.state = self.state.map(TxStateKind::$transition)
             }
            )+
     };
 }
 
-// Transaction state objects.
-#[derive(Default, Debug)]
-pub struct Received;
-#[derive(Debug)]
-pub struct Executed;
-#[derive(Debug)]
-pub struct Disputed;
-#[derive(Debug)]
-pub struct Reverted;
+/// Builder of transactions initialized as received, with amounts of `DIGITS` decimal
+/// digits parsed from decimals as in the input, see `Transaction::builder()`.
+/// Deposits, withdrawals and transfers fail with `EngineError::EmptyAmount`
+/// if the amount is not a valid one, or zero, as read ones are declined.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TransactionBuilder<const DIGITS: u32 = DECIMALS>;
 
-/// Used by state objects to return their state to caller.
-/// (This is done as an alternative to downcasting `<dyn TxState>`).
-#[derive(Debug)]
+impl<const DIGITS: u32> TransactionBuilder<DIGITS> {
+    pub fn deposit(self, client: u32, tx: u32, amount: &str) -> Result<Transaction, EngineError> {
+        self.funds(Tx::Deposit, client, tx, amount)
+    }
+
+    pub fn withdrawal(
+        self,
+        client: u32,
+        tx: u32,
+        amount: &str,
+    ) -> Result<Transaction, EngineError> {
+        self.funds(Tx::Withdrawal, client, tx, amount)
+    }
+
+    /// Makes transfer of the amount from the `client` to the `target` one.
+    pub fn transfer(
+        self,
+        client: u32,
+        tx: u32,
+        amount: &str,
+        target: u32,
+    ) -> Result<Transaction, EngineError> {
+        let mut transfer = self.funds(Tx::Transfer, client, tx, amount)?;
+        transfer.target = Some(target);
+        Ok(transfer)
+    }
+
+    /// Makes dispute of the whole `tx` transaction of the client.
+    pub fn dispute(self, client: u32, tx: u32) -> Transaction {
+        Self::event(Tx::Dispute, client, tx)
+    }
+
+    pub fn resolve(self, client: u32, tx: u32) -> Transaction {
+        Self::event(Tx::Resolve, client, tx)
+    }
+
+    pub fn chargeback(self, client: u32, tx: u32) -> Transaction {
+        Self::event(Tx::Chargeback, client, tx)
+    }
+
+    pub fn reverse_chargeback(self, client: u32, tx: u32) -> Transaction {
+        Self::event(Tx::ReverseChargeback, client, tx)
+    }
+
+    fn funds(self, ty: Tx, client: u32, id: u32, amount: &str) -> Result<Transaction, EngineError> {
+        let mut tx = Transaction::new(ty, client, id, parse_scaled_amount(amount, DIGITS));
+        tx.init(TxStateKind::Received)?;
+        Ok(tx)
+    }
+
+    fn event(ty: Tx, client: u32, id: u32) -> Transaction {
+        let mut tx = Transaction::new(ty, client, id, None);
+        // events of no amount are always valid
+        let _ = tx.init(TxStateKind::Received);
+        tx
+    }
+}
+
+/// Transaction state as reported to the caller,
+/// `Undefined` for a transaction not initialized yet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum State {
     Received,
     Executed,
@@ -89,120 +650,486 @@ pub enum State {
 }
 
 impl Transaction {
-    pub fn init(&mut self, state: Box<dyn TxState>) -> Result<(), String> {
+    /// Makes a transaction of the type, not initialized yet, as if it was read from input.
+    /// Events carry no amount, unless disputing a part of the transaction.
+    pub fn new(ty: Tx, client: u32, id: u32, amount: Option<Amount>) -> Self {
+        Transaction {
+            id,
+            ty: Some(ty),
+            client,
+            amount,
+            target: None,
+            currency: None,
+            adjustment: None,
+            state: None,
+            seq: 0,
+            at: None,
+            disputed: None,
+        }
+    }
+
+    /// Returns builder of transactions initialized as received, e.g. to be passed to
+    /// `Engine::process()`, with amounts given as decimals, as in the input.
+    pub fn builder() -> TransactionBuilder {
+        TransactionBuilder
+    }
+
+    /// Makes an executed manual adjustment of the client's balance by a signed `delta`.
+    pub fn adjustment(id: u32, client: u32, delta: i64, reason: String) -> Self {
+        Transaction {
+            id,
+            ty: Some(Tx::Adjustment),
+            client,
+            amount: Some(Amount::from(delta.unsigned_abs())),
+            target: None,
+            currency: None,
+            adjustment: Some(Box::new(Adjustment {
+                debit: delta < 0,
+                reason,
+            })),
+            state: Some(TxStateKind::Executed),
+            seq: 0,
+            at: None,
+            disputed: None,
+        }
+    }
+
+    /// Returns amount of the transaction signed by its direction for the client:
+    /// negative for withdrawals, transfers and debiting adjustments.
+    pub fn signed_amount(&self) -> Option<SignedAmount> {
+        let amount = signed(self.amount?);
+        match (&self.ty, &self.adjustment) {
+            (Some(Tx::Withdrawal | Tx::Transfer), _) => Some(-amount),
+            (_, Some(adj)) if adj.debit => Some(-amount),
+            _ => Some(amount),
+        }
+    }
+
+    /// Sets initial state of the transaction.
+    /// Fails for deposits, withdrawals and transfers with no or 0 amount, which are ignored.
+    /// Amounts of events are advisory, as they act on the transaction they refer to:
+    /// 0 is no amount, and only disputes keep theirs, for partial disputes.
+    pub fn init(&mut self, state: TxStateKind) -> Result<(), EngineError> {
         self.state = Some(state);
 
-        match self.ty {
-            Some(Tx::Deposit) | Some(Tx::Withdrawal) => match self.amount {
-                None | Some(0) => {
-                    Err(r"deposits\withdrawals with 0 amount are ignored".to_string())
+        match &self.ty {
+            Some(Tx::Deposit) | Some(Tx::Withdrawal) | Some(Tx::Transfer) => {
+                match self.amount.filter(|&a| a != 0) {
+                    None => Err(EngineError::EmptyAmount),
+                    _ => Ok(()),
                 }
-                _ => Ok(()),
-            },
+            }
+            Some(Tx::Dispute) => {
+                self.amount = self.amount.filter(|&a| a != 0);
+                Ok(())
+            }
+            Some(ty) if ty.is_event() => {
+                self.amount = None;
+                Ok(())
+            }
             _ => Ok(()),
         }
     }
 
+    /// Returns key the transaction is stored under: client and transaction IDs,
+    /// the latter being unique per client.
+    pub fn key(&self) -> TxKey {
+        (self.client, self.id)
+    }
+
     pub fn state(&self) -> State {
-        if let Some(s) = &self.state {
-            s.state()
-        } else {
-            State::Undefined
+        self.state.map_or(State::Undefined, TxStateKind::state)
+    }
+
+    declare_transitions!(execute, dispute, resolve, revert, reinstate);
+
+    /// Makes the `transition` and runs `f` against the transaction in its new state,
+    /// e.g. to apply the transition to the account. The state is rolled back
+    /// if `f` fails.
+    pub fn transition_with<T, E>(
+        &mut self,
+        transition: fn(&mut Self),
+        f: impl FnOnce(&Self) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let state = self.state;
+        transition(self);
+        let res = f(self);
+        if res.is_err() {
+            self.state = state;
         }
+        res
     }
+}
 
-    declare_transitions!(execute, dispute, resolve, revert);
+/// Transaction states, stored inline.
+/// Transitions map one state to another, falling back to the current state
+/// when the transition is not allowed from it. They are usable standalone,
+/// as `Transaction` applies them to its state.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxStateKind {
+    #[default]
+    Received,
+    Executed,
+    Disputed,
+    Reverted,
 }
 
-/// Interface for the state objects.
-pub trait TxState: std::fmt::Debug {
-    fn state(&self) -> State;
-    fn execute(self: Box<Self>) -> Box<dyn TxState>;
-    fn dispute(self: Box<Self>) -> Box<dyn TxState>;
-    fn resolve(self: Box<Self>) -> Box<dyn TxState>;
-    fn revert(self: Box<Self>) -> Box<dyn TxState>;
+impl TxStateKind {
+    /// Returns the state as reported to the caller.
+    pub fn state(self) -> State {
+        match self {
+            Self::Received => State::Received,
+            Self::Executed => State::Executed,
+            Self::Disputed => State::Disputed,
+            Self::Reverted => State::Reverted,
+        }
+    }
+
+    /// Executes a received transaction.
+    pub fn execute(self) -> Self {
+        match self {
+            Self::Received => Self::Executed,
+            s => s,
+        }
+    }
+
+    /// Disputes an executed transaction.
+    pub fn dispute(self) -> Self {
+        match self {
+            Self::Executed => Self::Disputed,
+            s => s,
+        }
+    }
+
+    /// Resolves a disputed transaction, making it executed again.
+    pub fn resolve(self) -> Self {
+        match self {
+            Self::Disputed => Self::Executed,
+            s => s,
+        }
+    }
+
+    /// Reverts a disputed transaction, which is what a chargeback does.
+    pub fn revert(self) -> Self {
+        match self {
+            Self::Disputed => Self::Reverted,
+            s => s,
+        }
+    }
+
+    /// Reinstates a reverted transaction, making it executed again,
+    /// which is what a chargeback reversal does.
+    pub fn reinstate(self) -> Self {
+        match self {
+            Self::Reverted => Self::Executed,
+            s => s,
+        }
+    }
 }
 
-macro_rules! impl_fallbacks {
-    ($($fallback:ident),+) => {
-            $(
-            fn $fallback(self: Box<Self>) ->  Box<dyn TxState> {
-                self
-            }
-           )+
-    };
+/// Integer type amounts and balances are stored in, scaled: `u64`,
+/// or `u128` with `bigamount` feature, for balances beyond `u64` ceiling.
+/// With `decimal` feature, which takes precedence, they are stored
+/// as `decimal::Amount` decimals, keeping all of their digits.
+#[cfg(not(any(feature = "bigamount", feature = "decimal")))]
+pub type Amount = u64;
+/// Integer type amounts and balances are stored in, `bigamount` one.
+#[cfg(all(feature = "bigamount", not(feature = "decimal")))]
+pub type Amount = u128;
+#[cfg(feature = "decimal")]
+pub use crate::decimal::Amount;
+
+/// Signed counterpart of `Amount`, for balances which may be negative: `i128`,
+/// or `Decimal` with `decimal` feature.
+#[cfg(not(feature = "decimal"))]
+pub type SignedAmount = i128;
+#[cfg(feature = "decimal")]
+pub type SignedAmount = rust_decimal::Decimal;
+
+/// Returns the amount as a signed one.
+/// Amounts beyond `i128::MAX`, possible with `bigamount` feature only, wrap around.
+#[cfg(not(feature = "decimal"))]
+pub fn signed(a: Amount) -> SignedAmount {
+    a as i128
+}
+#[cfg(feature = "decimal")]
+pub fn signed(a: Amount) -> SignedAmount {
+    a.to_decimal()
+}
+
+/// Number of decimal digits of the amounts, unless the engine is set up with another one,
+/// as in `Engine::<8>::default()`.
+pub const DECIMALS: u32 = 4;
+/// Scale of the amounts: we store them as integers equal to <amount>*10^4.
+pub const SCALE: Amount = scale(DECIMALS);
+/// Maximum amount supported, both for a single transaction and for a balance,
+/// in the scaled integer representation.
+/// It is what fits into `Amount`, which is what amounts and balances are stored in.
+pub const MAX_AMOUNT: Amount = Amount::MAX;
+/// Maximum amount supported, as a decimal.
+#[cfg(not(any(feature = "bigamount", feature = "decimal")))]
+pub const MAX_DECIMAL: &str = "1844674407370955.1615";
+#[cfg(all(feature = "bigamount", not(feature = "decimal")))]
+pub const MAX_DECIMAL: &str = "34028236692093846346337460743176821.1455";
+#[cfg(feature = "decimal")]
+pub const MAX_DECIMAL: &str = "7922816251426433759354395.0335";
+
+/// Returns scale of the amounts with the given number of decimal digits: 10^decimals.
+/// Up to 19 digits are supported, as 10^20 doesn't fit into `u64`,
+/// or 38 of them with `bigamount`, or 28 of them with `decimal`.
+#[cfg(not(feature = "decimal"))]
+pub const fn scale(decimals: u32) -> Amount {
+    Amount::pow(10, decimals)
+}
+#[cfg(feature = "decimal")]
+pub const fn scale(decimals: u32) -> Amount {
+    Amount::pow10(decimals)
+}
+
+/// Helper for amounts serialization, as decimals for them to be read back.
+fn ser_amount<S>(a: &Option<Amount>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match a {
+        Some(a) => serializer.serialize_some(&fmt_scaled_amount(*a, DECIMALS)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Helper for amounts deserialization, leaving the amount to be parsed
+/// into integer value = <amount>*10^4 once its sign is told.
+/// This allows balances up to ~1.84 quadrillion (`u64::MAX/10^4`),
+/// which should be quite enough, or ~3.4*10^34 with `bigamount`.
+/// If requested transaction balance is > `MAX_DECIMAL`,
+/// it's parsed to None then, as a non-string one is deserialized to None.
+fn deser_amount<'de, D>(de: D) -> Result<Option<&'de str>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<&str>::deserialize(de).unwrap_or(None))
+}
+
+/// Parser of the input amounts into integer value = <amount>*10^4,
+/// or whatever scale the engine is set up with,
+/// allowing for input formats other than plain decimals.
+pub trait AmountParser: Debug + Send + Sync {
+    fn parse(&self, s: &str) -> Result<Amount, AmountError>;
 }
 
-macro_rules! impl_state_getter {
-    ($state:ident) => {
-        fn state(&self) -> State {
-            State::$state
+/// Rounding of the amounts to the precision, when they have more digits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Drop the excess digits, rounding toward zero, e.g. `1.99999` to `1.9999`.
+    #[default]
+    Truncate,
+    /// Round to the nearest, ties away from zero, e.g. `1.23455` to `1.2346`.
+    HalfUp,
+    /// Round to the nearest, ties to the even last digit, e.g. `1.23455` to `1.2346`,
+    /// but `1.23465` to `1.2346` as well, not biased upwards on ties.
+    HalfEven,
+}
+
+impl RoundingMode {
+    /// Whether the digits kept, ending with the `last` one if any, are to be rounded up,
+    /// given the digits dropped after them.
+    fn rounds_up(self, last: Option<u8>, dropped: &str) -> bool {
+        let mut dropped = dropped.bytes();
+        let Some(first) = dropped.next() else {
+            return false;
+        };
+        match self {
+            RoundingMode::Truncate => false,
+            RoundingMode::HalfUp => first >= b'5',
+            RoundingMode::HalfEven => {
+                let odd = last.is_some_and(|d| (d - b'0') % 2 == 1);
+                first > b'5' || first == b'5' && (dropped.any(|d| d != b'0') || odd)
+            }
         }
-    };
+    }
+}
+
+/// Default amount parser, accepting ASCII decimals with `DIGITS` digits
+/// of precision, see `parse_rounded_amount()`, truncating the excess ones by default,
+/// or keeping them with `decimal` feature, see `parse_scaled_amount()`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DecimalParser<const DIGITS: u32 = DECIMALS> {
+    /// Rounding of the excess digits, if they are not to be treated by default.
+    pub rounding: Option<RoundingMode>,
 }
 
-impl TxState for Received {
-    fn execute(self: Box<Self>) -> Box<dyn TxState> {
-        Box::new(Executed)
+impl<const DIGITS: u32> AmountParser for DecimalParser<DIGITS> {
+    fn parse(&self, s: &str) -> Result<Amount, AmountError> {
+        match self.rounding {
+            Some(rounding) => parse_rounded_amount(s, DIGITS, rounding),
+            None => parse_scaled_amount(s, DIGITS),
+        }
+        .ok_or_else(|| AmountError(s.to_owned()))
     }
+}
 
-    impl_fallbacks!(dispute, resolve, revert);
-    impl_state_getter!(Received);
+/// Amount parser accepting integer counts of minor units, of `digits` decimal digits
+/// each, e.g. cents, the default, with no decimal parsing: `150` is `1.50` then.
+/// Counts which would be truncated at the engine scale of `DIGITS` are rejected.
+#[derive(Debug, Clone, Copy)]
+pub struct MinorUnitsParser<const DIGITS: u32 = DECIMALS> {
+    pub digits: u32,
 }
-impl TxState for Executed {
-    fn dispute(self: Box<Self>) -> Box<dyn TxState> {
-        Box::new(Disputed)
+
+impl<const DIGITS: u32> Default for MinorUnitsParser<DIGITS> {
+    fn default() -> Self {
+        MinorUnitsParser { digits: 2 }
     }
+}
 
-    impl_fallbacks!(execute, resolve, revert);
-    impl_state_getter!(Executed);
+impl<const DIGITS: u32> AmountParser for MinorUnitsParser<DIGITS> {
+    fn parse(&self, s: &str) -> Result<Amount, AmountError> {
+        let units = s.parse::<Amount>().ok();
+        match self.digits.checked_sub(DIGITS) {
+            None => units.and_then(|u| u.checked_mul(scale(DIGITS - self.digits))),
+            Some(excess) => units
+                .filter(|&u| u % scale(excess) == 0)
+                .map(|u| u / scale(excess)),
+        }
+        .ok_or_else(|| AmountError(s.to_owned()))
+    }
+}
+
+/// Amount parser tolerating amounts written as for humans to read: with a leading
+/// currency symbol, as in `$100.00`, and with digits of the integer part grouped
+/// in thousands, as in `1,234.56`, the rest being as for `DecimalParser`.
+/// Groups are to be of three digits, but the first one, so that e.g. `1,2,3.4`
+/// is rejected rather than taken for `123.4`.
+/// Grouping characters and the decimal point are configurable, for locales swapping
+/// them, see `LenientParser::decimal_comma()`.
+#[derive(Debug, Clone)]
+pub struct LenientParser<const DIGITS: u32 = DECIMALS> {
+    /// Characters grouping the digits, any of which may be used.
+    pub grouping: Vec<char>,
+    pub decimal_point: char,
 }
-impl TxState for Disputed {
-    fn resolve(self: Box<Self>) -> Box<dyn TxState> {
-        Box::new(Executed)
+
+impl<const DIGITS: u32> Default for LenientParser<DIGITS> {
+    fn default() -> Self {
+        LenientParser {
+            grouping: vec![','],
+            decimal_point: '.',
+        }
     }
+}
 
-    fn revert(self: Box<Self>) -> Box<dyn TxState> {
-        Box::new(Reverted)
+impl<const DIGITS: u32> LenientParser<DIGITS> {
+    /// Returns parser of the amounts with a decimal comma, grouped with dots or spaces,
+    /// as in `1.234,56` or `1 234,56`.
+    pub fn decimal_comma() -> Self {
+        LenientParser {
+            grouping: vec!['.', ' ', '\u{a0}'],
+            decimal_point: ',',
+        }
     }
 
-    impl_fallbacks!(execute, dispute);
-    impl_state_getter!(Disputed);
+    /// Returns the amount as plain decimal, with no currency symbol and grouping,
+    /// or `None` if its digits are not grouped properly.
+    fn normalize(&self, s: &str) -> Option<String> {
+        // a currency symbol: a dollar sign, or any other symbol beyond ASCII, e.g. `€`
+        let s = match s.chars().next() {
+            Some(c) if c == '$' || !(c.is_ascii() || c.is_alphanumeric() || c.is_whitespace()) => {
+                s[c.len_utf8()..].trim_start()
+            }
+            _ => s,
+        };
+        let (int, frac) = match s.split_once(self.decimal_point) {
+            Some((int, frac)) => (int, Some(frac)),
+            None => (s, None),
+        };
+        let mut groups = int.split(|c| self.grouping.contains(&c)).peekable();
+        let mut plain = groups.next()?.to_owned();
+        let first = plain.trim_start_matches(['+', '-']);
+        if groups.peek().is_some() && (first.is_empty() || first.len() > 3) {
+            return None;
+        }
+        for group in groups {
+            if group.len() != 3 || !group.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            plain.push_str(group);
+        }
+        if let Some(frac) = frac {
+            plain.push('.');
+            plain.push_str(frac);
+        }
+        // e.g. of `1,234.56` read with a decimal comma
+        (plain.matches('.').count() < 2).then_some(plain)
+    }
 }
-impl TxState for Reverted {
-    impl_fallbacks!(execute, dispute, resolve, revert);
-    impl_state_getter!(Reverted);
+
+impl<const DIGITS: u32> AmountParser for LenientParser<DIGITS> {
+    fn parse(&self, s: &str) -> Result<Amount, AmountError> {
+        self.normalize(s)
+            .and_then(|plain| parse_scaled_amount(&plain, DIGITS))
+            .ok_or_else(|| AmountError(s.to_owned()))
+    }
 }
 
-/// Helper for amounts deserialization.
-/// We deser amount to integer value = <amount>*10^4.
-/// This allows balances up to ~1.84 quadrillion (`u64::MAX/10^4`),
-/// which should be quite enough.
-/// If requested transaction balance is >= `u64::MAX/10^4`,
-/// we deseriaze it to None.
-fn deser_amount<'de, D>(de: D) -> Result<Option<u64>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    Ok(Option::<&str>::deserialize(de)
-        .unwrap_or(None)
-        .and_then(|s| {
-            let v = s.split('.').take(2).collect::<Vec<_>>();
-            let mut s = v[0].to_owned();
-            match v.len() {
-                1 => s.push_str("0000"),
-
-                2 => match v[1].len() {
-                    n @ 0..=4 => {
-                        s.push_str(&v[1][0..n]);
-                        for c in std::iter::repeat('0').take(4 - n) {
-                            s.push(c)
-                        }
-                    }
-                    5.. => s.push_str(&v[1][0..4]),
-                },
-                _ => (),
-            };
-            s.parse::<u64>().ok()
-        }))
+/// Parses decimal amount string into integer value = <amount>*10^4.
+/// Digits beyond 10^-4 precision are truncated, unless with `decimal` feature.
+/// Returns None if the string is not a valid amount or it exceeds `MAX_AMOUNT`.
+pub fn parse_amount(s: &str) -> Option<Amount> {
+    parse_scaled_amount(s, DECIMALS)
+}
+
+/// Parses decimal amount string into integer value = <amount>*10^decimals,
+/// in scientific notation as well, as in `1.5e3` or `1E-2`.
+/// Digits beyond 10^-decimals precision are truncated, or kept with `decimal` feature,
+/// see `decimal::parse_amount_exact()`.
+/// Returns None if the string is not a valid amount or it exceeds
+/// `MAX_AMOUNT / 10^decimals`.
+pub fn parse_scaled_amount(s: &str, decimals: u32) -> Option<Amount> {
+    #[cfg(feature = "decimal")]
+    return crate::decimal::parse_amount_exact(s, decimals);
+    #[cfg(not(feature = "decimal"))]
+    parse_rounded_amount(s, decimals, RoundingMode::Truncate)
+}
+
+/// Same as `parse_scaled_amount()`, but digits beyond 10^-decimals precision
+/// are rounded as given. Returns None as well if rounding up makes it exceed
+/// `MAX_AMOUNT / 10^decimals`.
+pub fn parse_rounded_amount(s: &str, decimals: u32, rounding: RoundingMode) -> Option<Amount> {
+    let (s, exp) = match s.split_once(['e', 'E']) {
+        Some((mantissa, _)) if !mantissa.contains(|c: char| c.is_ascii_digit()) => return None,
+        Some((mantissa, exp)) => (mantissa, exp.parse::<i64>().ok()?),
+        None => (s, 0),
+    };
+    let v = s.split('.').take(2).collect::<Vec<_>>();
+    let (int, frac) = (v[0], v.get(1).copied().unwrap_or_default());
+    let int = int.strip_prefix('+').unwrap_or(int);
+    let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(int) || !digits(frac) {
+        return None;
+    }
+    // number of zeros to append to the digits, or of the ones to drop if negative
+    let shift = i64::from(decimals)
+        .checked_add(exp)?
+        .checked_sub(i64::try_from(frac.len()).ok()?)?;
+    let mut s = [int, frac].concat();
+    let mut round_up = false;
+    match usize::try_from(shift) {
+        // any non-zero digit followed by as many zeros overflows `Amount`
+        Ok(zeros) => s.extend(std::iter::repeat_n('0', zeros.min(40))),
+        Err(_) => {
+            let drop = usize::try_from(shift.unsigned_abs()).unwrap_or(usize::MAX);
+            let keep = s.len().saturating_sub(drop);
+            // digits dropped beyond the ones given are zeros, so it's below the half then
+            round_up = drop <= s.len() && rounding.rounds_up(s[..keep].bytes().last(), &s[keep..]);
+            s.truncate(keep);
+            if s.is_empty() {
+                s.push('0');
+            }
+        }
+    }
+    // `MAX_AMOUNT` is `Amount::MAX`, so anything beyond fails to parse
+    s.parse::<Amount>()
+        .ok()?
+        .checked_add(Amount::from(round_up))
 }
@@ -0,0 +1,70 @@
+//! Write-ahead log of the transactions applied by the engine, for a run crashed
+//! mid-batch to be recovered by replaying it into a fresh engine,
+//! see `Engine::with_wal()` and `Engine::replay_wal()`.
+//! It is newline-delimited JSON, a record per line, appended as each transaction
+//! is applied, so a crash leaves at most the last line cut short.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::snapshot::TransactionSnapshot;
+use crate::transaction::TxStateKind;
+
+/// Transaction applied, along with the state it resulted in.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WalRecord {
+    /// Position in the sequence of transactions processed, declined ones included,
+    /// the clock of the dispute windows.
+    pub seq: u64,
+    /// Position in the sequence of transactions applied, see `Account::last_seq`.
+    pub applied: u64,
+    /// Transaction as it was applied.
+    pub tx: TransactionSnapshot,
+    /// State of the stored transaction it ended up at, or of the one it refers to
+    /// for an event; `None` if there is none stored.
+    pub state: Option<TxStateKind>,
+}
+
+/// Log file, appended to only.
+#[derive(Debug)]
+pub(crate) struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending, creating it if there is none.
+    pub(crate) fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal { file })
+    }
+
+    /// Appends the record as a single write, for it not to interleave with others.
+    pub(crate) fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+/// Reads records of the log at `path`. A cut short last line, left by a crash
+/// in the middle of its write, is skipped, while malformed ones fail it.
+pub fn read_wal(path: impl AsRef<Path>) -> io::Result<Vec<WalRecord>> {
+    let text = fs::read_to_string(path)?;
+    let complete = text.ends_with('\n');
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut records = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(_) if i + 1 == lines.len() && !complete => break,
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed WAL record at line {}: {}", i + 1, e),
+                ))
+            }
+        }
+    }
+    Ok(records)
+}
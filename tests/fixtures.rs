@@ -1,6 +1,7 @@
 use std::fs;
+use std::io::Write;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 #[test]
 fn integration_tests() {
@@ -11,6 +12,129 @@ fn integration_tests() {
     }
 }
 
+#[test]
+fn stdin_deltas_apply_on_top_of_base_file() {
+    let mut child = Command::new("cargo")
+        .args(["run", "--", "./fixtures/in/basic.csv", "--stdin"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to execute process");
+    // the stream comes with its own header
+    let deltas = "\
+type, client, tx, amount
+deposit, 2, 6, 1.0
+withdrawal, 1, 7, 0.5
+deposit, 3, 8, 4.0
+";
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(deltas.as_bytes())
+        .unwrap();
+    let res = child.wait_with_output().unwrap().stdout;
+
+    let result = String::from_utf8(res).unwrap();
+    let expected = "\
+client,available,held,total,locked
+1,1,0,1,false
+2,3,0,3,false
+3,4,0,4,false
+";
+
+    assert_eq!(result, expected)
+}
+
+#[test]
+fn stdin_is_read_without_input_file() {
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to execute process");
+    let input = fs::read("./fixtures/in/basic.csv").unwrap();
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let res = child.wait_with_output().unwrap().stdout;
+
+    let expected = fs::read_to_string("./fixtures/out/basic.csv").unwrap();
+    let result = String::from_utf8(res).unwrap();
+
+    assert_eq!(result, expected)
+}
+
+#[test]
+fn rejects_are_written_for_resubmission() {
+    let rejects = std::env::temp_dir().join("tpe-rejects.csv");
+    let status = Command::new("cargo")
+        .args(["run", "--", "./fixtures/rejects/in.csv", "--rejects"])
+        .arg(&rejects)
+        .stdout(Stdio::null())
+        .status()
+        .expect("failed to execute process");
+    assert!(status.success());
+
+    let expected = fs::read_to_string("./fixtures/rejects/out.csv").unwrap();
+    let result = fs::read_to_string(&rejects).unwrap();
+    fs::remove_file(&rejects).unwrap();
+
+    assert_eq!(result, expected)
+}
+
+#[test]
+fn output_format_and_destination_are_chosen() {
+    let dir = std::env::temp_dir();
+    let (output, errors) = (dir.join("tpe-output.json"), dir.join("tpe-errors.csv"));
+    let res = Command::new("cargo")
+        .args([
+            "run",
+            "--",
+            "./fixtures/in/faulty_records.csv",
+            "--format",
+            "json",
+        ])
+        .arg("--output")
+        .arg(&output)
+        .arg("--errors")
+        .arg(&errors)
+        .output()
+        .expect("failed to execute process");
+    assert!(res.status.success());
+    // all of it goes to the file
+    assert!(res.stdout.is_empty());
+
+    let result = fs::read_to_string(&output).unwrap();
+    let expected = r#"[
+  {
+    "client": 1,
+    "available": "10000",
+    "held": "0",
+    "total": "10000",
+    "locked": false
+  }
+]
+"#;
+    assert_eq!(result, expected);
+
+    let result = fs::read_to_string(&errors).unwrap();
+    let expected = "\
+line,tx,code,error
+6,4,31,unknown transaction type: withdraw
+8,5,31,unknown transaction type: withdraw
+4,3,10,empty amount
+7,4,10,empty amount
+10,0,13,missing required field: type
+3,,,malformed row
+5,,,malformed row
+9,,,malformed row
+11,,,malformed row
+";
+    assert_eq!(result, expected);
+    fs::remove_file(&output).unwrap();
+    fs::remove_file(&errors).unwrap();
+}
+
 fn run_fixture(fixt: fs::DirEntry) {
     let fixt = fixt.path();
     let name = fixt.file_name().unwrap();
@@ -34,11 +158,5 @@ fn run_fixture(fixt: fs::DirEntry) {
     let expected = fs::read_to_string(out).expect("can't read output fixture");
     let result = String::from_utf8(res).unwrap();
 
-    let mut e = expected.split('\n').collect::<Vec<_>>();
-    let mut r = result.split('\n').collect::<Vec<_>>();
-
-    e.sort();
-    r.sort();
-
-    assert_eq!(r, e, "fixture: {:?}", &name)
+    assert_eq!(result, expected, "fixture: {:?}", &name)
 }